@@ -1,11 +1,12 @@
 use crate::LOCKS;
-use crate::http::ResponseBody;
-use std::fmt::{Display, Formatter};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
+use crate::config::Config;
+use std::fs;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::PoisonError;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{fmt, fs};
 
 /// Logs info to the standard output, adding the current date and time, and using colors to
 /// indicate it is an info log
@@ -67,13 +68,18 @@ macro_rules! log_error {
 /// The error type of the application, representing all known variants of errors that might occur
 /// during the lifetime of the application
 /// - **IO**: This represents errors that have to do with reading or writing to files or streams, they
-/// are critical and typically mean something is wrong with the application or configuration
+///   are critical and typically mean something is wrong with the application or configuration
 /// - **Invalid**: This represents errors from a malformed request or invalid body, they are usually
-/// not critical
+///   not critical
 /// - **NotFound**: This represents errors that come from the client requesting a file or page that
-/// can't be found on the server
+///   can't be found on the server
 /// - **NotPermitted**: This represents errors that emanate from the client attempting to access a
-/// resource outside the permission, usually a file outside the uploads directory.
+///   resource outside the permission, usually a file outside the uploads directory.
+/// - **Timeout**: This represents errors from a client taking too long to send its request
+/// - **HeaderTooLarge**: This represents errors from a client sending more or longer headers than
+///   the server accepts
+/// - **UriTooLong**: This represents errors from a client sending a request line longer than the
+///   server accepts
 /// - **Unknown**: This represents all errors of unknown reason or origin.
 #[derive(Debug)]
 pub(crate) enum AppError {
@@ -81,16 +87,51 @@ pub(crate) enum AppError {
     Invalid(String),
     NotFound(String),
     NotPermitted(String),
+    Timeout(String),
+    HeaderTooLarge(String),
+    UriTooLong(String),
     Unknown(String),
 }
 
 pub(crate) struct Time;
 
+/// The calendar date and time of day of a timestamp, in UTC
+#[derive(Debug, PartialEq)]
+struct DateTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    /// The day of the week, where 0 is Sunday
+    weekday: u8,
+}
+
 impl Time {
+    const SECONDS_PER_MINUTE: u64 = 60;
+    const SECONDS_PER_HOUR: u64 = 3_600;
+    const SECONDS_PER_DAY: u64 = 86_400;
+    const WEEKDAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&'static str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
     /// Checks if a year is a leap year using the Gregorian calendar's definition of a leap year.
     /// A year is a leap year if it is divisible by 4 but not by 100, or it is divisible by 400
     fn is_leap_year(year: u16) -> bool {
-        (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+        (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+    }
+
+    /// Gets the number of days in a year
+    fn days_in_year(year: u16) -> u64 {
+        if Self::is_leap_year(year) { 366 } else { 365 }
+    }
+
+    /// Gets the number of days in each month of a year
+    fn days_in_months(year: u16) -> [u8; 12] {
+        let february = if Self::is_leap_year(year) { 29 } else { 28 };
+        [31, february, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31]
     }
 
     /// Gets the current timestamp by taking the current `SystemTime` and finding the duration from the
@@ -105,99 +146,456 @@ impl Time {
     ///
     /// Arguments:
     /// - **timestamp**: The timestamp to be transformed to military time
+    pub(crate) fn get_date_string_from_timestamp(timestamp: u64) -> String {
+        let DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            ..
+        } = Self::get_date_time_from_timestamp(timestamp);
+
+        format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}")
+    }
+
+    /// Converts a timestamp to an HTTP date, as used by headers like *Last-Modified*
+    /// (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`).
+    ///
+    /// Arguments:
+    /// - **timestamp**: The timestamp to be transformed to an HTTP date
+    pub(crate) fn get_http_date_from_timestamp(timestamp: u64) -> String {
+        let DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            weekday,
+        } = Self::get_date_time_from_timestamp(timestamp);
+
+        format!(
+            "{}, {day:02} {} {year} {hour:02}:{minute:02}:{second:02} GMT",
+            Self::WEEKDAYS[weekday as usize],
+            Self::MONTHS[month as usize - 1]
+        )
+    }
+
+    /// Converts an HTTP date, as sent in headers like *If-Modified-Since*, to a timestamp
+    ///
+    /// Arguments:
+    /// - **http_date**: The HTTP date to be parsed
+    ///
+    /// All three formats HTTP allows are accepted; the preferred `Sun, 06 Nov 1994 08:49:37 GMT`,
+    /// and the obsolete `Sunday, 06-Nov-94 08:49:37 GMT` and `Sun Nov  6 08:49:37 1994`.  
+    /// `None` is returned if the date is in none of these formats or isn't a valid date.
+    pub(crate) fn get_timestamp_from_http_date(http_date: &str) -> Option<u64> {
+        let parts: Vec<&str> = http_date.split_whitespace().collect();
+
+        let (day, month, year, time) = match parts.as_slice() {
+            [_, day, month, year, time, "GMT"] => (*day, *month, year.parse().ok()?, *time),
+            [_, date, time, "GMT"] => {
+                let mut date_parts = date.split('-');
+                let (day, month, year) =
+                    (date_parts.next()?, date_parts.next()?, date_parts.next()?);
+                let year: u16 = year.parse().ok()?;
+                // Two digit years are read as the closest year that isn't far in the future
+                let year = if year < 70 { 2000 + year } else { 1900 + year };
+                (day, month, year, *time)
+            }
+            [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+            _ => return None,
+        };
+
+        let month = Self::MONTHS.iter().position(|name| *name == month)? as u8 + 1;
+        let day: u8 = day.parse().ok()?;
+        let mut time_parts = time.split(':').map(|part| part.parse::<u8>().ok());
+        let (hour, minute, second) = (
+            time_parts.next()??,
+            time_parts.next()??,
+            time_parts.next()??,
+        );
+
+        if year < 1970
+            || day == 0
+            || day > Self::days_in_months(year)[month as usize - 1]
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return None;
+        }
+
+        let days_since_epoch = (1970..year).map(Self::days_in_year).sum::<u64>()
+            + Self::days_in_months(year)[..month as usize - 1]
+                .iter()
+                .map(|days| *days as u64)
+                .sum::<u64>()
+            + (day as u64 - 1);
+
+        Some(
+            days_since_epoch * Self::SECONDS_PER_DAY
+                + hour as u64 * Self::SECONDS_PER_HOUR
+                + minute as u64 * Self::SECONDS_PER_MINUTE
+                + second as u64,
+        )
+    }
+
+    /// Converts a timestamp to a `DateTime`
+    ///
+    /// Arguments:
+    /// - **timestamp**: The timestamp to be converted
     ///
     /// The timestamp is divided by the number of seconds in a day to derive how many days have
     /// passed since the UNIX epoch. The number of days is then used to figure out how many years
     /// have passed since the then.  
     /// The remainder of the division is used to find the time of the current day
-    pub(crate) fn get_date_string_from_timestamp(timestamp: u64) -> String {
-        let seconds_per_minute = 60u32;
-        let seconds_per_hour = 3_600u32;
-        let seconds_per_day = 86_400u32;
-
-        let mut days_since_epoch = timestamp / seconds_per_day as u64;
+    fn get_date_time_from_timestamp(timestamp: u64) -> DateTime {
+        let mut days_since_epoch = timestamp / Self::SECONDS_PER_DAY;
         let mut current_year = 1970u16;
-        let seconds_in_current_day: u32 = (timestamp % seconds_per_day as u64) as u32;
+        let seconds_in_current_day = timestamp % Self::SECONDS_PER_DAY;
+        // The UNIX epoch was on a Thursday
+        let weekday = ((days_since_epoch + 4) % 7) as u8;
 
         // Continuously subtracts the number days of each year from the total days since the UNIX epoch,
         // until the days left can't make up a year
-        while days_since_epoch
-            >= if Self::is_leap_year(current_year) {
-                366
-            } else {
-                365
-            }
-        {
+        while days_since_epoch >= Self::days_in_year(current_year) {
+            days_since_epoch -= Self::days_in_year(current_year);
             current_year += 1;
-            days_since_epoch -= if Self::is_leap_year(current_year) {
-                366
-            } else {
-                365
-            }
         }
 
-        let days_in_months: [u8; 12] = [
-            31,
-            if Self::is_leap_year(current_year) {
-                29
-            } else {
-                28
-            },
-            31,
-            30,
-            31,
-            30,
-            31,
-            31,
-            30,
-            31,
-            30,
-            31,
-        ];
-
         let mut current_month = 1;
         // Repeatedly subtracts the number of days of each month from the days left, until the subtraction
         // amounts in a negative number
-        for days in days_in_months {
+        for days in Self::days_in_months(current_year) {
             days_since_epoch = match days_since_epoch.checked_sub(days as u64) {
                 Some(d) => d,
                 None => break,
             };
             current_month += 1;
         }
-        let current_day: u8 = days_since_epoch as u8 + 1;
-        let hour = seconds_in_current_day / seconds_per_hour;
-        let minute = (seconds_in_current_day % seconds_per_hour) / seconds_per_minute;
-        let seconds = (seconds_in_current_day % seconds_per_minute) % seconds_per_minute;
 
-        format!(
-            "{current_year}-{current_month:02}-{current_day:02}T{hour:02}:{minute:02}:{seconds:02}"
-        )
+        DateTime {
+            year: current_year,
+            month: current_month,
+            day: days_since_epoch as u8 + 1,
+            hour: (seconds_in_current_day / Self::SECONDS_PER_HOUR) as u8,
+            minute: (seconds_in_current_day % Self::SECONDS_PER_HOUR / Self::SECONDS_PER_MINUTE)
+                as u8,
+            second: (seconds_in_current_day % Self::SECONDS_PER_MINUTE) as u8,
+            weekday,
+        }
     }
 }
 
+/// What `FileManager::move_file()` did
+/// - **Moved**: The file was moved to where there was no file
+/// - **Replaced**: The file replaced the file that was at its destination
+/// - **Refused**: Nothing was moved, as there was a file at the destination that wasn't to be replaced
+#[derive(Debug, PartialEq)]
+pub(crate) enum MoveOutcome {
+    Moved,
+    Replaced,
+    Refused,
+}
+
 /// Handles all file reading and writing logic besides the basics
 pub(crate) struct FileManager;
 
 impl FileManager {
-    /// Saves a file to a specified directory
+    /// Saves uploaded files, creating the directory they are uploaded into first
+    ///
+    /// Arguments:
+    /// - **dir**: The directory uploaded files are stored in
+    /// - **directory**: The directory the files are uploaded into, relative to `dir`, which is
+    ///   created with its missing parents the same way as with `create_dir()`, if there is one
+    /// - **files**: The resolved path each file is saved at, with the `TempFile` it was streamed into
+    /// - **overwrite**: Whether files already at the paths are replaced
+    ///
+    /// Each `TempFile` is flushed to disk and then renamed to its path. The rename is atomic, so
+    /// the file either appears complete or not at all, and nobody ever sees a half written upload.  
+    /// The lock is held from checking for files at the paths until every file is saved, so the
+    /// directory can't be deleted in between, and no file can appear at a path after it was checked.
+    /// If there is a file at any of the paths and `overwrite` is `false`, nothing is saved and no
+    /// directory is created.  
+    /// Returns whether the files were saved.
+    pub(crate) fn save_files(
+        dir: &str,
+        directory: Option<&Path>,
+        files: Vec<(PathBuf, TempFile)>,
+        overwrite: bool,
+    ) -> Result<bool, AppError> {
+        // A panic while holding the lock doesn't leave anything inconsistent, as it guards no data
+        let _mutex_guard = LOCKS
+            .create_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if !overwrite && files.iter().any(|(path, _)| path.exists()) {
+            return Ok(false);
+        }
+        if let Some(directory) = directory {
+            Self::create_dir_locked(dir, directory)?;
+        }
+        for (path, temp_file) in files {
+            temp_file.persist(&path)?;
+        }
+        Ok(true)
+    }
+
+    /// Deletes an uploaded file, along with the directories it leaves empty
+    ///
+    /// Arguments:
+    /// - **dir**: The directory uploaded files are stored in, which is never removed
+    /// - **path**: The canonical path of the file to delete, which must be inside the uploads
+    ///   directory
+    ///
+    /// The same lock as `save_files()` is held while the file and its empty directories are removed,
+    /// so that files are never created, moved and deleted at the same time, and an upload can't be
+    /// saved into a directory as it is removed.
+    pub(crate) fn delete_file(dir: &str, path: &Path) -> Result<(), AppError> {
+        let _mutex_guard = LOCKS
+            .create_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        fs::remove_file(path).map_err(|e| {
+            AppError::IO(format!("Failed to delete file {}: {}", path.display(), e))
+        })?;
+        Self::remove_empty_dirs(dir, path)
+    }
+
+    /// Removes the directories above a file that was removed from them while they are empty
     ///
     /// Arguments:
-    /// - **dir**: The directory to save the file in
-    /// - **buffered_file**: An `BufferedFile` to be saved
+    /// - **dir**: The directory uploaded files are stored in, which is never removed
+    /// - **path**: The canonical path the file was at
     ///
-    /// A `Path` is made out of the directory, and then joined with the file name to create the file path.  
-    /// A `File` is then created in that `Path`, and the contents of the `BufferedFile` are written into it.  
-    /// This will override any previous file saved in the same path with the same name.
-    pub(crate) fn save_file(dir: &str, buffered_file: BufferedFile) -> Result<(), AppError> {
-        let _mutex_guard = LOCKS.create_file.lock().unwrap();
-        let path = Path::new(dir).join(buffered_file.name);
+    /// Each directory is removed in turn, stopping at the first one that still has entries.
+    /// Callers must hold the lock.
+    fn remove_empty_dirs(dir: &str, path: &Path) -> Result<(), AppError> {
+        let base_path = Path::new(dir)
+            .canonicalize()
+            .map_err(|_| AppError::Unknown("Failed to canonicalize base path".to_string()))?;
+        // Removing a directory fails once it isn't empty, which ends the clean up
+        for parent in path.ancestors().skip(1) {
+            if parent == base_path
+                || !parent.starts_with(&base_path)
+                || fs::remove_dir(parent).is_err()
+            {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes an empty directory in the uploads directory
+    ///
+    /// Arguments:
+    /// - **path**: The canonical path of the directory to delete, which must be inside the uploads
+    ///   directory
+    ///
+    /// Only an empty directory is removed, so files can never be lost by deleting the directory they
+    /// are in, and an error is returned for one that still has entries.  
+    /// The same lock as `save_files()` is held, so an upload can't be saved into the directory while
+    /// it is removed.
+    pub(crate) fn delete_dir(path: &Path) -> Result<(), AppError> {
+        let _mutex_guard = LOCKS
+            .create_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        fs::remove_dir(path).map_err(|e| match e.kind() {
+            ErrorKind::DirectoryNotEmpty => AppError::NotPermitted(format!(
+                "Client attempted to delete a folder that is not empty: {}",
+                path.display()
+            )),
+            _ => AppError::IO(format!(
+                "Failed to delete directory {}: {}",
+                path.display(),
+                e
+            )),
+        })
+    }
+
+    /// Moves or renames an uploaded file
+    ///
+    /// Arguments:
+    /// - **dir**: The directory uploaded files are stored in, which is never removed
+    /// - **from**: The canonical path of the file to move, which must be inside the uploads directory
+    /// - **to**: The path to move the file to, which must be inside the uploads directory, in a
+    ///   directory that exists
+    /// - **overwrite**: Whether a file already at `to` is replaced
+    ///
+    /// The same lock as `save_files()` is held, so no file can appear at `to` or be removed from it
+    /// between checking for one and moving the file, and what the move did is always what is
+    /// returned. If there is a file at `to` and `overwrite` is `false`, nothing is moved.  
+    /// The rename is atomic, so a replaced file is never missing. The directories the file leaves
+    /// empty are then removed, the same as when it is deleted.
+    pub(crate) fn move_file(
+        dir: &str,
+        from: &Path,
+        to: &Path,
+        overwrite: bool,
+    ) -> Result<MoveOutcome, AppError> {
+        let _mutex_guard = LOCKS
+            .create_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let replaces_file = to.exists();
+        if replaces_file && !overwrite {
+            return Ok(MoveOutcome::Refused);
+        }
+        fs::rename(from, to).map_err(|e| {
+            AppError::IO(format!(
+                "Failed to move file {} to {}: {}",
+                from.display(),
+                to.display(),
+                e
+            ))
+        })?;
+        Self::remove_empty_dirs(dir, from)?;
+        Ok(match replaces_file {
+            true => MoveOutcome::Replaced,
+            false => MoveOutcome::Moved,
+        })
+    }
+
+    /// Creates a directory in the uploads directory, along with any of its parents that are missing
+    ///
+    /// Arguments:
+    /// - **dir**: The directory uploaded files are stored in
+    /// - **path**: The path of the directory to create, relative to `dir`, which must only have
+    ///   normal segments, with no `.` or `..`
+    ///
+    /// The directories on the path are created one at a time. One that already exists is
+    /// canonicalized, to assert that it is still inside `dir` and is a directory, so that a symbolic
+    /// link can't lead the rest of the path outside the uploads directory.  
+    /// The same lock as `save_files()` is held, so the directories can't be removed by a delete while
+    /// they are created.  
+    /// Returns whether the directory was created, which is `false` if it already existed.
+    pub(crate) fn create_dir(dir: &str, path: &Path) -> Result<bool, AppError> {
+        let _mutex_guard = LOCKS
+            .create_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        Self::create_dir_locked(dir, path)
+    }
+
+    /// Creates a directory like `create_dir()`, for callers that already hold the lock
+    fn create_dir_locked(dir: &str, path: &Path) -> Result<bool, AppError> {
+        let base_path = Path::new(dir)
+            .canonicalize()
+            .map_err(|_| AppError::Unknown("Failed to canonicalize base path".to_string()))?;
+
+        let mut current = base_path.clone();
+        let mut created = false;
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                return Err(AppError::NotPermitted(format!(
+                    "Client attempted to create a directory outside the uploads directory: {}",
+                    path.display()
+                )));
+            };
+            current.push(name);
+
+            match current.canonicalize() {
+                Ok(resolved) if resolved.starts_with(&base_path) && resolved.is_dir() => {
+                    current = resolved;
+                    created = false;
+                }
+                Ok(resolved) if resolved.starts_with(&base_path) => {
+                    return Err(AppError::NotPermitted(format!(
+                        "Client attempted to create a directory where there is a file: {}",
+                        path.display()
+                    )));
+                }
+                Ok(_) => {
+                    return Err(AppError::NotPermitted(format!(
+                        "Client attempted to create a directory outside the uploads directory: {}",
+                        path.display()
+                    )));
+                }
+                Err(_) => {
+                    fs::create_dir(&current).map_err(|e| {
+                        AppError::IO(format!(
+                            "Failed to create directory {}: {}",
+                            current.display(),
+                            e
+                        ))
+                    })?;
+                    created = true;
+                }
+            }
+        }
+        Ok(created)
+    }
 
-        let mut file = File::create(&path) // Create or overwrite the file
-            .map_err(|_| AppError::IO("Failed to create file".to_string()))?;
-        file.write_all(&*buffered_file.content) // Write the bytes to the file
-            .map_err(|_| AppError::IO("Failed to read file".to_string()))?;
+    /// Returns the paths of every directory in a directory and its subdirectories, relative to it
+    ///
+    /// Arguments:
+    /// - **dir**: The directory to search in for directories
+    ///
+    /// Directories are listed before the directories inside them, each level sorted by name.
+    /// Symbolic links to directories aren't listed.
+    pub(crate) fn list_dirs(dir: &str) -> Result<Vec<String>, AppError> {
+        let mut dirs = Vec::new();
+        let mut pending = vec![(PathBuf::from(dir), String::new())];
+
+        while let Some((path, relative_path)) = pending.pop() {
+            let mut children = Vec::new();
+            for entry in fs::read_dir(&path)
+                .map_err(|_| AppError::IO(format!("Failed to read directory: {}", path.display())))?
+                .flatten()
+            {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                // Symbolic links aren't followed, as they can lead outside the directory
+                if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                    let relative_dir = match relative_path.is_empty() {
+                        true => name,
+                        false => format!("{relative_path}/{name}"),
+                    };
+                    children.push((entry.path(), relative_dir));
+                }
+            }
+            children.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+            dirs.extend(
+                children
+                    .iter()
+                    .map(|(_, relative_dir)| relative_dir.clone()),
+            );
+            pending.extend(children.into_iter().rev());
+        }
+        Ok(dirs)
+    }
 
+    /// Removes temporary upload files left behind in a directory, for example by a crash
+    ///
+    /// Arguments:
+    /// - **dir**: The directory to clean up
+    ///
+    /// Only files named like a `TempFile` are removed.
+    pub(crate) fn remove_temp_files(dir: &str) -> Result<(), AppError> {
+        for entry in fs::read_dir(dir)
+            .map_err(|_| AppError::IO(format!("Failed to read directory: {dir}")))?
+            .flatten()
+        {
+            if TempFile::is_temp_file_name(&entry.file_name().to_string_lossy()) {
+                fs::remove_file(entry.path()).map_err(|_| {
+                    AppError::IO(format!(
+                        "Failed to remove temporary file: {}",
+                        entry.path().display()
+                    ))
+                })?;
+            }
+        }
         Ok(())
     }
 
@@ -251,7 +649,10 @@ impl FileManager {
                 format!("{}/{}", relative_path, file_name)
             };
 
-            if path.is_dir() {
+            if TempFile::is_temp_file_name(&file_name) {
+                // Skip uploads that are still being written
+                continue;
+            } else if path.is_dir() {
                 // Recursively process subdirectories
                 Self::traverse_dir(&path, files, relative_file_path)?;
             } else {
@@ -262,121 +663,209 @@ impl FileManager {
         Ok(())
     }
 
+    /// Gets a strong entity tag for a file, which changes whenever the file does
+    ///
+    /// Arguments:
+    /// - **metadata**: The `Metadata` of the file
+    ///
+    /// The tag is made from the size and the modification time, down to the nanosecond, of the file,
+    /// which saves reading and hashing the whole file every time it is requested.
+    pub(crate) fn get_etag(metadata: &Metadata) -> String {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        format!(
+            r#""{:x}-{:x}-{:x}""#,
+            metadata.len(),
+            modified.as_secs(),
+            modified.subsec_nanos()
+        )
+    }
+
+    /// Gets the timestamp of the last time a file was modified, in whole seconds
+    ///
+    /// Arguments:
+    /// - **metadata**: The `Metadata` of the file
+    pub(crate) fn get_last_modified(metadata: &Metadata) -> u64 {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Appends a line to the log file, which is opened the first time it is written to and kept open
+    ///
+    /// Logs are written from the event loop as well as the workers, so failing to write one must
+    /// never take the server down. If the log file can't be opened or written to, for example
+    /// because the disk is full or its directory was removed, the error is printed to the standard
+    /// error output instead, where the line itself was already printed. After a failed write, the
+    /// file is opened again for the next line.
     pub(crate) fn append_to_log_file(line: String) {
-        let _mutex_guard = LOCKS.append_log.lock().unwrap();
+        let mut log_file = LOCKS
+            .append_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let log_file_path = &Config::get().log_file;
 
         let mut line = line;
-        line.push_str("\n");
+        line.push('\n');
+
+        if log_file.is_none() {
+            match OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(log_file_path)
+            {
+                Ok(file) => *log_file = Some(file),
+                Err(e) => {
+                    eprintln!("Failed to open log file {log_file_path}: {e}");
+                    return;
+                }
+            }
+        }
+        if let Some(file) = log_file.as_mut()
+            && let Err(e) = file.write_all(line.as_bytes())
+        {
+            eprintln!("Failed to write to log file {log_file_path}: {e}");
+            *log_file = None;
+        }
+    }
 
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open("logs.txt")
-            .expect("Failed to open logs.txt file");
-        file.write_all(line.as_bytes())
-            .expect("Failed to write to logs.txt file");
+    /// Flushes everything written to the log file to the disk, so no logs are lost when the process
+    /// exits
+    pub(crate) fn flush_log_file() -> Result<(), AppError> {
+        let log_file = LOCKS
+            .append_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        match log_file.as_ref() {
+            Some(file) => file
+                .sync_all()
+                .map_err(|e| AppError::IO(format!("Failed to flush log file: {e}"))),
+            None => Ok(()),
+        }
     }
 }
 
-/// A `BufferedFile` is an abstraction of a file in memory.  
-/// It can be any file, be it one gotten from a form, an upload being served or an HTML template    
-/// It contains a name and the contents of the file in a byte buffer
-pub(crate) struct BufferedFile {
-    pub(crate) name: String,
-    pub(crate) content: Vec<u8>,
+/// A `TempFile` is a file that an upload is streamed into while it arrives, so that uploads never
+/// have to be held in memory.  
+/// It is created with a hidden name in the directory the upload will be saved in, so that saving it
+/// is a cheap rename on the same file system. If it is dropped without being persisted, for example
+/// because the upload failed halfway, the file is removed.
+pub(crate) struct TempFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    persisted: bool,
 }
 
-impl Display for BufferedFile {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let content = String::from_utf8(self.content.clone())
-            .unwrap_or_else(|_| "File is not text based".to_string());
-        write!(f, "Filename: {}\nFile content: {}", self.name, content)
+/// Counts the temporary files created, so that every `TempFile` gets a unique name
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl TempFile {
+    const PREFIX: &'static str = ".upload-";
+    const SUFFIX: &'static str = ".part";
+
+    /// Creates a new, empty `TempFile`
+    ///
+    /// Arguments:
+    /// - **dir**: The directory to create the file in
+    ///
+    /// The file name is made unique using the process ID and a counter shared by all threads.
+    pub(crate) fn create(dir: &str) -> Result<TempFile, AppError> {
+        let count = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = Path::new(dir).join(format!(
+            "{}{}-{}{}",
+            Self::PREFIX,
+            std::process::id(),
+            count,
+            Self::SUFFIX
+        ));
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| AppError::IO(format!("Failed to create temporary file: {e}")))?;
+
+        Ok(TempFile {
+            path,
+            writer: BufWriter::new(file),
+            size: 0,
+            persisted: false,
+        })
     }
-}
 
-impl TryFrom<ResponseBody> for Option<BufferedFile> {
-    type Error = AppError;
-
-    /// Tries to cast a `ResponseBody` into an `Option<BufferedFile>`.  
-    /// The resultant `BufferedFile` is optional because not all `ResponseBody`s contain a file
-    fn try_from(value: ResponseBody) -> Result<Self, Self::Error> {
-        match value {
-            ResponseBody::File(filename) => {
-                let path = Path::new(&filename);
-                let uploaded_file = BufferedFile::try_from(path)?;
-                Ok(Some(uploaded_file))
-            }
-            ResponseBody::Text(text) => {
-                let uploaded_file = BufferedFile {
-                    name: "response.html".to_string(),
-                    content: text.as_bytes().to_vec(),
-                };
-                Ok(Some(uploaded_file))
-            }
-            ResponseBody::Empty => Ok(None),
-        }
+    /// Returns the number of bytes written to the file
+    pub(crate) fn size(&self) -> u64 {
+        self.size
     }
-}
 
-impl TryFrom<&Path> for BufferedFile {
-    type Error = AppError;
-
-    /// Tries to cast a `&Path` into an `BufferedFile`.  
-    /// This is for scenarios where the path of a file is known, and it needs to be constructed into
-    /// an `BufferedFile` to be served to the client.
-    ///
-    /// The path is first validated to ensure it exists and isn't a directory, then an attempt to open
-    /// the file is made, if successful, the contents are read into a buffer, which becomes the `content`
-    /// of the `BufferedFile`, while the filename is gotten from the `Path` and validated to be UTF-8
-    /// before being passed into the `BufferedFile` constructor as the `name` field.
-    fn try_from(path: &Path) -> Result<Self, Self::Error> {
-        if !path.exists() {
-            return Err(AppError::NotFound(format!(
-                "File does not exist: {}",
-                path.display()
-            )));
-        }
-        if path.is_dir() {
-            return Err(AppError::NotFound(format!(
-                "Path for file is a directory: {}",
-                path.display()
-            )));
-        }
-        let file_name = path
-            .file_name()
-            .ok_or(AppError::Invalid(format!(
-                "Invalid file name: {}",
-                path.display()
-            )))?
-            .to_str()
-            .ok_or(AppError::Invalid(format!(
-                "File name is not valid UTF-8: {}",
-                path.display()
-            )))?;
+    /// Flushes what was written so far and opens the file again, to read it from the start
+    pub(crate) fn open_reader(&mut self) -> Result<File, AppError> {
+        self.writer
+            .flush()
+            .map_err(|e| AppError::IO(format!("Failed to write file: {e}")))?;
+        File::open(&self.path)
+            .map_err(|e| AppError::IO(format!("Failed to read temporary file: {e}")))
+    }
 
-        let mut file = match File::open(path) {
-            Ok(file) => file,
-            Err(_) => {
-                return Err(AppError::NotFound(format!(
-                    "File failed to open: {file_name}"
-                )));
-            }
-        };
+    /// Checks if a file name is that of a `TempFile`
+    pub(crate) fn is_temp_file_name(file_name: &str) -> bool {
+        file_name.starts_with(Self::PREFIX) && file_name.ends_with(Self::SUFFIX)
+    }
 
-        let mut file_buffer = Vec::new();
-        file.read_to_end(&mut file_buffer)
-            .map_err(|_| AppError::IO(format!("Error reading file into buffer: {file_name}")))?;
+    /// Flushes the file to disk and renames it to its final path, keeping it from being removed
+    ///
+    /// Arguments:
+    /// - **path**: The path the file is moved to
+    fn persist(mut self, path: &Path) -> Result<(), AppError> {
+        self.writer
+            .flush()
+            .and_then(|_| self.writer.get_ref().sync_all())
+            .map_err(|e| AppError::IO(format!("Failed to write file: {e}")))?;
+        fs::rename(&self.path, path)
+            .map_err(|e| AppError::IO(format!("Failed to save file: {e}")))?;
+        self.persisted = true;
 
-        Ok(BufferedFile {
-            name: file_name.to_string(),
-            content: file_buffer,
-        })
+        Ok(())
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes_written = self.writer.write(buf)?;
+        self.size += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::common::Time;
+    use crate::common::{FileManager, MoveOutcome, TempFile, Time};
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
 
     #[test]
     fn test_get_date_string_from_timestamp() {
@@ -384,5 +873,135 @@ mod tests {
         let date_string = Time::get_date_string_from_timestamp(timestamp);
 
         println!("date string: {}", date_string);
+
+        assert_eq!(
+            Time::get_date_string_from_timestamp(94_694_400),
+            "1973-01-01T00:00:00"
+        );
+        assert_eq!(
+            Time::get_date_string_from_timestamp(1_709_210_096),
+            "2024-02-29T12:34:56"
+        );
+    }
+
+    #[test]
+    fn test_http_dates() {
+        let timestamp = 784_111_777;
+        assert_eq!(
+            Time::get_http_date_from_timestamp(timestamp),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+
+        for http_date in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(
+                Time::get_timestamp_from_http_date(http_date),
+                Some(timestamp)
+            );
+        }
+        assert_eq!(
+            Time::get_timestamp_from_http_date("Sun, 31 Feb 1994 08:49:37 GMT"),
+            None
+        );
+        assert_eq!(Time::get_timestamp_from_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn persist_temp_files_or_clean_them_up() {
+        let dir = env::temp_dir().join(format!("web-server-temp-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        let temp_file_count = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .flatten()
+                .filter(|entry| TempFile::is_temp_file_name(&entry.file_name().to_string_lossy()))
+                .count()
+        };
+
+        // An upload that fails halfway is removed as its `TempFile` is dropped
+        let mut temp_file = TempFile::create(dir_name).unwrap();
+        temp_file.write_all(b"half an upl").unwrap();
+        assert_eq!(temp_file_count(), 1);
+        drop(temp_file);
+        assert_eq!(temp_file_count(), 0);
+
+        let mut temp_file = TempFile::create(dir_name).unwrap();
+        temp_file.write_all(b"a whole upload").unwrap();
+        assert_eq!(temp_file.size(), 14);
+        temp_file.persist(&dir.join("upload.txt")).unwrap();
+        assert_eq!(temp_file_count(), 0);
+        assert_eq!(
+            fs::read_to_string(dir.join("upload.txt")).unwrap(),
+            "a whole upload"
+        );
+
+        // A crash leaves its temporary files behind, which are removed on the next start
+        fs::write(dir.join(".upload-1-0.part"), "left behind").unwrap();
+        FileManager::remove_temp_files(dir_name).unwrap();
+        assert_eq!(temp_file_count(), 0);
+        assert!(dir.join("upload.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn create_dirs_only_inside_base() {
+        let root = env::temp_dir().join(format!("web-server-dirs-{}", std::process::id()));
+        let base = root.join("uploads");
+        let outside = root.join("outside");
+        fs::create_dir_all(&base).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, base.join("link")).unwrap();
+        let base_dir = base.to_str().unwrap();
+
+        assert!(FileManager::create_dir(base_dir, Path::new("team/reports")).unwrap());
+        assert!(!FileManager::create_dir(base_dir, Path::new("team")).unwrap());
+        assert!(base.join("team/reports").is_dir());
+        assert_eq!(
+            FileManager::list_dirs(base_dir).unwrap(),
+            ["team", "team/reports"]
+        );
+
+        assert!(FileManager::create_dir(base_dir, Path::new("link/escaped")).is_err());
+        assert!(FileManager::create_dir(base_dir, Path::new("../escaped")).is_err());
+        assert!(!outside.join("escaped").exists());
+        assert!(!root.join("escaped").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn move_files_over_others_only_when_asked() {
+        let dir = env::temp_dir().join(format!("web-server-moves-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        let base_dir = dir.to_str().unwrap();
+
+        assert_eq!(
+            FileManager::move_file(base_dir, &dir.join("a.txt"), &dir.join("b.txt"), false)
+                .unwrap(),
+            MoveOutcome::Refused
+        );
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "b");
+
+        assert_eq!(
+            FileManager::move_file(base_dir, &dir.join("a.txt"), &dir.join("c.txt"), false)
+                .unwrap(),
+            MoveOutcome::Moved
+        );
+        assert_eq!(
+            FileManager::move_file(base_dir, &dir.join("c.txt"), &dir.join("b.txt"), true).unwrap(),
+            MoveOutcome::Replaced
+        );
+        assert!(!dir.join("a.txt").exists() && !dir.join("c.txt").exists());
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "a");
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
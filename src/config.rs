@@ -0,0 +1,698 @@
+use crate::common::AppError;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The configuration the server was started with, set once in `main`
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// A `Config` holds every setting of the server that can differ between deployments.
+///
+/// Settings are loaded from, in order of increasing precedence:
+/// 1. The defaults in `Config::default()`
+/// 2. A TOML configuration file, `config.toml` in the working directory unless another one is given
+///    with `--config` or `WEB_SERVER_CONFIG`
+/// 3. Environment variables, named after the setting with a `WEB_SERVER_` prefix, e.g.
+///    `WEB_SERVER_UPLOADS_DIR`
+/// 4. Command-line flags, named after the setting with dashes, e.g. `--uploads-dir`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Config {
+    /// The host and port the server listens on
+    pub(crate) address: String,
+    /// The number of worker threads handling requests
+    pub(crate) workers: usize,
+    /// The number of requests that can wait for a worker, before new ones are rejected
+    pub(crate) queue_size: usize,
+    /// The directory uploaded files are stored in
+    pub(crate) uploads_dir: String,
+    /// The file logs are appended to
+    pub(crate) log_file: String,
+    /// The largest request body accepted, in bytes
+    pub(crate) max_body_size: u64,
+    /// The file extensions that can be uploaded and viewed
+    pub(crate) allowed_extensions: Vec<String>,
+    /// How long in-flight requests are given to finish when the server shuts down, in seconds
+    pub(crate) shutdown_timeout: u64,
+    /// How long a client has to send the request line and headers of a request, in seconds
+    pub(crate) header_timeout: u64,
+    /// How long a client has to send the body of a request, in seconds
+    pub(crate) body_timeout: u64,
+    /// The slowest a request body may arrive, in bytes per second, or 0 for no minimum
+    pub(crate) min_body_rate: u64,
+    /// How long a connection may stay idle between requests before it is closed, in seconds
+    pub(crate) keep_alive_timeout: u64,
+    /// How long a client may go without taking any of a response before it is closed, in seconds
+    pub(crate) write_timeout: u64,
+    /// The longest request line accepted, in bytes
+    pub(crate) max_request_line_length: u64,
+    /// The longest header line accepted, in bytes
+    pub(crate) max_header_line_length: u64,
+    /// The most headers a request may have
+    pub(crate) max_header_count: usize,
+    /// A directory of templates that replace the embedded templates of the same name
+    pub(crate) theme_dir: Option<String>,
+    /// Whether pages are compiled again once their theme files change, so changes show right away
+    pub(crate) dev_mode: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "localhost:7878".to_string(),
+            workers: 4,
+            queue_size: 128,
+            uploads_dir: "uploads".to_string(),
+            log_file: "logs.txt".to_string(),
+            max_body_size: 1024 * 1024 * 1024,
+            allowed_extensions: ["txt", "png", "jpg", "pdf"].map(String::from).to_vec(),
+            shutdown_timeout: 30,
+            header_timeout: 10,
+            body_timeout: 300,
+            min_body_rate: 1024,
+            keep_alive_timeout: 5,
+            write_timeout: 30,
+            max_request_line_length: 8 * 1024,
+            max_header_line_length: 8 * 1024,
+            max_header_count: 100,
+            theme_dir: None,
+            dev_mode: false,
+        }
+    }
+}
+
+/// A value of a setting, as it was given in the configuration file, an environment variable or a flag
+#[derive(Debug, PartialEq)]
+enum ConfigValue {
+    Text(String),
+    Integer(i64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+impl Config {
+    /// The names of every setting, as used in the configuration file
+    const SETTINGS: [&'static str; 18] = [
+        "address",
+        "workers",
+        "queue_size",
+        "uploads_dir",
+        "log_file",
+        "max_body_size",
+        "allowed_extensions",
+        "shutdown_timeout",
+        "header_timeout",
+        "body_timeout",
+        "min_body_rate",
+        "keep_alive_timeout",
+        "write_timeout",
+        "max_request_line_length",
+        "max_header_line_length",
+        "max_header_count",
+        "theme_dir",
+        "dev_mode",
+    ];
+
+    /// The prefix of the environment variables that override settings
+    const ENV_PREFIX: &'static str = "WEB_SERVER_";
+
+    /// The help text printed for `--help`
+    pub(crate) const USAGE: &'static str = "\
+Usage: web-server [OPTIONS]
+
+Options:
+  --config <PATH>                   TOML configuration file [default: config.toml]
+  --address <HOST:PORT>             Address to listen on [default: localhost:7878]
+  --workers <COUNT>                 Number of worker threads [default: 4]
+  --queue-size <COUNT>              Requests that can wait for a worker before new ones get 503 [default: 128]
+  --uploads-dir <PATH>              Directory uploaded files are stored in [default: uploads]
+  --log-file <PATH>                 File logs are appended to [default: logs.txt]
+  --max-body-size <SIZE>            Largest request body, in bytes or with a KB, MB or GB suffix [default: 1GB]
+  --allowed-extensions <LIST>       Comma-separated file extensions that can be uploaded [default: txt,png,jpg,pdf]
+  --shutdown-timeout <SECONDS>      Time in-flight requests get to finish on shutdown [default: 30]
+  --header-timeout <SECONDS>        Time a client has to send the headers of a request [default: 10]
+  --body-timeout <SECONDS>          Time a client has to send the body of a request [default: 300]
+  --min-body-rate <SIZE>            Slowest rate a body may arrive at, per second, 0 for none [default: 1KB]
+  --keep-alive-timeout <SECONDS>    Time an idle connection is kept open between requests [default: 5]
+  --write-timeout <SECONDS>         Time a client may go without taking any of a response [default: 30]
+  --max-request-line-length <SIZE>  Longest request line accepted [default: 8KB]
+  --max-header-line-length <SIZE>   Longest header line accepted [default: 8KB]
+  --max-header-count <COUNT>        Most headers a request may have [default: 100]
+  --theme-dir <PATH>                Directory of templates that replace the embedded ones by name [default: none]
+  --dev-mode <BOOL>                 Compile pages again when their theme files change [default: false]
+  -h, --help                        Print this help
+
+Every option except --config and --help can also be set in the configuration file, using its name
+with underscores, or with an environment variable, e.g. WEB_SERVER_UPLOADS_DIR.";
+
+    /// Gets the configuration of the server, which is the default one until `Config::init()` is called
+    #[cfg(not(test))]
+    pub(crate) fn get() -> &'static Config {
+        CONFIG.get_or_init(Config::default)
+    }
+
+    /// Gets the configuration tests run with, which is loaded from a configuration file that only
+    /// moves uploads and logs from the working directory to a temporary directory of the test run.
+    /// The directory is removed when the test run exits.
+    #[cfg(test)]
+    pub(crate) fn get() -> &'static Config {
+        unsafe extern "C" {
+            fn atexit(callback: extern "C" fn()) -> i32;
+        }
+        extern "C" fn remove_test_dir() {
+            let _ = fs::remove_dir_all(Config::test_dir());
+        }
+
+        CONFIG.get_or_init(|| {
+            let dir = Self::test_dir();
+            let uploads_dir = dir.join("uploads");
+            fs::create_dir_all(&uploads_dir).expect("Failed to create uploads directory of tests");
+            // SAFETY: `atexit` only stores the callback, which is a plain function that never unwinds
+            unsafe { atexit(remove_test_dir) };
+
+            let contents = format!(
+                "uploads_dir = {:?}\nlog_file = {:?}\n",
+                uploads_dir.to_string_lossy(),
+                dir.join("logs.txt").to_string_lossy()
+            );
+            Config::from_sources(Some(("tests.toml", &contents)), |_| None, Vec::new())
+                .expect("Failed to load configuration of tests")
+        })
+    }
+
+    /// Gets the temporary directory the tests of this process write uploads and logs to
+    #[cfg(test)]
+    fn test_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("web-server-tests-{}", std::process::id()))
+    }
+
+    /// Sets the configuration of the server, which can only be done once, before it is first read
+    pub(crate) fn init(config: Config) {
+        if CONFIG.set(config).is_err() {
+            panic!("Configuration was already initialized");
+        }
+    }
+
+    /// Loads the configuration from the configuration file, the environment and command-line flags
+    ///
+    /// Arguments:
+    /// - **args**: The command-line arguments, without the name of the binary
+    /// - **get_env**: Gets the value of an environment variable, if it is set
+    ///
+    /// The flags are parsed first, as one of them may name the configuration file, which is read
+    /// before every source is passed to `Config::from_sources()`.
+    /// An `AppError::Invalid` describing the problem is returned if any source can't be read or
+    /// parsed, or any setting has an invalid value.
+    pub(crate) fn load<I, F>(args: I, get_env: F) -> Result<Config, AppError>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut config_path = None;
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(AppError::Invalid(format!("Unexpected argument: {arg}")));
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args.next().ok_or(AppError::Invalid(format!(
+                        "Missing value for flag: --{flag}"
+                    )))?;
+                    (flag.to_string(), value)
+                }
+            };
+
+            if name == "config" {
+                config_path = Some(value);
+            } else {
+                flags.push((name.replace('-', "_"), value));
+            }
+        }
+
+        // The default configuration file is optional, unlike one that was asked for
+        let config_path = config_path
+            .or_else(|| get_env(&format!("{}CONFIG", Self::ENV_PREFIX)))
+            .or_else(|| {
+                let default_path = "config.toml";
+                Path::new(default_path)
+                    .is_file()
+                    .then(|| default_path.to_string())
+            });
+        let contents = match &config_path {
+            Some(path) => Some(fs::read_to_string(path).map_err(|e| {
+                AppError::Invalid(format!("Failed to read configuration file {path}: {e}"))
+            })?),
+            None => None,
+        };
+        let toml = config_path.as_deref().zip(contents.as_deref());
+
+        Self::from_sources(toml, get_env, flags)
+    }
+
+    /// Builds the configuration from sources that were already read
+    ///
+    /// Arguments:
+    /// - **toml**: The path and contents of the configuration file, if there is one
+    /// - **get_env**: Gets the value of an environment variable, if it is set
+    /// - **flags**: The names and values of the command-line flags, with underscores in the names
+    ///
+    /// Each source is applied on top of the defaults in order of precedence, and the result is
+    /// validated, returning an `AppError::Invalid` describing the problem if anything is invalid.
+    pub(crate) fn from_sources<F>(
+        toml: Option<(&str, &str)>,
+        get_env: F,
+        flags: Vec<(String, String)>,
+    ) -> Result<Config, AppError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut config = Config::default();
+        if let Some((path, contents)) = toml {
+            config.apply_toml(contents, path)?;
+        }
+
+        for setting in Self::SETTINGS {
+            let env_name = format!("{}{}", Self::ENV_PREFIX, setting.to_uppercase());
+            if let Some(value) = get_env(&env_name) {
+                config.set(setting, ConfigValue::Text(value), &env_name)?;
+            }
+        }
+
+        for (name, value) in flags {
+            let source = format!("--{}", name.replace('_', "-"));
+            config.set(&name, ConfigValue::Text(value), &source)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Applies the settings in the contents of a TOML configuration file
+    ///
+    /// Arguments:
+    /// - **contents**: The contents of the file
+    /// - **path**: The path of the file, used in error messages
+    ///
+    /// Only the subset of TOML needed for the settings is supported: top-level keys with string,
+    /// integer, boolean or string array values, and comments. Arrays can span multiple lines.
+    fn apply_toml(&mut self, contents: &str, path: &str) -> Result<(), AppError> {
+        let mut lines = contents.lines().enumerate();
+
+        while let Some((index, line)) = lines.next() {
+            let location = format!("{path}:{}", index + 1);
+            let line = Self::strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                return Err(AppError::Invalid(format!(
+                    "{location}: Tables are not supported, settings must be top-level keys"
+                )));
+            }
+
+            let (key, value) = line.split_once('=').ok_or(AppError::Invalid(format!(
+                "{location}: Expected a `key = value` line"
+            )))?;
+            let key = key.trim();
+            let mut value = value.trim().to_string();
+
+            // Keep reading lines until the closing bracket of an array spanning multiple lines
+            if value.starts_with('[') {
+                while !value.ends_with(']') {
+                    let (_, next_line) = lines.next().ok_or(AppError::Invalid(format!(
+                        "{location}: Array of `{key}` is never closed"
+                    )))?;
+                    value.push_str(Self::strip_comment(next_line).trim());
+                }
+            }
+
+            let value = Self::parse_toml_value(&value).map_err(|e| {
+                AppError::Invalid(format!("{location}: Invalid value of `{key}`: {e}"))
+            })?;
+            self.set(key, value, &location)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes a comment from the end of a TOML line, ignoring `#` characters inside strings
+    fn strip_comment(line: &str) -> &str {
+        let mut quote = None;
+        for (index, c) in line.char_indices() {
+            match (c, quote) {
+                ('"' | '\'', None) => quote = Some(c),
+                (c, Some(open_quote)) if c == open_quote => quote = None,
+                ('#', None) => return &line[..index],
+                _ => {}
+            }
+        }
+        line
+    }
+
+    /// Parses a TOML value, which can be a string, an integer, a boolean or an array of strings
+    fn parse_toml_value(value: &str) -> Result<ConfigValue, String> {
+        match value {
+            "true" => return Ok(ConfigValue::Bool(true)),
+            "false" => return Ok(ConfigValue::Bool(false)),
+            _ => {}
+        }
+        if let Some(items) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            let items = items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| match Self::parse_toml_value(item) {
+                    Ok(ConfigValue::Text(text)) => Ok(text),
+                    _ => Err(format!("Array items must be strings: {item}")),
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+            return Ok(ConfigValue::List(items));
+        }
+
+        let is_quoted =
+            |quote: char| value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote);
+        if is_quoted('\'') {
+            return Ok(ConfigValue::Text(value[1..value.len() - 1].to_string()));
+        }
+        if is_quoted('"') {
+            let mut text = String::new();
+            let mut chars = value[1..value.len() - 1].chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    text.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('\\') => text.push('\\'),
+                    Some('"') => text.push('"'),
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    escape => {
+                        return Err(format!(
+                            "Unsupported escape sequence: \\{}",
+                            escape.unwrap_or(' ')
+                        ));
+                    }
+                }
+            }
+            return Ok(ConfigValue::Text(text));
+        }
+
+        value
+            .replace('_', "")
+            .parse()
+            .map(ConfigValue::Integer)
+            .map_err(|_| {
+                format!("Expected a string, integer, boolean or array of strings, found {value}")
+            })
+    }
+
+    /// Sets a setting from a value
+    ///
+    /// Arguments:
+    /// - **name**: The name of the setting
+    /// - **value**: The new value of the setting
+    /// - **source**: Where the value came from, used in error messages
+    ///
+    /// Values from environment variables and flags are always text, so text is also accepted for
+    /// numbers and booleans, which are parsed, and lists, which are split by commas. An empty
+    /// `theme_dir` means no theme.
+    fn set(&mut self, name: &str, value: ConfigValue, source: &str) -> Result<(), AppError> {
+        let invalid = |message: &str| AppError::Invalid(format!("{source}: {message}"));
+
+        match (name, value) {
+            ("address", ConfigValue::Text(address)) => self.address = address,
+            ("uploads_dir", ConfigValue::Text(dir)) => self.uploads_dir = dir,
+            ("log_file", ConfigValue::Text(file)) => self.log_file = file,
+            ("theme_dir", ConfigValue::Text(dir)) => {
+                self.theme_dir = Some(dir).filter(|dir| !dir.trim().is_empty());
+            }
+            ("dev_mode", value @ (ConfigValue::Bool(_) | ConfigValue::Text(_))) => {
+                self.dev_mode = Self::parse_bool(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("workers", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.workers = Self::parse_number(name, value).map_err(|e| invalid(&e))? as usize;
+            }
+            ("queue_size", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.queue_size =
+                    Self::parse_number(name, value).map_err(|e| invalid(&e))? as usize;
+            }
+            ("shutdown_timeout", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.shutdown_timeout = Self::parse_number(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("header_timeout", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.header_timeout = Self::parse_number(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("body_timeout", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.body_timeout = Self::parse_number(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("keep_alive_timeout", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.keep_alive_timeout =
+                    Self::parse_number(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("write_timeout", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.write_timeout = Self::parse_number(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("max_header_count", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.max_header_count =
+                    Self::parse_number(name, value).map_err(|e| invalid(&e))? as usize;
+            }
+            ("max_body_size", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.max_body_size =
+                    Self::parse_size_value(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("min_body_rate", value @ (ConfigValue::Integer(_) | ConfigValue::Text(_))) => {
+                self.min_body_rate =
+                    Self::parse_size_value(name, value).map_err(|e| invalid(&e))?;
+            }
+            (
+                "max_request_line_length",
+                value @ (ConfigValue::Integer(_) | ConfigValue::Text(_)),
+            ) => {
+                self.max_request_line_length =
+                    Self::parse_size_value(name, value).map_err(|e| invalid(&e))?;
+            }
+            (
+                "max_header_line_length",
+                value @ (ConfigValue::Integer(_) | ConfigValue::Text(_)),
+            ) => {
+                self.max_header_line_length =
+                    Self::parse_size_value(name, value).map_err(|e| invalid(&e))?;
+            }
+            ("allowed_extensions", ConfigValue::List(extensions)) => {
+                self.allowed_extensions = extensions;
+            }
+            ("allowed_extensions", ConfigValue::Text(extensions)) => {
+                self.allowed_extensions = extensions
+                    .split(',')
+                    .map(|extension| extension.trim().to_string())
+                    .filter(|extension| !extension.is_empty())
+                    .collect();
+            }
+            (name, value) if Self::SETTINGS.contains(&name) => {
+                return Err(invalid(&format!(
+                    "Wrong type of value for `{name}`: {value:?}"
+                )));
+            }
+            (name, _) => return Err(invalid(&format!("Unknown setting `{name}`"))),
+        }
+
+        Ok(())
+    }
+
+    /// Parses a number, which can be given as an integer or as text
+    fn parse_number(name: &str, value: ConfigValue) -> Result<u64, String> {
+        match value {
+            ConfigValue::Integer(number) => u64::try_from(number)
+                .map_err(|_| format!("`{name}` must be a positive number, found {number}")),
+            ConfigValue::Text(text) => text
+                .trim()
+                .parse()
+                .map_err(|_| format!("`{name}` must be a positive number, found {text}")),
+            value => Err(format!("`{name}` must be a number, found {value:?}")),
+        }
+    }
+
+    /// Parses a boolean, which can be given as a boolean or as `true` or `false` text
+    fn parse_bool(name: &str, value: ConfigValue) -> Result<bool, String> {
+        match value {
+            ConfigValue::Bool(value) => Ok(value),
+            ConfigValue::Text(text) => match text.trim().to_lowercase().as_str() {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => Err(format!("`{name}` must be true or false, found {text}")),
+            },
+            value => Err(format!("`{name}` must be true or false, found {value:?}")),
+        }
+    }
+
+    /// Parses a size in bytes, which can be given as an integer or as text with a KB, MB or GB suffix
+    fn parse_size_value(name: &str, value: ConfigValue) -> Result<u64, String> {
+        match value {
+            ConfigValue::Integer(size) => u64::try_from(size)
+                .map_err(|_| format!("`{name}` must be a positive number, found {size}")),
+            ConfigValue::Text(size) => Self::parse_size(&size).ok_or(format!(
+                "`{name}` must be a number of bytes, optionally with a KB, MB or GB suffix, found {size}"
+            )),
+            value => Err(format!("`{name}` must be a size, found {value:?}")),
+        }
+    }
+
+    /// Parses a size in bytes, which may have a KB, MB or GB suffix, e.g. `50MB`
+    fn parse_size(size: &str) -> Option<u64> {
+        let size = size.trim().to_uppercase();
+        let (number, multiplier) = [
+            ("GB", 1024 * 1024 * 1024),
+            ("MB", 1024 * 1024),
+            ("KB", 1024),
+        ]
+        .into_iter()
+        .find_map(|(suffix, multiplier)| {
+            size.strip_suffix(suffix)
+                .map(|number| (number.trim(), multiplier))
+        })
+        .unwrap_or((size.as_str(), 1));
+
+        number.parse::<u64>().ok()?.checked_mul(multiplier)
+    }
+
+    /// Validates every setting, returning all the problems found in a single error
+    fn validate(&mut self) -> Result<(), AppError> {
+        let mut errors = Vec::new();
+
+        if self.address.to_socket_addrs().is_err() {
+            errors.push(format!(
+                "`address` must be a host and port, such as localhost:7878, found {}",
+                self.address
+            ));
+        }
+        if self.workers == 0 {
+            errors.push("`workers` must be at least 1".to_string());
+        }
+        if self.queue_size == 0 {
+            errors.push("`queue_size` must be at least 1".to_string());
+        }
+        if self.uploads_dir.trim().is_empty() {
+            errors.push("`uploads_dir` must not be empty".to_string());
+        } else if Path::new(&self.uploads_dir).exists() && !Path::new(&self.uploads_dir).is_dir() {
+            errors.push(format!(
+                "`uploads_dir` is not a directory: {}",
+                self.uploads_dir
+            ));
+        }
+        if let Some(theme_dir) = &self.theme_dir
+            && !Path::new(theme_dir).is_dir()
+        {
+            errors.push(format!("`theme_dir` is not a directory: {theme_dir}"));
+        }
+        if self.log_file.trim().is_empty() {
+            errors.push("`log_file` must not be empty".to_string());
+        } else if Path::new(&self.log_file).is_dir() {
+            errors.push(format!("`log_file` is a directory: {}", self.log_file));
+        }
+        let must_be_positive = [
+            ("max_body_size", self.max_body_size),
+            ("header_timeout", self.header_timeout),
+            ("body_timeout", self.body_timeout),
+            ("keep_alive_timeout", self.keep_alive_timeout),
+            ("write_timeout", self.write_timeout),
+            ("max_request_line_length", self.max_request_line_length),
+            ("max_header_line_length", self.max_header_line_length),
+            ("max_header_count", self.max_header_count as u64),
+        ];
+        for (name, value) in must_be_positive {
+            if value == 0 {
+                errors.push(format!("`{name}` must be greater than 0"));
+            }
+        }
+
+        // Extensions are compared without the dot, but writing it is a natural mistake
+        for extension in &mut self.allowed_extensions {
+            if let Some(stripped) = extension.strip_prefix('.') {
+                *extension = stripped.to_string();
+            }
+        }
+        if self.allowed_extensions.is_empty() {
+            errors.push("`allowed_extensions` must contain at least one extension".to_string());
+        }
+        for extension in &self.allowed_extensions {
+            if extension.is_empty() || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+                errors.push(format!(
+                    "`allowed_extensions` must only contain letters and digits, found {extension:?}"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Invalid(errors.join("\n")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::AppError;
+    use crate::config::Config;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn load_config_in_order_of_precedence() {
+        let path = env::temp_dir().join(format!("web-server-config-{}.toml", std::process::id()));
+        let contents = r#"
+            # Settings for this deployment
+            address = "127.0.0.1:8080"
+            workers = 8
+            uploads_dir = 'files' # Relative to the working directory
+            dev_mode = true
+            allowed_extensions = [
+                "txt",
+                ".md",
+            ]
+        "#;
+        fs::write(&path, contents).unwrap();
+
+        let args = [
+            format!("--config={}", path.display()),
+            "--workers".to_string(),
+            "2".to_string(),
+        ];
+        let config = Config::load(args, |name| match name {
+            "WEB_SERVER_WORKERS" => Some("16".to_string()),
+            "WEB_SERVER_MAX_BODY_SIZE" => Some("50MB".to_string()),
+            _ => None,
+        });
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.address, "127.0.0.1:8080");
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.uploads_dir, "files");
+        assert_eq!(config.log_file, "logs.txt");
+        assert_eq!(config.max_body_size, 50 * 1024 * 1024);
+        assert_eq!(config.allowed_extensions, ["txt", "md"]);
+        assert!(config.dev_mode);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let args = ["--workers=0", "--allowed-extensions=txt,p/ng"].map(String::from);
+        let Err(AppError::Invalid(message)) = Config::load(args, |_| None) else {
+            panic!("Invalid configuration was accepted");
+        };
+        assert!(message.contains("`workers` must be at least 1"));
+        assert!(message.contains("\"p/ng\""));
+
+        let args = ["--port", "80"].map(String::from);
+        assert!(Config::load(args, |_| None).is_err());
+        let args = ["--workers", "many"].map(String::from);
+        assert!(Config::load(args, |_| None).is_err());
+        let args = ["--dev-mode", "yes"].map(String::from);
+        assert!(Config::load(args, |_| None).is_err());
+    }
+}
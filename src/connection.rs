@@ -0,0 +1,516 @@
+use crate::common::AppError;
+use crate::config::Config;
+use crate::http::{BodyDrain, RequestParser, ResponseWriter};
+use crate::poller::Interest;
+use std::io::{ErrorKind, Read};
+use std::mem;
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+/// The maximum number of requests served on a single connection, so that one client can't hold
+/// onto it forever
+pub(crate) const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+/// The most bytes read from a connection each time it is ready, so that a client sending a lot
+/// can't keep the event loop from the other connections
+const READ_LIMIT: usize = 256 * 1024;
+
+/// The size of each read from a connection
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// How long a body may arrive before the rate it arrives at is checked, so that a slow start, such as
+/// a client waiting for `100 Continue`, isn't mistaken for a slow client
+const MIN_BODY_RATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The state of a `Connection`
+/// - **Reading**: Waiting for the rest of a request, or for the next one
+/// - **Handling**: A worker is handling the request that arrived
+/// - **Writing**: The response is being written, as fast as the client takes it
+/// - **Closed**: The connection is done with, and is removed from the event loop
+enum ConnectionState {
+    Reading,
+    Handling,
+    Writing {
+        response_writer: ResponseWriter,
+        keep_alive: bool,
+    },
+    Closed,
+}
+
+/// What reading from a `Connection` produced
+/// - **Pending**: Nothing to act on yet
+/// - **Request**: A whole request arrived, and is waiting to be handled
+/// - **Invalid**: The request couldn't be parsed, and is waiting to be answered with an error
+pub(crate) enum ReadOutcome {
+    Pending,
+    Request(Box<RequestParser>),
+    Invalid(AppError),
+}
+
+/// Why a `Connection` timed out
+/// - **Idle**: Nothing of a request arrived, so the connection can be closed without a response
+/// - **Request**: A request didn't arrive in time, and is waiting to be answered with a 408 status
+/// - **Response**: The client stopped taking the response, so the connection can only be closed
+pub(crate) enum Timeout {
+    Idle,
+    Request(AppError),
+    Response(AppError),
+}
+
+/// A `Connection` is a non-blocking `TcpStream` and what the event loop knows about it.
+/// It never blocks: it reads and parses whatever has arrived when its stream is readable, and
+/// writes as much of the response as the stream takes when it is writable, keeping track of where
+/// it is in between, so that a thread is only ever busy with it while there is something to do.
+pub(crate) struct Connection {
+    stream: TcpStream,
+    /// Bytes read from the stream that weren't parsed yet
+    buffer: Vec<u8>,
+    parser: RequestParser,
+    state: ConnectionState,
+    /// What the connection was last registered to be waited on for
+    registered_interest: Interest,
+    requests_served: usize,
+    /// When something last arrived on the connection, or was written to it
+    last_active: Instant,
+    /// When the first bytes of the current request arrived, or the connection was accepted
+    request_started: Instant,
+    /// When the head of the current request was parsed, after which its body is arriving
+    body_started: Option<Instant>,
+    /// The client closed its side of the connection, so no more requests can arrive on it
+    read_closed: bool,
+}
+
+impl Connection {
+    /// Creates a new `Connection` waiting for its first request
+    ///
+    /// Arguments:
+    /// - **stream**: The `TcpStream` of the connection, which must be non-blocking
+    pub(crate) fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            buffer: Vec::new(),
+            parser: RequestParser::new(),
+            state: ConnectionState::Reading,
+            registered_interest: Interest::Read,
+            requests_served: 0,
+            last_active: Instant::now(),
+            request_started: Instant::now(),
+            body_started: None,
+            read_closed: false,
+        }
+    }
+
+    /// Gets the `TcpStream` of the connection, to register it with a `Poller`
+    pub(crate) fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Gets the number of requests that arrived on the connection, including the current one
+    pub(crate) fn requests_served(&self) -> usize {
+        self.requests_served
+    }
+
+    /// Gets what the connection is waited on for in its current state
+    ///
+    /// A connection whose request body is backlogged isn't read from until workers wrote enough of
+    /// it, so that a client can't send faster than the disk takes it.
+    fn interest(&self) -> Interest {
+        match self.state {
+            ConnectionState::Reading if self.parser.is_body_backlogged() => Interest::Nothing,
+            ConnectionState::Reading => Interest::Read,
+            ConnectionState::Writing { .. } => Interest::Write,
+            ConnectionState::Handling | ConnectionState::Closed => Interest::Nothing,
+        }
+    }
+
+    /// Gets what the connection is now waited on for, if that changed since it was last registered,
+    /// which is then taken to be registered
+    ///
+    /// A new connection is expected to be registered to be waited on for reading.
+    pub(crate) fn take_interest_change(&mut self) -> Option<Interest> {
+        let interest = self.interest();
+        if interest == self.registered_interest {
+            return None;
+        }
+        self.registered_interest = interest;
+        Some(interest)
+    }
+
+    /// Gets a `BodyDrain` for the chunks of the body of the request arriving on the connection,
+    /// which a worker must run to write them, if any are waiting for one
+    pub(crate) fn take_body_drain(&self) -> Option<BodyDrain> {
+        match self.state {
+            ConnectionState::Reading => self.parser.take_body_drain(),
+            _ => None,
+        }
+    }
+
+    /// Checks if the connection is done with
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, ConnectionState::Closed)
+    }
+
+    /// Checks if the connection is waiting for a next request that hasn't started arriving yet, in
+    /// which case it can be closed without leaving the client without a response
+    ///
+    /// The client of a connection that has no requests yet is still owed a response, as its
+    /// connection was accepted.
+    pub(crate) fn is_between_requests(&self) -> bool {
+        matches!(self.state, ConnectionState::Reading)
+            && self.requests_served > 0
+            && self.buffer.is_empty()
+            && !self.parser.is_started()
+    }
+
+    /// Checks if the connection has been waiting on the client for longer than the `Config` allows
+    ///
+    /// Arguments:
+    /// - **now**: The current time
+    ///
+    /// A request must have its head arrive within the header timeout, and its body within the body
+    /// timeout, at no less than the minimum body rate on average, which isn't checked while the body
+    /// is backlogged, as it is then the server that is slow. A connection between requests is
+    /// closed after the keep-alive timeout, and so is a new one that nothing arrived on within the
+    /// header timeout. A response must have some of it taken within the write timeout.  
+    /// A connection whose request is with a worker isn't waiting on the client, so it never times out.
+    /// Handlers only work on local files and templates, and a worker can't be interrupted anyway, so
+    /// a deadline could only drop the response, not free the thread. On shutdown, the event loop
+    /// stops waiting for these connections once the shutdown timeout runs out, and the thread pool
+    /// stops waiting for their workers, so a stuck handler can't keep the server from stopping.
+    pub(crate) fn check_timeout(&self, now: Instant) -> Option<Timeout> {
+        let config = Config::get();
+        let seconds = Duration::from_secs;
+        let head_elapsed = now.duration_since(self.request_started);
+
+        match self.state {
+            ConnectionState::Handling | ConnectionState::Closed => None,
+            ConnectionState::Writing { .. } => {
+                (now.duration_since(self.last_active) >= seconds(config.write_timeout)).then(|| {
+                    Timeout::Response(AppError::Timeout(format!(
+                        "Client took none of the response for {}s",
+                        config.write_timeout
+                    )))
+                })
+            }
+            ConnectionState::Reading if self.is_between_requests() => {
+                (now.duration_since(self.last_active) >= seconds(config.keep_alive_timeout))
+                    .then_some(Timeout::Idle)
+            }
+            ConnectionState::Reading => match self.body_started {
+                None if head_elapsed < seconds(config.header_timeout) => None,
+                None if self.requests_served == 0 && self.buffer.is_empty() => Some(Timeout::Idle),
+                None => Some(Timeout::Request(AppError::Timeout(format!(
+                    "Request head was not received within {}s",
+                    config.header_timeout
+                )))),
+                Some(body_started) => {
+                    let elapsed = now.duration_since(body_started);
+                    if elapsed >= seconds(config.body_timeout) {
+                        return Some(Timeout::Request(AppError::Timeout(format!(
+                            "Request body was not received within {}s",
+                            config.body_timeout
+                        ))));
+                    }
+
+                    let rate = self.parser.body_received() as f64 / elapsed.as_secs_f64();
+                    (config.min_body_rate > 0
+                        && elapsed >= MIN_BODY_RATE_GRACE_PERIOD
+                        && !self.parser.is_body_backlogged()
+                        && rate < config.min_body_rate as f64)
+                        .then(|| {
+                            Timeout::Request(AppError::Timeout(format!(
+                                "Request body arrived at {:.0} bytes/s, below the minimum of {} bytes/s",
+                                rate, config.min_body_rate
+                            )))
+                        })
+                }
+            },
+        }
+    }
+
+    /// Reads what has arrived on the connection and parses it
+    ///
+    /// The stream is read until it has nothing more, or `READ_LIMIT` is reached, in which case it is
+    /// still readable, and is read again on the next turn of the event loop. Reading stops early
+    /// once a whole request has arrived, as the next one can't be handled before it is answered,
+    /// and once its body is backlogged, until workers wrote enough of it.
+    /// The connection is closed if the client closed it before sending a whole request.
+    pub(crate) fn read(&mut self) -> ReadOutcome {
+        if !matches!(self.state, ConnectionState::Reading) {
+            return ReadOutcome::Pending;
+        }
+
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let mut bytes_read_total = 0;
+        while bytes_read_total < READ_LIMIT && !self.parser.is_body_backlogged() {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    break;
+                }
+                Ok(bytes_read) => {
+                    bytes_read_total += bytes_read;
+                    self.last_active = Instant::now();
+                    if self.buffer.is_empty() && !self.parser.is_started() {
+                        self.request_started = self.last_active;
+                    }
+                    self.buffer.extend_from_slice(&chunk[..bytes_read]);
+
+                    match self.parse() {
+                        ReadOutcome::Pending => {}
+                        outcome => return outcome,
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.close();
+                    return ReadOutcome::Pending;
+                }
+            }
+        }
+
+        if self.read_closed {
+            self.close();
+        }
+        ReadOutcome::Pending
+    }
+
+    /// Parses the bytes waiting in the buffer
+    ///
+    /// A client sending `Expect: 100-continue` is told to go on with the body once the head of its
+    /// request was parsed without an error. The interim response is written like any other, and
+    /// the connection goes back to reading the request once all of it was written.
+    fn parse(&mut self) -> ReadOutcome {
+        match self.parser.parse(&mut self.buffer) {
+            Ok(true) => {
+                self.requests_served += 1;
+                self.body_started = None;
+                self.state = ConnectionState::Handling;
+                ReadOutcome::Request(Box::new(mem::take(&mut self.parser)))
+            }
+            Ok(false) => {
+                if self.parser.is_started() && self.body_started.is_none() {
+                    self.body_started = Some(Instant::now());
+                }
+                if self.parser.take_continue_expected() {
+                    return self.respond(ResponseWriter::continue_response(), true);
+                }
+                ReadOutcome::Pending
+            }
+            Err(app_error) => {
+                self.state = ConnectionState::Handling;
+                ReadOutcome::Invalid(app_error)
+            }
+        }
+    }
+
+    /// Starts writing the response to the current request
+    ///
+    /// Arguments:
+    /// - **response_writer**: The `ResponseWriter` of the response
+    /// - **keep_alive**: Whether the connection stays open for the next request afterwards
+    ///
+    /// As much of the response as the stream takes is written right away, which is usually all of it.
+    pub(crate) fn respond(
+        &mut self,
+        response_writer: ResponseWriter,
+        keep_alive: bool,
+    ) -> ReadOutcome {
+        self.last_active = Instant::now();
+        self.state = ConnectionState::Writing {
+            response_writer,
+            keep_alive,
+        };
+        self.write()
+    }
+
+    /// Writes as much of the response as the stream takes
+    ///
+    /// Once the whole response is written, the connection is closed, unless it is kept alive, in
+    /// which case it goes back to reading, starting with any bytes of the next request that
+    /// already arrived.
+    pub(crate) fn write(&mut self) -> ReadOutcome {
+        let ConnectionState::Writing {
+            response_writer,
+            keep_alive,
+        } = &mut self.state
+        else {
+            return ReadOutcome::Pending;
+        };
+
+        let result = response_writer.write_some(&mut self.stream);
+        if result.is_ok() {
+            self.last_active = Instant::now();
+        }
+        match result {
+            Ok(true) if *keep_alive && !self.read_closed => {
+                self.state = ConnectionState::Reading;
+                self.request_started = self.last_active;
+                self.parse()
+            }
+            Ok(true) => {
+                self.close();
+                ReadOutcome::Pending
+            }
+            Ok(false) => ReadOutcome::Pending,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                ReadOutcome::Pending
+            }
+            Err(_) => {
+                self.close();
+                ReadOutcome::Pending
+            }
+        }
+    }
+
+    /// Closes the connection
+    pub(crate) fn close(&mut self) {
+        // The client may have already closed its end of the connection
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.state = ConnectionState::Closed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::{Connection, ReadOutcome};
+    use crate::http::{Response, ResponseBody};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Opens a connection on a free port, returning the client's end of it and the `Connection` of
+    /// the server's end
+    fn connect() -> (TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        (client, Connection::new(stream))
+    }
+
+    /// Reads from a connection until something other than `ReadOutcome::Pending` comes of it
+    fn read_until_request(connection: &mut Connection) -> ReadOutcome {
+        for _ in 0..200 {
+            match connection.read() {
+                ReadOutcome::Pending => thread::sleep(Duration::from_millis(10)),
+                outcome => return outcome,
+            }
+        }
+        panic!("No request arrived on the connection");
+    }
+
+    fn respond(connection: &mut Connection, body: &str, keep_alive: bool) -> ReadOutcome {
+        let response_writer = Response::builder()
+            .body(ResponseBody::Text(body.to_string()))
+            .build()
+            .into_writer()
+            .unwrap();
+        connection.respond(response_writer, keep_alive)
+    }
+
+    #[test]
+    fn serve_pipelined_requests_on_one_connection() {
+        let (mut client, mut connection) = connect();
+        client
+            .write_all(
+                b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\nGET /second HTTP/1.1\r\n\r\n",
+            )
+            .unwrap();
+
+        let ReadOutcome::Request(parser) = read_until_request(&mut connection) else {
+            panic!("First request could not be parsed");
+        };
+        assert_eq!(parser.into_request().unwrap().path.path(), "/first");
+        assert_eq!(connection.requests_served(), 1);
+
+        // The second request already arrived, so it is parsed as soon as the first is answered
+        let ReadOutcome::Request(parser) = respond(&mut connection, "first", true) else {
+            panic!("Pipelined request was not parsed after the first response");
+        };
+        assert_eq!(parser.into_request().unwrap().path.path(), "/second");
+        assert_eq!(connection.requests_served(), 2);
+
+        assert!(matches!(
+            respond(&mut connection, "second", false),
+            ReadOutcome::Pending
+        ));
+        assert!(connection.is_closed());
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(responses.find("first").unwrap() < responses.find("second").unwrap());
+    }
+
+    #[test]
+    fn tell_client_to_continue_before_reading_the_body() {
+        let (mut client, mut connection) = connect();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n")
+            .unwrap();
+
+        // The interim response is written once the head arrived, before any of the body
+        let interim = b"HTTP/1.1 100 Continue\r\n\r\n";
+        let mut received = Vec::new();
+        client
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        for _ in 0..200 {
+            assert!(matches!(connection.read(), ReadOutcome::Pending));
+            let mut chunk = [0; 64];
+            if let Ok(bytes_read) = client.read(&mut chunk) {
+                received.extend_from_slice(&chunk[..bytes_read]);
+            }
+            if received.len() >= interim.len() {
+                break;
+            }
+        }
+        assert_eq!(received, interim);
+
+        client.write_all(b"body").unwrap();
+        let ReadOutcome::Request(parser) = read_until_request(&mut connection) else {
+            panic!("Request could not be parsed after the interim response");
+        };
+        assert_eq!(parser.into_request().unwrap().path.path(), "/");
+    }
+
+    #[test]
+    fn wait_for_next_request_on_kept_alive_connection() {
+        let (mut client, mut connection) = connect();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(
+            read_until_request(&mut connection),
+            ReadOutcome::Request(_)
+        ));
+        assert!(!connection.is_between_requests());
+
+        assert!(matches!(
+            respond(&mut connection, "index", true),
+            ReadOutcome::Pending
+        ));
+        assert!(!connection.is_closed());
+        assert!(connection.is_between_requests());
+
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let ReadOutcome::Request(parser) = read_until_request(&mut connection) else {
+            panic!("Next request could not be parsed");
+        };
+        assert_eq!(parser.into_request().unwrap().path.path(), "/metrics");
+        assert_eq!(connection.requests_served(), 2);
+
+        // A client closing its end between requests closes the connection
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        respond(&mut connection, "metrics", true);
+        for _ in 0..200 {
+            connection.read();
+            if connection.is_closed() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(connection.is_closed());
+    }
+}
@@ -1,71 +1,776 @@
-use crate::common::{AppError, FileManager};
+use crate::common::{AppError, FileManager, MoveOutcome};
+use crate::config::Config;
 use crate::http::{
-    HttpHeader, HttpMethod, HttpStatus, Request, RequestBody, Response, ResponseBody,
+    ByteRange, HttpHeader, HttpMethod, HttpStatus, Request, RequestBody, Response, ResponseBody,
+    Url,
 };
+use crate::metrics::Metrics;
+use crate::router::{RouteParams, Router};
+use crate::template::{Context, Template, Value};
 use crate::warn;
 use crate::{Time, log_error};
-use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+use std::time::SystemTime;
+
+/// The pages of the server, compiled with the theme of the `Config`
+static TEMPLATES: OnceLock<TemplateCache> = OnceLock::new();
+
+/// A compiled page, with the templates it was compiled from, and the time the theme file of each
+/// of them was last modified, or `None` for the ones that were embedded
+struct CompiledPage {
+    template: Arc<Template>,
+    sources: Vec<(String, Option<SystemTime>)>,
+}
+
+/// A `TemplateCache` holds the compiled pages, along with the theme they are compiled with
+struct TemplateCache {
+    /// A directory of templates that replace the embedded templates of the same name
+    theme_dir: Option<PathBuf>,
+    /// Whether pages are compiled again once their theme files change
+    dev_mode: bool,
+    /// The compiled pages, by their name
+    pages: RwLock<HashMap<String, CompiledPage>>,
+}
+
+impl TemplateCache {
+    /// Creates a new `TemplateCache` with no compiled pages
+    ///
+    /// Arguments:
+    /// - **theme_dir**: The directory of templates that replace the embedded ones, if there is one
+    /// - **dev_mode**: Whether pages are compiled again once their theme files change
+    fn new(theme_dir: Option<PathBuf>, dev_mode: bool) -> Self {
+        TemplateCache {
+            theme_dir,
+            dev_mode,
+            pages: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Gets the source of a template by its name, with the time its theme file was last modified
+    ///
+    /// Arguments:
+    /// - **name**: The name of the template, which must be one of the embedded templates
+    ///
+    /// The template is read from the theme directory if it has a file of that name, falling back to
+    /// the embedded template, which has no modification time. Only the names of embedded templates
+    /// are looked up, so a theme can't include a file from outside its directory.
+    fn load(&self, name: &str) -> Option<(String, Option<SystemTime>)> {
+        let embedded = Templates::embedded(name)?;
+
+        if let Some(theme_dir) = &self.theme_dir {
+            let path = theme_dir.join(name);
+            let modified = self.theme_file_modified(name);
+            match fs::read_to_string(&path) {
+                Ok(source) => return Some((source, modified)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!(
+                    "Failed to read theme template {}, using the embedded one: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        Some((embedded.to_string(), None))
+    }
+
+    /// Gets the time the theme file of a template was last modified, or `None` if there is no
+    /// theme file for it
+    fn theme_file_modified(&self, name: &str) -> Option<SystemTime> {
+        let theme_dir = self.theme_dir.as_ref()?;
+        fs::metadata(theme_dir.join(name))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Checks if every template a page was compiled from is still the same, so it doesn't need to
+    /// be compiled again
+    fn is_current(&self, page: &CompiledPage) -> bool {
+        page.sources
+            .iter()
+            .all(|(name, modified)| self.theme_file_modified(name) == *modified)
+    }
+
+    /// Compiles a page, with the names of the values it is rendered with
+    ///
+    /// The templates loaded while compiling are recorded with their modification times, so that
+    /// the page is only compiled again in development mode once one of them changes.
+    fn compile(&self, name: &str) -> Result<CompiledPage, AppError> {
+        let (_, values) = Templates::PAGES
+            .iter()
+            .find(|(page, _)| *page == name)
+            .ok_or(AppError::Unknown(format!("Template {name} is not a page")))?;
+
+        let sources = RefCell::new(Vec::new());
+        let load = |name: &str| {
+            let (source, modified) = self.load(name)?;
+            sources.borrow_mut().push((name.to_string(), modified));
+            Some(source)
+        };
+        let template = Template::compile(name, &load, values)?;
+
+        Ok(CompiledPage {
+            template: Arc::new(template),
+            sources: sources.into_inner(),
+        })
+    }
+
+    /// Gets a compiled `Template` by its name
+    ///
+    /// Arguments:
+    /// - **name**: The name of the page, one of the constants of `Templates`
+    ///
+    /// The page compiled by `Templates::init()` is returned, unless the cache is in development
+    /// mode and a template it was compiled from was changed, added to or removed from the theme
+    /// since, in which case it is compiled again, so that changes to the theme show without a
+    /// restart. If that fails, the error is logged once, and the last page that compiled is returned
+    /// until the theme changes again.  
+    /// A page that isn't compiled yet is compiled when it is first needed, which panics if it
+    /// fails, as the embedded templates are all compiled by the tests.
+    fn get(&self, name: &str) -> Arc<Template> {
+        let compiled = self
+            .pages
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .map(|page| {
+                let is_current = !self.dev_mode || self.is_current(page);
+                (page.template.clone(), page.sources.clone(), is_current)
+            });
+        if let Some((template, _, true)) = compiled {
+            return template;
+        }
+
+        let page = match (self.compile(name), compiled) {
+            (Ok(page), _) => page,
+            (Err(error), Some((template, sources, _))) => {
+                log_error!("Failed to reload template {}: {:?}", name, error);
+                let sources = sources
+                    .into_iter()
+                    .map(|(name, _)| {
+                        let modified = self.theme_file_modified(&name);
+                        (name, modified)
+                    })
+                    .collect();
+                CompiledPage { template, sources }
+            }
+            (Err(error), None) => panic!("Failed to compile template {name}: {error:?}"),
+        };
+        let template = page.template.clone();
+        self.pages
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_string(), page);
+        template
+    }
+}
 
 /// This stores the HTML templates as strings in the binary during compile time, reducing the
-/// dependency on a templates folder's existence
-struct Templates;
+/// dependency on a templates folder's existence. Any of them can be replaced by a file of the same
+/// name in the theme directory of the `Config`, so the look of the pages can be changed without
+/// rebuilding.
+pub(crate) struct Templates;
 
 impl Templates {
-    const ACCESS_DENIED: &'static str = include_str!("../templates/access-denied.html");
-    const BAD_REQUEST: &'static str = include_str!("../templates/bad-request.html");
-    const FILE_NOT_FOUND: &'static str = include_str!("../templates/file-not-found.html");
-    const INDEX: &'static str = include_str!("../templates/index.html");
-    const PAGE_NOT_FOUND: &'static str = include_str!("../templates/page-not-found.html");
-    const SERVER_ERROR: &'static str = include_str!("../templates/server-error.html");
-    const UPLOAD: &'static str = include_str!("../templates/upload.html");
+    const ACCESS_DENIED: &'static str = "access-denied.html";
+    const BAD_REQUEST: &'static str = "bad-request.html";
+    const FILE_NOT_FOUND: &'static str = "file-not-found.html";
+    const INDEX: &'static str = "index.html";
+    const METHOD_NOT_ALLOWED: &'static str = "method-not-allowed.html";
+    const PAGE_NOT_FOUND: &'static str = "page-not-found.html";
+    const REQUEST_TIMEOUT: &'static str = "request-timeout.html";
+    const REQUEST_TOO_LARGE: &'static str = "request-too-large.html";
+    const SERVER_ERROR: &'static str = "server-error.html";
+    const SERVICE_UNAVAILABLE: &'static str = "service-unavailable.html";
+    const UPLOAD: &'static str = "upload.html";
+
+    /// The pages the handlers render, with the names of the values each of them is rendered with
+    const PAGES: [(&'static str, &'static [&'static str]); 11] = [
+        (Self::ACCESS_DENIED, &[]),
+        (Self::BAD_REQUEST, &["error_message"]),
+        (Self::FILE_NOT_FOUND, &[]),
+        (Self::INDEX, &["files", "folders"]),
+        (Self::METHOD_NOT_ALLOWED, &["method"]),
+        (Self::PAGE_NOT_FOUND, &[]),
+        (Self::REQUEST_TIMEOUT, &["error_message"]),
+        (Self::REQUEST_TOO_LARGE, &["status", "error_message"]),
+        (Self::SERVER_ERROR, &[]),
+        (Self::SERVICE_UNAVAILABLE, &[]),
+        (Self::UPLOAD, &["folders"]),
+    ];
+
+    const EMBEDDED: [(&'static str, &'static str); 14] = [
+        (
+            Self::ACCESS_DENIED,
+            include_str!("../templates/access-denied.html"),
+        ),
+        (
+            "back-link.html",
+            include_str!("../templates/back-link.html"),
+        ),
+        (
+            Self::BAD_REQUEST,
+            include_str!("../templates/bad-request.html"),
+        ),
+        ("error.html", include_str!("../templates/error.html")),
+        (
+            Self::FILE_NOT_FOUND,
+            include_str!("../templates/file-not-found.html"),
+        ),
+        (Self::INDEX, include_str!("../templates/index.html")),
+        ("layout.html", include_str!("../templates/layout.html")),
+        (
+            Self::METHOD_NOT_ALLOWED,
+            include_str!("../templates/method-not-allowed.html"),
+        ),
+        (
+            Self::PAGE_NOT_FOUND,
+            include_str!("../templates/page-not-found.html"),
+        ),
+        (
+            Self::REQUEST_TIMEOUT,
+            include_str!("../templates/request-timeout.html"),
+        ),
+        (
+            Self::REQUEST_TOO_LARGE,
+            include_str!("../templates/request-too-large.html"),
+        ),
+        (
+            Self::SERVER_ERROR,
+            include_str!("../templates/server-error.html"),
+        ),
+        (
+            Self::SERVICE_UNAVAILABLE,
+            include_str!("../templates/service-unavailable.html"),
+        ),
+        (Self::UPLOAD, include_str!("../templates/upload.html")),
+    ];
+
+    /// Gets the source of an embedded template by its name
+    fn embedded(name: &str) -> Option<&'static str> {
+        Self::EMBEDDED
+            .iter()
+            .find(|(embedded_name, _)| *embedded_name == name)
+            .map(|(_, source)| *source)
+    }
+
+    /// Compiles every page, which is done when the server starts
+    ///
+    /// As the pages are compiled with the theme, if there is one, any template that can't be parsed,
+    /// or uses a value its page isn't rendered with, is reported before the server serves a page.
+    /// Files in the theme directory that don't replace any template are warned about, as they are
+    /// most likely misnamed.  
+    /// An `AppError::Invalid` listing every problem is returned if any page fails to compile.
+    pub(crate) fn init() -> Result<(), AppError> {
+        let cache = Self::cache();
+        let mut templates = HashMap::new();
+        let mut errors = Vec::new();
+        for (name, _) in Self::PAGES {
+            match cache.compile(name) {
+                Ok(page) => {
+                    templates.insert(name.to_string(), page);
+                }
+                Err(AppError::Invalid(error)) => errors.push(error),
+                Err(error) => return Err(error),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(AppError::Invalid(errors.join("\n")));
+        }
+
+        if let Some(theme_dir) = &cache.theme_dir {
+            let entries = fs::read_dir(theme_dir).map_err(|e| {
+                AppError::Invalid(format!(
+                    "Failed to read theme directory {}: {e}",
+                    theme_dir.display()
+                ))
+            })?;
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if !Self::EMBEDDED.iter().any(|(name, _)| *name == file_name) {
+                    warn!("Theme file {file_name} doesn't replace any template, so it isn't used");
+                }
+            }
+        }
+
+        *cache.pages.write().unwrap_or_else(PoisonError::into_inner) = templates;
+        Ok(())
+    }
+
+    /// Gets the `TemplateCache` of the server, created with the theme of the `Config`
+    fn cache() -> &'static TemplateCache {
+        TEMPLATES.get_or_init(|| {
+            let config = Config::get();
+            TemplateCache::new(
+                config.theme_dir.as_ref().map(PathBuf::from),
+                config.dev_mode,
+            )
+        })
+    }
+
+    /// Gets a compiled `Template` by its name, from the `TemplateCache` of the server
+    fn get(name: &str) -> Arc<Template> {
+        Self::cache().get(name)
+    }
+
+    /// Renders a template into the body of a response
+    ///
+    /// Arguments:
+    /// - **name**: The name of the template, one of the constants of `Templates`
+    /// - **context**: The values the template is rendered with
+    fn render(name: &str, context: &Context) -> ResponseBody {
+        ResponseBody::Text(Self::get(name).render(context))
+    }
 }
 
 /// Contains all logic to handle each valid request
 pub(crate) struct RequestHandler;
 
 impl RequestHandler {
+    /// Builds the `Router` with the routes of every handler
+    pub(crate) fn routes() -> Router {
+        Router::new()
+            .get("/", |_, _| Self::list_files())
+            .get("/metrics", |_, _| Self::get_metrics())
+            .get("/uploads/*path", Self::view_file)
+            .delete("/uploads/*path", Self::delete_file)
+            .route(HttpMethod::Move, "/uploads/*path", Self::move_file)
+            .post("/uploads/*path", Self::submit_file_form)
+            .post("/folders", |request, _| Self::create_folder(request))
+            .delete("/folders/*path", |_, params| Self::delete_folder(params))
+            .post("/folders/*path", Self::submit_folder_form)
+            .group("/upload", |group| {
+                group
+                    .get("/", |_, _| Self::get_file_upload_view())
+                    .post("/", |request, _| Self::upload_file(request))
+            })
+    }
+
     /// Lists files in the upload folder
     ///
-    /// The `index.html` template file is opened, and read into a string.
-    /// The files in the upload folder are fetched, and then an HTML string og lists is generated with
-    /// each file path as the `href`, and the filename as the display. This string is interpolated into
-    /// the template file, and the resulting string returned in the response.
+    /// The paths of the files and folders in the upload folder are collected first, and the
+    /// `index.html` template renders a list item for each file, with its path as the `href`,
+    /// percent-encoded so that any file name can be linked, and the escaped path as the display.
+    /// The folders are listed after them, so that empty ones can be deleted.  
+    /// Only the paths are held in memory, while the page rendered from them, which is much larger,
+    /// is rendered in chunks as the response is written.
     pub(crate) fn list_files() -> Result<Response, AppError> {
-        let template = Templates::INDEX;
+        // Files are linked through their route, as the uploads directory can be anywhere on disk
+        let files = FileManager::list_files_with_paths(&Config::get().uploads_dir)?
+            .into_iter()
+            .map(|(name, _)| Value::from(name))
+            .collect::<Vec<_>>();
+        let folders = FileManager::list_dirs(&Config::get().uploads_dir)?
+            .into_iter()
+            .map(Value::from)
+            .collect::<Vec<_>>();
+        let context = Context::new().with("files", files).with("folders", folders);
 
-        let files = FileManager::list_files_with_paths("uploads")?;
+        Ok(Response::builder()
+            .header(HttpHeader::CONTENT_TYPE, "text/html; charset=UTF-8")
+            .body(ResponseBody::Stream(Box::new(
+                Templates::get(Templates::INDEX).reader(context),
+            )))
+            .chunked()
+            .build())
+    }
 
-        let file_links: String = files
-            .iter()
-            .map(|(name, path)| format!(r#"<li><a href="{}">{}</a></li>"#, path, name))
-            .collect::<Vec<String>>()
-            .join("\n");
+    /// Returns the `Metrics` of the server, in the plain text format monitoring tools scrape
+    pub(crate) fn get_metrics() -> Result<Response, AppError> {
+        Ok(Response::builder()
+            .header(HttpHeader::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(ResponseBody::Text(Metrics::render()))
+            .build())
+    }
 
-        let html_output = template.replace("{{FILES_LIST}}", &file_links);
+    /// Returns an uploaded file in the response to be viewed in the browser
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` for the file
+    /// - **params**: The `RouteParams` of the request, with the `path` of the file, which can possibly
+    ///   include a directory
+    ///
+    /// The file path is resolved with `resolve_upload_path()`, which protects against traversal
+    /// attacks, returning an error if it fails.  
+    /// With `?download=true` in the query, the file is sent as an attachment, which browsers save
+    /// instead of showing.  
+    /// Every file response advertises support for ranges and has an *ETag* and *Last-Modified*
+    /// header. The conditional headers of the request are then checked with `check_preconditions()`,
+    /// returning a 304 status with no body if the client's copy is still current.  
+    /// If the request has a *Range* header, and either no *If-Range* header or one matching the
+    /// file, only the requested ranges are returned with a 206 status, or a 416 status if none of
+    /// them are in the file. Otherwise, the whole file is returned.
+    pub(crate) fn view_file(request: Request, params: RouteParams) -> Result<Response, AppError> {
+        let resolved_path = Self::resolve_upload_path(params.get("path").unwrap_or_default())?;
+        let metadata = fs::metadata(&resolved_path).map_err(|_| {
+            AppError::NotFound(format!(
+                "Client attempted to access a file that does not exist: {}",
+                resolved_path.display()
+            ))
+        })?;
+        if metadata.is_dir() {
+            return Err(AppError::NotFound(format!(
+                "Path for file is a directory: {}",
+                resolved_path.display()
+            )));
+        }
+
+        let etag = FileManager::get_etag(&metadata);
+        let last_modified = FileManager::get_last_modified(&metadata);
+        let file_path = resolved_path.to_string_lossy().to_string();
+        let mut response = Response::builder()
+            .header(HttpHeader::ACCEPT_RANGES, "bytes")
+            .header(HttpHeader::ETAG, &etag)
+            .header(
+                HttpHeader::LAST_MODIFIED,
+                &Time::get_http_date_from_timestamp(last_modified),
+            );
+        if request
+            .query()
+            .get_parsed::<bool>("download")?
+            .unwrap_or(false)
+        {
+            let file_name = resolved_path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            response = response.header(
+                HttpHeader::CONTENT_DISPOSITION,
+                &Response::content_disposition("attachment", &file_name),
+            );
+        }
+
+        if let Some(status) = Self::check_preconditions(&request, Some(&metadata)) {
+            return Ok(response.status(status).body(ResponseBody::Empty).build());
+        }
+
+        // A range is only valid for the version of the file named in If-Range, if there is one
+        let ranges = request
+            .get_header(HttpHeader::RANGE)
+            .filter(|_| match request.get_header(HttpHeader::IF_RANGE) {
+                Some(if_range) if if_range.trim().starts_with('"') => if_range.trim() == etag,
+                Some(if_range) => {
+                    Time::get_timestamp_from_http_date(if_range) == Some(last_modified)
+                }
+                None => true,
+            })
+            .and_then(|range| ByteRange::parse_ranges(range, metadata.len()));
+
+        let response = match ranges {
+            Some(ranges) if ranges.is_empty() => response
+                .status(HttpStatus::RangeNotSatisfiable)
+                .header(
+                    HttpHeader::CONTENT_RANGE,
+                    &format!("bytes */{}", metadata.len()),
+                )
+                .body(ResponseBody::Empty),
+            Some(ranges) => response
+                .status(HttpStatus::PartialContent)
+                .body(ResponseBody::FileRanges(file_path, ranges)),
+            None => response.body(ResponseBody::File(file_path)),
+        };
+
+        Ok(response.build())
+    }
+
+    /// Deletes an uploaded file
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` to delete the file
+    /// - **params**: The `RouteParams` of the request, with the `path` of the file, which can possibly
+    ///   include a directory
+    ///
+    /// The file path is resolved with `resolve_upload_path()`, the same as when it is viewed, so a
+    /// file outside the uploads directory can't be deleted. Folders are deleted with
+    /// `delete_folder()` instead.  
+    /// Any conditional headers, such as *If-Match*, are checked against the file, returning a 412
+    /// status if one fails, so clients can avoid deleting a file that changed since they saw it.  
+    /// The file is then removed, along with any folders it leaves empty up to the uploads directory,
+    /// and a response with a 204 status is returned.
+    pub(crate) fn delete_file(request: Request, params: RouteParams) -> Result<Response, AppError> {
+        let resolved_path = Self::resolve_upload_path(params.get("path").unwrap_or_default())?;
+        let metadata = fs::metadata(&resolved_path).map_err(|_| {
+            AppError::NotFound(format!(
+                "Client attempted to delete a file that does not exist: {}",
+                resolved_path.display()
+            ))
+        })?;
+        if metadata.is_dir() {
+            return Err(AppError::NotPermitted(format!(
+                "Client attempted to delete a directory: {}",
+                resolved_path.display()
+            )));
+        }
+
+        if let Some(status) = Self::check_preconditions(&request, Some(&metadata)) {
+            return Ok(Response::builder()
+                .status(status)
+                .body(ResponseBody::Empty)
+                .build());
+        }
+
+        FileManager::delete_file(&Config::get().uploads_dir, &resolved_path)?;
 
         Ok(Response::builder()
-            .body(ResponseBody::Text(html_output))
+            .status(HttpStatus::NoContent)
+            .body(ResponseBody::Empty)
             .build())
     }
 
-    /// Returns an uploaded file in the response to be viewed in the browser
+    /// Moves or renames an uploaded file, to the path in the *Destination* header
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` to move the file, with the *Destination* header
+    /// - **params**: The `RouteParams` of the request, with the `path` of the file, which can possibly
+    ///   include a directory
+    ///
+    /// The destination must be a path under `/uploads/`, either on its own, or as an absolute URI.
+    /// A file already at the destination is only replaced if the *Overwrite* header is `T`, as a
+    /// rename is easily mistyped, and the file it would replace can't be brought back. The file is
+    /// then moved with `move_upload()`.
+    pub(crate) fn move_file(request: Request, params: RouteParams) -> Result<Response, AppError> {
+        let destination = request
+            .get_header(HttpHeader::DESTINATION)
+            .ok_or(AppError::Invalid(
+                "Destination header is missing".to_string(),
+            ))?;
+        let destination_url = Url::from_uri(destination)?;
+        let destination =
+            destination_url
+                .path()
+                .strip_prefix("/uploads/")
+                .ok_or(AppError::NotPermitted(format!(
+                    "Client attempted to move a file outside the uploads directory: {destination}"
+                )))?;
+
+        let overwrite = match request.get_header(HttpHeader::OVERWRITE).map(str::trim) {
+            Some("T") => true,
+            Some("F") | None => false,
+            Some(overwrite) => {
+                return Err(AppError::Invalid(format!(
+                    "Overwrite header must be T or F: {overwrite}"
+                )));
+            }
+        };
+
+        let source = params.get("path").unwrap_or_default();
+        Self::move_upload(&request, source, destination, overwrite)
+    }
+
+    /// Moves or renames an uploaded file
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` to move the file, whose conditional headers are checked
+    /// - **source**: The path of the file, relative to the uploads directory
+    /// - **destination**: The path to move the file to, relative to the uploads directory
+    /// - **overwrite**: Whether a file already at the destination is replaced
+    ///
+    /// The source is resolved with `resolve_upload_path()`, the same as when it is viewed, and the
+    /// destination with `resolve_new_upload_path()`, so both are validated and protected against
+    /// traversal attacks. Directories can't be moved, and a file can't replace a directory.  
+    /// Any conditional headers, such as *If-Match*, are checked against the file, returning a 412
+    /// status if one fails.  
+    /// A 412 status is also returned if there is a file at the destination and `overwrite` is
+    /// `false`. Otherwise, the file is moved, removing any folders it leaves empty, and a response
+    /// with a 201 status and the new *Location* of the file is returned, or a 204 status if it
+    /// replaced a file.
+    fn move_upload(
+        request: &Request,
+        source: &str,
+        destination: &str,
+        overwrite: bool,
+    ) -> Result<Response, AppError> {
+        let source_path = Self::resolve_upload_path(source)?;
+        let metadata = fs::metadata(&source_path).map_err(|_| {
+            AppError::NotFound(format!(
+                "Client attempted to move a file that does not exist: {}",
+                source_path.display()
+            ))
+        })?;
+        if metadata.is_dir() {
+            return Err(AppError::NotPermitted(format!(
+                "Client attempted to move a directory: {}",
+                source_path.display()
+            )));
+        }
+
+        let destination_path = Self::resolve_new_upload_path(destination, None)?;
+        if destination_path == source_path {
+            return Err(AppError::Invalid(format!(
+                "File is already at its destination: {destination}"
+            )));
+        }
+        if destination_path.is_dir() {
+            return Err(AppError::NotPermitted(format!(
+                "Client attempted to replace a directory: {}",
+                destination_path.display()
+            )));
+        }
+
+        if let Some(status) = Self::check_preconditions(request, Some(&metadata)) {
+            return Ok(Response::builder()
+                .status(status)
+                .body(ResponseBody::Empty)
+                .build());
+        }
+
+        let response = match FileManager::move_file(
+            &Config::get().uploads_dir,
+            &source_path,
+            &destination_path,
+            overwrite,
+        )? {
+            MoveOutcome::Refused => Response::builder().status(HttpStatus::PreconditionFailed),
+            MoveOutcome::Replaced => Response::builder().status(HttpStatus::NoContent),
+            MoveOutcome::Moved => Response::builder().status(HttpStatus::Created).header(
+                HttpHeader::LOCATION,
+                &format!("/uploads/{}", Url::encode_path(destination)),
+            ),
+        };
+        Ok(response.body(ResponseBody::Empty).build())
+    }
+
+    /// Handles a form submitted for an uploaded file, which is how browsers, that can only send
+    /// forms with GET and POST, use the other methods of a file
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` with the form
+    /// - **params**: The `RouteParams` of the request, with the `path` of the file
+    ///
+    /// The `_method` field of the form names the method the form stands in for, and the request is
+    /// handled by its handler. A `MOVE` form has the path to move the file to, relative to the
+    /// uploads directory, in its `destination` field, and replaces a file already there only if its
+    /// `overwrite` field is `true`. A successful form is then answered with a redirect to the file
+    /// listing, so the browser shows the result, while an error response is returned as is.  
+    /// An error is returned if the body isn't a URL encoded form, or `_method` is missing or
+    /// isn't supported.
+    pub(crate) fn submit_file_form(
+        request: Request,
+        params: RouteParams,
+    ) -> Result<Response, AppError> {
+        let RequestBody::Form(form) = &request.body else {
+            return Err(AppError::Invalid(format!(
+                "Request body is not a form: {}",
+                request.body
+            )));
+        };
+
+        let response = match form.get("_method").map(str::to_uppercase).as_deref() {
+            Some("DELETE") => Self::delete_file(request, params)?,
+            Some("MOVE") => {
+                let destination = form.get("destination").ok_or(AppError::Invalid(
+                    "Form is missing the destination field".to_string(),
+                ))?;
+                let overwrite = form.get("overwrite") == Some("true");
+                let source = params.get("path").unwrap_or_default();
+                Self::move_upload(&request, source, destination.trim(), overwrite)?
+            }
+            Some(method) => {
+                return Err(AppError::Invalid(format!(
+                    "Form method is not supported: {method}"
+                )));
+            }
+            None => {
+                return Err(AppError::Invalid(
+                    "Form is missing the _method field".to_string(),
+                ));
+            }
+        };
+
+        if !response.is_success() {
+            return Ok(response);
+        }
+        Ok(Response::builder()
+            .status(HttpStatus::SeeOther)
+            .header(HttpHeader::LOCATION, "/")
+            .body(ResponseBody::Empty)
+            .build())
+    }
+
+    /// Checks the conditional headers of a request against the current state of a file
     ///
     /// Arguments:
-    /// - **filename**: The name of the file to be viewed, can possibly include a directory
+    /// - **request**: The `Request` that has the conditional headers
+    /// - **metadata**: The `Metadata` of the file the request is for, `None` if it doesn't exist
+    ///
+    /// The headers are evaluated in the order HTTP defines. *If-Match*, or *If-Unmodified-Since* when
+    /// there is no *If-Match*, must pass or a 412 status is returned. Then a matching *If-None-Match*,
+    /// or *If-Modified-Since* without a newer modification when there is no *If-None-Match*, means
+    /// the client already has the file, and a 304 status is returned for reads, or a 412 status for
+    /// writes.  
+    /// `None` is returned if the request should go ahead.
+    fn check_preconditions(request: &Request, metadata: Option<&Metadata>) -> Option<HttpStatus> {
+        let is_read = matches!(request.method, HttpMethod::Get | HttpMethod::Head);
+        let etag = metadata.map(FileManager::get_etag);
+        let last_modified = metadata.map(FileManager::get_last_modified);
+        let headers = request.headers();
+
+        // Entity tag lists can be split across several fields, which are read as one
+        if let Some(if_match) = headers.get_combined(HttpHeader::IF_MATCH) {
+            if !Self::etag_list_matches(&if_match, etag.as_deref(), false) {
+                return Some(HttpStatus::PreconditionFailed);
+            }
+        } else if let (Some(if_unmodified_since), Some(last_modified)) =
+            (headers.date(HttpHeader::IF_UNMODIFIED_SINCE), last_modified)
+            && last_modified > if_unmodified_since
+        {
+            return Some(HttpStatus::PreconditionFailed);
+        }
+
+        let not_modified =
+            if let Some(if_none_match) = headers.get_combined(HttpHeader::IF_NONE_MATCH) {
+                Self::etag_list_matches(&if_none_match, etag.as_deref(), true)
+            } else if let (true, Some(if_modified_since), Some(last_modified)) = (
+                is_read,
+                headers.date(HttpHeader::IF_MODIFIED_SINCE),
+                last_modified,
+            ) {
+                last_modified <= if_modified_since
+            } else {
+                false
+            };
+
+        match (not_modified, is_read) {
+            (true, true) => Some(HttpStatus::NotModified),
+            (true, false) => Some(HttpStatus::PreconditionFailed),
+            (false, _) => None,
+        }
+    }
+
+    /// Checks if an entity tag list, like the value of an *If-Match* header, matches a file
     ///
-    /// "/uploads/" is trimmed from the start of the file name, and then the file path is validated
-    /// to assert that it meets all requirements, then the file name is joined with the uploads
+    /// Arguments:
+    /// - **etag_list**: A comma separated list of entity tags, or `*` to match any existing file
+    /// - **etag**: The *ETag* of the file, `None` if it doesn't exist
+    /// - **weak**: Whether weak tags, prefixed with `W/`, are compared as if they were strong
+    fn etag_list_matches(etag_list: &str, etag: Option<&str>, weak: bool) -> bool {
+        let Some(etag) = etag else {
+            return false;
+        };
+
+        etag_list.split(',').map(str::trim).any(|tag| {
+            tag == "*" || (weak && tag.trim_start_matches("W/") == etag) || (!weak && tag == etag)
+        })
+    }
+
+    /// Resolves the path of an uploaded file from a request path
+    ///
+    /// Arguments:
+    /// - **filename**: The name of the file, can possibly include a directory
+    ///
+    /// The file path is validated to assert that it meets all requirements, then the file name is joined with the uploads
     /// directory and an assert is done to ensure the file is inside the directory, to protect against
     /// possible traversal attacks.  
     /// If the validation or canonicalization fails, an error is returned.
-    pub(crate) fn view_file(filename: String) -> Result<Response, AppError> {
-        let filename = filename
-            .trim_start_matches('/')
-            .trim_start_matches("uploads/");
-
+    fn resolve_upload_path(filename: &str) -> Result<PathBuf, AppError> {
         Self::validate_filename(filename)?;
 
-        let base_path = Path::new("uploads");
+        let base_path = Path::new(&Config::get().uploads_dir);
         let requested_path = base_path.join(filename);
 
         // Get the absolute path, removing all traversals, this protects from traversal attacks
@@ -77,11 +782,7 @@ impl RequestHandler {
 
                 // Assert that the path is still within the uploads directory
                 if resolved_path.starts_with(canonicalized_base_path) {
-                    Ok(Response::builder()
-                        .body(ResponseBody::File(
-                            resolved_path.to_string_lossy().to_string(),
-                        ))
-                        .build())
+                    Ok(resolved_path)
                 } else {
                     Err(AppError::NotPermitted(format!(
                         "Client attempted to access a path outside the uploads directory: {}",
@@ -96,51 +797,316 @@ impl RequestHandler {
         }
     }
 
+    /// Resolves the path a new file is put at, such as the destination of a move
+    ///
+    /// Arguments:
+    /// - **filename**: The name of the file, relative to the uploads directory, which can possibly
+    ///   include a directory
+    /// - **new_dir**: A directory, relative to the uploads directory, that is created before the
+    ///   file is put in it, if there is one
+    ///
+    /// The file path is validated, and its traversals are resolved, to assert that it is inside the
+    /// uploads directory, and isn't the directory itself.  
+    /// The directory the file goes in must already exist, unless it is `new_dir` or one of its
+    /// parents. The deepest directory on the path that exists is canonicalized, so that a symbolic
+    /// link can't lead outside the uploads directory either, and the directories that don't exist
+    /// yet are added back to it. The file itself may not exist yet.  
+    /// If the validation or canonicalization fails, an error is returned.
+    fn resolve_new_upload_path(
+        filename: &str,
+        new_dir: Option<&Path>,
+    ) -> Result<PathBuf, AppError> {
+        Self::validate_filename(filename)?;
+
+        let base_path = Path::new(&Config::get().uploads_dir);
+        let path = Self::resolve_traversals(&base_path.join(filename));
+        let outside_uploads = || {
+            AppError::NotPermitted(format!(
+                "Client attempted to access a path outside the uploads directory: {filename}"
+            ))
+        };
+        if !path.starts_with(base_path) || path == base_path {
+            return Err(outside_uploads());
+        }
+
+        let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(outside_uploads());
+        };
+        let is_created = new_dir.is_some_and(|new_dir| {
+            Self::resolve_traversals(&base_path.join(new_dir)).starts_with(parent)
+        });
+        let existing_dir = match is_created {
+            true => parent
+                .ancestors()
+                .find(|ancestor| ancestor.exists())
+                .unwrap_or(parent),
+            false => parent,
+        };
+        let resolved_dir = existing_dir.canonicalize().map_err(|_| {
+            AppError::NotFound(format!(
+                "Client attempted to use a directory that does not exist: {}",
+                parent.display()
+            ))
+        })?;
+        let canonicalized_base_path = base_path
+            .canonicalize()
+            .map_err(|_| AppError::Unknown("Failed to canonicalize base path".to_string()))?;
+        if !resolved_dir.starts_with(canonicalized_base_path) {
+            return Err(outside_uploads());
+        }
+
+        let missing_dirs = parent.strip_prefix(existing_dir).unwrap_or(Path::new(""));
+        Ok(resolved_dir.join(missing_dirs).join(file_name))
+    }
+
     /// Returns the view of the template to upload a new file
+    ///
+    /// The folders already in the uploads directory are suggested for the folder the files are
+    /// uploaded into.
     pub(crate) fn get_file_upload_view() -> Result<Response, AppError> {
+        let folders = FileManager::list_dirs(&Config::get().uploads_dir)?
+            .into_iter()
+            .map(Value::from)
+            .collect::<Vec<_>>();
+        let context = Context::new().with("folders", folders);
+
+        Ok(Response::builder()
+            .body(Templates::render(Templates::UPLOAD, &context))
+            .build())
+    }
+
+    /// Creates a folder in the uploads directory, from a form
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` with a URL encoded form, whose `path` field is the path of the
+    ///   folder relative to the uploads directory, which can possibly include its parent folders
+    ///
+    /// The path is validated with `validate_dirname()`, then the folder and any of its missing
+    /// parents are created with `FileManager::create_dir()`, which asserts that each of them is
+    /// inside the uploads directory.  
+    /// A successful form is answered with a redirect to the file listing, and an error is returned
+    /// if the folder already exists.
+    pub(crate) fn create_folder(request: Request) -> Result<Response, AppError> {
+        let RequestBody::Form(form) = &request.body else {
+            return Err(AppError::Invalid(format!(
+                "Request body is not a form: {}",
+                request.body
+            )));
+        };
+        let path = form
+            .get("path")
+            .ok_or(AppError::Invalid(
+                "Form is missing the path field".to_string(),
+            ))?
+            .trim()
+            .trim_matches('/');
+
+        Self::validate_dirname(path)?;
+        if !FileManager::create_dir(&Config::get().uploads_dir, Path::new(path))? {
+            return Err(AppError::NotPermitted(format!(
+                "Client attempted to create a folder that already exists: {path}"
+            )));
+        }
+
+        Ok(Response::builder()
+            .status(HttpStatus::SeeOther)
+            .header(HttpHeader::LOCATION, "/")
+            .body(ResponseBody::Empty)
+            .build())
+    }
+
+    /// Deletes an empty folder in the uploads directory
+    ///
+    /// Arguments:
+    /// - **params**: The `RouteParams` of the request, with the `path` of the folder, relative to the
+    ///   uploads directory
+    ///
+    /// The path is validated with `validate_dirname()`, then canonicalized, to assert that it is a
+    /// folder inside the uploads directory, and that none of the folders on the path is a symbolic
+    /// link, so that only the folder that was named can be deleted.  
+    /// The folder is removed with `FileManager::delete_dir()`, which fails if it isn't empty, and a
+    /// response with a 204 status is returned.
+    pub(crate) fn delete_folder(params: RouteParams) -> Result<Response, AppError> {
+        let path = params.get("path").unwrap_or_default().trim_matches('/');
+        Self::validate_dirname(path)?;
+
+        let base_path = Path::new(&Config::get().uploads_dir)
+            .canonicalize()
+            .map_err(|_| AppError::Unknown("Failed to canonicalize base path".to_string()))?;
+        let requested_path = base_path.join(path);
+        let resolved_path = requested_path.canonicalize().map_err(|_| {
+            AppError::NotFound(format!(
+                "Client attempted to delete a folder that does not exist: {}",
+                requested_path.display()
+            ))
+        })?;
+        if resolved_path != requested_path || !resolved_path.is_dir() {
+            return Err(AppError::NotPermitted(format!(
+                "Client attempted to delete something other than a folder: {}",
+                requested_path.display()
+            )));
+        }
+
+        FileManager::delete_dir(&resolved_path)?;
+
         Ok(Response::builder()
-            .body(ResponseBody::Text(Templates::UPLOAD.to_string()))
+            .status(HttpStatus::NoContent)
+            .body(ResponseBody::Empty)
             .build())
     }
 
-    /// Uploads a file from a request
+    /// Handles a form submitted for a folder, which is how browsers, that can only send forms with
+    /// GET and POST, delete a folder
     ///
     /// Arguments:
-    /// - **request_body**: The `RequestBody` to be used to get the file from
+    /// - **request**: The `Request` with the form
+    /// - **params**: The `RouteParams` of the request, with the `path` of the folder
     ///
-    /// The `RequestBody` must be of the `Multipart` variant or an error is returned.  
-    /// The file path is then validated to assert that it meets all requirements.
-    /// The file path is again resolved to assert that it is inside the 'uploads' directory.    
-    /// If all conditions pass, the file gets saved and a response with an empty body gets returned.
-    pub(crate) fn upload_file(request_body: RequestBody) -> Result<Response, AppError> {
+    /// The `_method` field of the form must be `DELETE`, and the folder is deleted with
+    /// `delete_folder()`. A successful form is answered with a redirect to the file listing, while
+    /// an error response is returned as is.
+    pub(crate) fn submit_folder_form(
+        request: Request,
+        params: RouteParams,
+    ) -> Result<Response, AppError> {
+        let RequestBody::Form(form) = &request.body else {
+            return Err(AppError::Invalid(format!(
+                "Request body is not a form: {}",
+                request.body
+            )));
+        };
+
+        match form.get("_method").map(str::to_uppercase).as_deref() {
+            Some("DELETE") => {}
+            Some(method) => {
+                return Err(AppError::Invalid(format!(
+                    "Form method is not supported: {method}"
+                )));
+            }
+            None => {
+                return Err(AppError::Invalid(
+                    "Form is missing the _method field".to_string(),
+                ));
+            }
+        }
+
+        let response = Self::delete_folder(params)?;
+        if !response.is_success() {
+            return Ok(response);
+        }
+        Ok(Response::builder()
+            .status(HttpStatus::SeeOther)
+            .header(HttpHeader::LOCATION, "/")
+            .body(ResponseBody::Empty)
+            .build())
+    }
+
+    /// Uploads every file from a request
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` whose body is used to get the files from
+    ///
+    /// The `RequestBody` must be of the `Multipart` variant and contain at least one file, or an
+    /// error is returned.  
+    /// The files are uploaded into the folder in the `directory` field of the form, relative to the
+    /// uploads directory, or into the uploads directory itself if it is missing or empty. The folder
+    /// is validated with `validate_dirname()`, and every file path is validated to assert that it
+    /// meets all requirements, then resolved with `resolve_new_upload_path()` to assert that it is
+    /// inside the 'uploads' directory, even if the folder doesn't exist yet.  
+    /// Two files of the form resolving to the same path are answered with a 409 status, as one would
+    /// replace the other.  
+    /// Any conditional headers, such as *If-Match*, are checked against the file each upload would
+    /// replace, returning a 412 status if one fails, so clients can avoid overwriting changes.  
+    /// The files, which were streamed into temporary files while the request was read, are only
+    /// saved once all of them pass, so a single bad file doesn't leave the upload half done, or an
+    /// empty folder behind. The folder is then created if it doesn't exist yet, and the files are
+    /// saved at exactly the paths that were resolved, with `FileManager::save_files()`, and a
+    /// response with an empty body gets returned. Files already at the paths are only replaced if
+    /// the `overwrite` field of the form is `true`, otherwise nothing is saved and a response with a
+    /// 409 status is returned. Temporary files that aren't saved are removed.
+    pub(crate) fn upload_file(mut request: Request) -> Result<Response, AppError> {
         // Ensure that the `RequestBody` is a `Multipart` type, as that is the only supported type
         // for file uploads on this server
-        let uploaded_file = match request_body {
-            RequestBody::Multipart(uploaded_file) => uploaded_file,
-            _ => {
+        let form = match mem::replace(&mut request.body, RequestBody::Empty) {
+            RequestBody::Multipart(form) => form,
+            request_body => {
                 return Err(AppError::Invalid(format!(
                     "Request body is not multipart: {request_body}"
                 )));
             }
         };
 
-        Self::validate_filename(&uploaded_file.name)?;
-
-        let path = Path::new("uploads").join(&uploaded_file.name);
-        let resolved_path = Self::resolve_traversals(&path);
-        if !resolved_path.starts_with("uploads/") {
-            return Err(AppError::NotPermitted(
-                "Client attempted to access a path outside the uploads directory".to_string(),
+        if form.files.is_empty() {
+            return Err(AppError::Invalid(
+                "file data missing from form body".to_string(),
             ));
         }
 
-        FileManager::save_file("uploads", uploaded_file)?;
+        let directory = form
+            .fields
+            .iter()
+            .find(|field| field.name == "directory")
+            .map(|field| field.value.trim().trim_matches('/'))
+            .unwrap_or_default();
+        let overwrite = form
+            .fields
+            .iter()
+            .any(|field| field.name == "overwrite" && field.value == "true");
+        let filenames = form
+            .files
+            .iter()
+            .map(|form_file| match directory.is_empty() {
+                true => form_file.filename.clone(),
+                false => format!("{directory}/{}", form_file.filename),
+            })
+            .collect::<Vec<_>>();
 
-        Ok(Response::builder()
-            .status(HttpStatus::SeeOther)
-            .header(HttpHeader::LOCATION, "/")
-            .body(ResponseBody::Empty)
-            .build())
+        // Every file is checked before the folder is created, so a rejected upload leaves nothing
+        for filename in &filenames {
+            Self::validate_filename(filename)?;
+        }
+        let directory = match directory.is_empty() {
+            true => None,
+            false => {
+                Self::validate_dirname(directory)?;
+                Some(Path::new(directory))
+            }
+        };
+
+        let mut paths = Vec::with_capacity(filenames.len());
+        for filename in &filenames {
+            let path = Self::resolve_new_upload_path(filename, directory)?;
+
+            let metadata = fs::metadata(&path).ok();
+            if let Some(status) = Self::check_preconditions(&request, metadata.as_ref()) {
+                return Ok(Response::builder()
+                    .status(status)
+                    .body(ResponseBody::Empty)
+                    .build());
+            }
+            if paths.contains(&path) {
+                return Ok(Response::builder()
+                    .status(HttpStatus::Conflict)
+                    .body(ResponseBody::Empty)
+                    .build());
+            }
+            paths.push(path);
+        }
+
+        let files = paths
+            .into_iter()
+            .zip(form.files)
+            .map(|(path, form_file)| (path, form_file.file))
+            .collect();
+        let response =
+            match FileManager::save_files(&Config::get().uploads_dir, directory, files, overwrite)?
+            {
+                true => Response::builder()
+                    .status(HttpStatus::SeeOther)
+                    .header(HttpHeader::LOCATION, "/"),
+                false => Response::builder().status(HttpStatus::Conflict),
+            };
+        Ok(response.body(ResponseBody::Empty).build())
     }
 
     /// Validates a file name
@@ -158,7 +1124,7 @@ impl RequestHandler {
     /// can't serve
     fn validate_filename(path: &str) -> Result<(), AppError> {
         // A list of allowed extensions to limit the supported file types
-        let allowed_extensions = ["txt", "png", "jpg", "pdf"];
+        let allowed_extensions = &Config::get().allowed_extensions;
 
         // Sanitize file name in case it contains unanticipated characters
         let sanitized_filename = Path::new(path)
@@ -180,7 +1146,7 @@ impl RequestHandler {
             .extension()
             .and_then(|ext| ext.to_str())
             .and_then(|ext| {
-                if allowed_extensions.contains(&ext) {
+                if allowed_extensions.iter().any(|allowed| allowed == ext) {
                     Some(ext)
                 } else {
                     None
@@ -193,6 +1159,41 @@ impl RequestHandler {
         Ok(())
     }
 
+    /// Validates the path of a folder
+    ///
+    /// Arguments:
+    /// - **path**: The folder path to validate, relative to the uploads directory
+    ///
+    /// This method ensures a folder path to be created meets all our requirements:
+    /// 1. It is not an empty string
+    /// 2. It is relative, and has no `.` or `..` segments, so it can only lead further into the
+    ///    uploads directory
+    /// 3. None of its folder names start with a `.`, so it can't be confused with a hidden or
+    ///    temporary file
+    fn validate_dirname(path: &str) -> Result<(), AppError> {
+        if path.is_empty() {
+            return Err(AppError::Invalid("Folder name is empty".to_string()));
+        }
+
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) if !name.to_string_lossy().starts_with('.') => {}
+                Component::Normal(_) => {
+                    return Err(AppError::Invalid(format!(
+                        "Folder name starts with a dot: {path}"
+                    )));
+                }
+                _ => {
+                    return Err(AppError::NotPermitted(format!(
+                        "Client attempted to create a folder outside the uploads directory: {path}"
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Resolves the traversal of a file path
     ///
     /// Arguments:
@@ -222,33 +1223,6 @@ impl RequestHandler {
     }
 }
 
-/// Abstracts routing from a request method and path to a handler
-pub(crate) struct Router;
-
-impl Router {
-    /// Routes a request to its appropriate handler
-    ///
-    /// Arguments:
-    /// - **request**: A `Request` to route to a possible handler
-    ///
-    /// The method and path are matched against, and if a supported handler exists, it is called and
-    /// the response is returned
-    pub(crate) fn route_request(request: Request) -> Result<Response, AppError> {
-        match (&request.method, request.path.as_str()) {
-            (HttpMethod::Get, "/") => RequestHandler::list_files(),
-            (HttpMethod::Get, file_path) if file_path.starts_with("/uploads") => {
-                RequestHandler::view_file(file_path.to_string())
-            }
-            (HttpMethod::Get, "/upload") => RequestHandler::get_file_upload_view(),
-            (HttpMethod::Post, "/upload") => RequestHandler::upload_file(request.body),
-            _ => Ok(ErrorHandler::handle_invalid_page_request(
-                request.method,
-                request.path.clone(),
-            )),
-        }
-    }
-}
-
 /// Handles all error cases
 pub(crate) struct ErrorHandler;
 
@@ -259,19 +1233,39 @@ impl ErrorHandler {
         warn!("Invalid page request: {} {}", http_method, path);
         Response::builder()
             .status(HttpStatus::NotFound)
-            .body(ResponseBody::Text(Templates::PAGE_NOT_FOUND.to_string()))
+            .body(Templates::render(
+                Templates::PAGE_NOT_FOUND,
+                &Context::new(),
+            ))
+            .build()
+    }
+
+    /// Handles cases where the client requests a page with a method it doesn't support.
+    /// A 405 status code is returned, along with the methods the page supports in the *Allow* header
+    /// and an HTML template for the error case.
+    pub(crate) fn handle_method_not_allowed(
+        http_method: HttpMethod,
+        path: String,
+        allowed_methods: &str,
+    ) -> Response {
+        warn!("Method not allowed: {} {}", http_method, path);
+        let context = Context::new().with("method", http_method.to_string());
+
+        Response::builder()
+            .status(HttpStatus::MethodNotAllowed)
+            .header(HttpHeader::ALLOW, allowed_methods)
+            .body(Templates::render(Templates::METHOD_NOT_ALLOWED, &context))
             .build()
     }
 
     /// Handles cases where the client does not send a valid request body.
     /// A 400 status code is returned, along with an HTML template that shows the error.
     pub(crate) fn handle_bad_request(error_message: String) -> Response {
-        let template = Templates::BAD_REQUEST;
-        let html = template.replace("{{ERROR_MESSAGE}}", error_message.as_str());
+        let context = Context::new().with("error_message", error_message);
 
         Response::builder()
-            .status(HttpStatus::NotFound)
-            .body(ResponseBody::Text(html))
+            .status(HttpStatus::BadRequest)
+            .body(Templates::render(Templates::BAD_REQUEST, &context))
             .build()
     }
 
@@ -280,7 +1274,7 @@ impl ErrorHandler {
     pub(crate) fn handle_access_denied() -> Response {
         Response::builder()
             .status(HttpStatus::Forbidden)
-            .body(ResponseBody::Text(Templates::ACCESS_DENIED.to_string()))
+            .body(Templates::render(Templates::ACCESS_DENIED, &Context::new()))
             .build()
     }
 
@@ -289,7 +1283,10 @@ impl ErrorHandler {
     pub(crate) fn handle_invalid_file_request() -> Response {
         Response::builder()
             .status(HttpStatus::NotFound)
-            .body(ResponseBody::Text(Templates::FILE_NOT_FOUND.to_string()))
+            .body(Templates::render(
+                Templates::FILE_NOT_FOUND,
+                &Context::new(),
+            ))
             .build()
     }
 
@@ -298,7 +1295,56 @@ impl ErrorHandler {
     pub(crate) fn handle_server_error() -> Response {
         Response::builder()
             .status(HttpStatus::ServerError)
-            .body(ResponseBody::Text(Templates::SERVER_ERROR.to_string()))
+            .body(Templates::render(Templates::SERVER_ERROR, &Context::new()))
+            .build()
+    }
+
+    /// Handles cases where the server is too busy to take on another connection.
+    /// A 503 status code is returned, along with a *Retry-After* header telling the client how many
+    /// seconds to wait before trying again, and an HTML template that explains this.
+    pub(crate) fn handle_service_unavailable(retry_after: u64) -> Response {
+        Response::builder()
+            .status(HttpStatus::ServiceUnavailable)
+            .header(HttpHeader::RETRY_AFTER, &retry_after.to_string())
+            .body(Templates::render(
+                Templates::SERVICE_UNAVAILABLE,
+                &Context::new(),
+            ))
+            .build()
+    }
+
+    /// Handles cases where the client took too long to send its request.
+    /// A 408 status code is returned, along with an HTML template that explains this, and the
+    /// connection is closed after it.
+    pub(crate) fn handle_request_timeout(error_message: String) -> Response {
+        let context = Context::new().with("error_message", error_message);
+
+        Response::builder()
+            .status(HttpStatus::RequestTimeout)
+            .body(Templates::render(Templates::REQUEST_TIMEOUT, &context))
+            .build()
+    }
+
+    /// Handles cases where the client sent a request head larger than the server accepts.
+    /// Depending on the part of the head that is too large, a 414 status code is returned for the
+    /// request line, or a 431 status code for the headers, along with an HTML template that
+    /// explains this.
+    ///
+    /// Arguments:
+    /// - **status**: Either `HttpStatus::UriTooLong` or `HttpStatus::HeaderTooLarge`
+    /// - **error_message**: What part of the head was too large
+    pub(crate) fn handle_request_too_large(status: HttpStatus, error_message: String) -> Response {
+        let title = match status {
+            HttpStatus::UriTooLong => "414 - URI Too Long",
+            _ => "431 - Request Header Fields Too Large",
+        };
+        let context = Context::new()
+            .with("status", title)
+            .with("error_message", error_message);
+
+        Response::builder()
+            .status(status)
+            .body(Templates::render(Templates::REQUEST_TOO_LARGE, &context))
             .build()
     }
 
@@ -307,31 +1353,435 @@ impl ErrorHandler {
     /// Arguments:
     /// - **app_error**: The `AppError` to be to a handler
     ///
-    /// The given `AppError` is matched against, and routed to an appropriate error handler after the
-    /// error is logged.  
-    /// All errors propagate to this function and so it is the best and only place that logs errors.
+    /// The given `AppError` is matched against, and routed to an appropriate error handler.  
+    /// Errors are not logged here, that is left to the `ErrorLogger` middleware for errors from
+    /// handlers, and to the server for errors outside of handlers, using `log_error()`.
     pub(crate) fn map_error_to_handler(app_error: AppError) -> Response {
         match app_error {
-            AppError::Invalid(error) => {
-                warn!("{}", error);
-                Self::handle_bad_request(error)
-            }
-            AppError::NotFound(error) => {
-                warn!("{}", error);
-                Self::handle_invalid_file_request()
+            AppError::Invalid(error) => Self::handle_bad_request(error),
+            AppError::NotFound(_) => Self::handle_invalid_file_request(),
+            AppError::NotPermitted(_) => Self::handle_access_denied(),
+            AppError::Timeout(error) => Self::handle_request_timeout(error),
+            AppError::HeaderTooLarge(error) => {
+                Self::handle_request_too_large(HttpStatus::HeaderTooLarge, error)
             }
-            AppError::NotPermitted(error) => {
-                warn!("{}", error);
-                Self::handle_access_denied()
+            AppError::UriTooLong(error) => {
+                Self::handle_request_too_large(HttpStatus::UriTooLong, error)
             }
-            AppError::IO(error) => {
-                log_error!("{}", error);
-                Self::handle_server_error()
+            AppError::IO(_) | AppError::Unknown(_) => Self::handle_server_error(),
+        }
+    }
+
+    /// Logs an `AppError`
+    ///
+    /// Arguments:
+    /// - **app_error**: The `AppError` to be logged
+    ///
+    /// Errors caused by the client are logged as warnings, while IO and unknown errors are logged as
+    /// errors, as they typically mean something is wrong with the server.
+    pub(crate) fn log_error(app_error: &AppError) {
+        match app_error {
+            AppError::Invalid(error)
+            | AppError::NotFound(error)
+            | AppError::NotPermitted(error)
+            | AppError::Timeout(error)
+            | AppError::HeaderTooLarge(error)
+            | AppError::UriTooLong(error) => {
+                warn!("{}", error)
             }
-            AppError::Unknown(error) => {
-                log_error!("{}", error);
-                Self::handle_server_error()
+            AppError::IO(error) | AppError::Unknown(error) => log_error!("{}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{AppError, FileManager};
+    use crate::config::Config;
+    use crate::handlers::{ErrorHandler, RequestHandler, TemplateCache, Templates};
+    use crate::http::RequestParser;
+    use crate::template::{Context, Template};
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::{env, process};
+
+    /// Handles a request through the routes of the server, returning the status line of the response
+    ///
+    /// Arguments:
+    /// - **head**: The request line and any headers of the request, each ending with a CRLF
+    /// - **form**: A URL encoded form sent as the body, if it isn't empty
+    fn handle(head: &str, form: &str) -> Result<String, AppError> {
+        handle_with_body(head, "application/x-www-form-urlencoded", form)
+    }
+
+    /// Uploads files through the routes of the server, returning the status line of the response
+    ///
+    /// Arguments:
+    /// - **head**: Any headers of the request, besides those of its body, each ending with a CRLF
+    /// - **fields**: The name and value of each field of the form, such as `directory`
+    /// - **files**: The name and content of each file
+    fn upload(
+        head: &str,
+        fields: &[(&str, &str)],
+        files: &[(&str, &str)],
+    ) -> Result<String, AppError> {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            ));
+        }
+        for (filename, content) in files {
+            body.push_str(&format!(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\r\n{content}\r\n"
+            ));
+        }
+        body.push_str("--XyZ--\r\n");
+
+        handle_with_body(
+            &format!("POST /upload HTTP/1.1\r\n{head}"),
+            "multipart/form-data; boundary=XyZ",
+            &body,
+        )
+    }
+
+    /// Handles a request with a body through the routes of the server, returning the status line of
+    /// the response
+    fn handle_with_body(head: &str, content_type: &str, body: &str) -> Result<String, AppError> {
+        let content_headers = match body.is_empty() {
+            true => String::new(),
+            false => format!(
+                "Content-Type: {content_type}\r\nContent-Length: {}\r\n",
+                body.len()
+            ),
+        };
+        let mut buffer =
+            format!("{head}Host: localhost\r\n{content_headers}\r\n{body}").into_bytes();
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer)?);
+
+        let response = RequestHandler::routes().route_request(parser.into_request()?)?;
+        let mut written = Vec::new();
+        response
+            .into_writer()?
+            .write_some(&mut written)
+            .map_err(|e| AppError::IO(e.to_string()))?;
+        let written = String::from_utf8_lossy(&written);
+        Ok(written.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[test]
+    fn compile_every_template() {
+        let load = |name: &str| Templates::embedded(name).map(str::to_string);
+        for (name, values) in Templates::PAGES {
+            if let Err(e) = Template::compile(name, &load, values) {
+                panic!("Failed to compile template {name}: {e:?}");
             }
         }
     }
+
+    #[test]
+    fn recompile_pages_only_when_the_theme_changes() {
+        let theme_dir = env::temp_dir().join(format!("web-server-theme-{}", process::id()));
+        fs::create_dir_all(&theme_dir).unwrap();
+        let cache = TemplateCache::new(Some(theme_dir.clone()), true);
+        let render = || {
+            cache
+                .get(Templates::METHOD_NOT_ALLOWED)
+                .render(&Context::new().with("method", "PUT"))
+        };
+        let theme_file = theme_dir.join(Templates::METHOD_NOT_ALLOWED);
+
+        // A theme file replaces the embedded template
+        let embedded_page = render();
+        fs::write(&theme_file, "<p>Custom {{ method }}</p>").unwrap();
+        assert_eq!(render(), "<p>Custom PUT</p>");
+
+        // The page is compiled again only once the theme file is modified
+        let template = cache.get(Templates::METHOD_NOT_ALLOWED);
+        assert!(Arc::ptr_eq(
+            &template,
+            &cache.get(Templates::METHOD_NOT_ALLOWED)
+        ));
+        let modified = fs::metadata(&theme_file).unwrap().modified().unwrap();
+        fs::write(&theme_file, "<p>Edited {{ method }}</p>").unwrap();
+        File::options()
+            .write(true)
+            .open(&theme_file)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(render(), "<p>Edited PUT</p>");
+
+        // A theme file that doesn't compile leaves the last page that did
+        fs::write(&theme_file, "<p>{{ missing }}</p>").unwrap();
+        File::options()
+            .write(true)
+            .open(&theme_file)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(render(), "<p>Edited PUT</p>");
+
+        fs::remove_file(&theme_file).unwrap();
+        assert_eq!(render(), embedded_page);
+        fs::remove_dir(&theme_dir).unwrap();
+    }
+
+    #[test]
+    fn delete_files_and_empty_folders_inside_uploads_only() {
+        let uploads_dir = &Config::get().uploads_dir;
+        let base = Path::new(uploads_dir);
+        FileManager::create_dir(uploads_dir, Path::new("delete-test/kept/empty")).unwrap();
+        fs::write(base.join("delete-test/kept/a.txt"), "a").unwrap();
+        let outside = base.parent().unwrap().join("outside-delete-test.txt");
+        fs::write(&outside, "outside").unwrap();
+
+        // Deleting a file keeps a folder that still has entries
+        let status = handle("DELETE /uploads/delete-test/kept/a.txt HTTP/1.1\r\n", "");
+        assert!(status.unwrap().starts_with("HTTP/1.1 204 "));
+        assert!(!base.join("delete-test/kept/a.txt").exists());
+        assert!(base.join("delete-test/kept/empty").is_dir());
+
+        for path in [
+            "/uploads/../outside-delete-test.txt",
+            "/uploads/%2E%2E/outside-delete-test.txt",
+            "/folders/..",
+            "/folders/delete-test/../..",
+        ] {
+            let status = handle(&format!("DELETE {path} HTTP/1.1\r\n"), "");
+            assert!(
+                !status.is_ok_and(|status| status.starts_with("HTTP/1.1 2")),
+                "{path} was deleted"
+            );
+        }
+        assert!(outside.exists());
+        assert!(base.is_dir());
+
+        // Only empty folders are deleted, through DELETE or a form
+        assert!(matches!(
+            handle("DELETE /folders/delete-test/kept HTTP/1.1\r\n", ""),
+            Err(AppError::NotPermitted(_))
+        ));
+        let status = handle("DELETE /folders/delete-test/kept/empty HTTP/1.1\r\n", "");
+        assert!(status.unwrap().starts_with("HTTP/1.1 204 "));
+        let status = handle(
+            "POST /folders/delete-test/kept HTTP/1.1\r\n",
+            "_method=DELETE",
+        );
+        assert!(status.unwrap().starts_with("HTTP/1.1 303 "));
+        assert!(!base.join("delete-test/kept").exists());
+        assert!(base.join("delete-test").is_dir());
+
+        fs::remove_dir(base.join("delete-test")).unwrap();
+        fs::remove_file(&outside).unwrap();
+    }
+
+    #[test]
+    fn remove_folders_emptied_by_a_delete_up_to_uploads() {
+        let uploads_dir = &Config::get().uploads_dir;
+        let base = Path::new(uploads_dir);
+        FileManager::create_dir(uploads_dir, Path::new("a/b")).unwrap();
+        fs::write(base.join("a/b/x.txt"), "x").unwrap();
+
+        let status = handle("DELETE /uploads/a/b/x.txt HTTP/1.1\r\n", "");
+        assert!(status.unwrap().starts_with("HTTP/1.1 204 "));
+        assert!(!base.join("a/b").exists());
+        assert!(!base.join("a").exists());
+        assert!(base.is_dir());
+    }
+
+    #[test]
+    fn move_files_inside_uploads_only() {
+        let base = Path::new(&Config::get().uploads_dir);
+        fs::create_dir_all(base.join("move-test")).unwrap();
+        fs::write(base.join("move-test/a.txt"), "a").unwrap();
+        fs::write(base.join("move-test/b.txt"), "b").unwrap();
+        let move_a = |headers: &str| {
+            handle(
+                &format!("MOVE /uploads/move-test/a.txt HTTP/1.1\r\n{headers}"),
+                "",
+            )
+        };
+
+        // A file at the destination is only replaced with `Overwrite: T`
+        let status = move_a("Destination: /uploads/move-test/b.txt\r\n");
+        assert!(status.unwrap().starts_with("HTTP/1.1 412 "));
+        assert_eq!(
+            fs::read_to_string(base.join("move-test/b.txt")).unwrap(),
+            "b"
+        );
+        let status = move_a("Destination: /uploads/move-test/b.txt\r\nOverwrite: T\r\n");
+        assert!(status.unwrap().starts_with("HTTP/1.1 204 "));
+        assert_eq!(
+            fs::read_to_string(base.join("move-test/b.txt")).unwrap(),
+            "a"
+        );
+        assert!(!base.join("move-test/a.txt").exists());
+
+        fs::write(base.join("move-test/a.txt"), "a").unwrap();
+        let status = move_a("Destination: http://localhost/uploads/move-test/c.txt\r\n");
+        assert!(status.unwrap().starts_with("HTTP/1.1 201 "));
+
+        // Moving the last file out of a folder removes the folder
+        fs::create_dir_all(base.join("move-test/old/inner")).unwrap();
+        fs::write(base.join("move-test/old/inner/e.txt"), "e").unwrap();
+        let status = handle(
+            "MOVE /uploads/move-test/old/inner/e.txt HTTP/1.1\r\nDestination: /uploads/move-test/e.txt\r\n",
+            "",
+        );
+        assert!(status.unwrap().starts_with("HTTP/1.1 201 "));
+        assert!(!base.join("move-test/old").exists());
+        assert!(base.join("move-test/e.txt").exists());
+
+        for destination in [
+            "/outside-move-test.txt",
+            "/uploads/../outside-move-test.txt",
+            "/uploads/move-test/../../outside-move-test.txt",
+        ] {
+            let status = handle(
+                &format!(
+                    "MOVE /uploads/move-test/c.txt HTTP/1.1\r\nDestination: {destination}\r\n"
+                ),
+                "",
+            );
+            assert!(matches!(status, Err(AppError::NotPermitted(_))));
+        }
+        assert!(
+            !base
+                .parent()
+                .unwrap()
+                .join("outside-move-test.txt")
+                .exists()
+        );
+        assert!(base.join("move-test/c.txt").exists());
+
+        // Browsers move files with a form, which is answered with a redirect once it succeeds
+        let submit_move = |form: &str| handle("POST /uploads/move-test/c.txt HTTP/1.1\r\n", form);
+        let status = submit_move("_method=MOVE&destination=move-test%2Fb.txt");
+        assert!(status.unwrap().starts_with("HTTP/1.1 412 "));
+        let status = submit_move("_method=MOVE&destination=move-test%2Fb.txt&overwrite=true");
+        assert!(status.unwrap().starts_with("HTTP/1.1 303 "));
+        assert!(!base.join("move-test/c.txt").exists());
+        assert!(matches!(
+            submit_move("_method=COPY&destination=move-test%2Fd.txt"),
+            Err(AppError::Invalid(_))
+        ));
+
+        fs::remove_dir_all(base.join("move-test")).unwrap();
+    }
+
+    #[test]
+    fn upload_files_only_once_every_file_passes() {
+        let base = Path::new(&Config::get().uploads_dir);
+
+        // The folder is created along with its parents, and each file is saved where it was resolved
+        let status = upload(
+            "",
+            &[("directory", "upload-test/new")],
+            &[("a.txt", "a"), ("b.txt", "b")],
+        );
+        assert!(status.unwrap().starts_with("HTTP/1.1 303 "));
+        assert_eq!(
+            fs::read_to_string(base.join("upload-test/new/a.txt")).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            fs::read_to_string(base.join("upload-test/new/b.txt")).unwrap(),
+            "b"
+        );
+
+        // A rejected upload saves none of its files, and creates no folders
+        let status = upload(
+            "",
+            &[("directory", "upload-test/bad")],
+            &[("c.txt", "c"), ("d.exe", "d")],
+        );
+        assert!(matches!(status, Err(AppError::Invalid(_))));
+        let status = upload(
+            "",
+            &[("directory", "upload-test/bad")],
+            &[("../../../escaped.txt", "e")],
+        );
+        assert!(matches!(status, Err(AppError::NotPermitted(_))));
+        let status = upload(
+            "",
+            &[("directory", "upload-test/new")],
+            &[("missing/f.txt", "f")],
+        );
+        assert!(matches!(status, Err(AppError::NotFound(_))));
+        let status = upload(
+            "If-None-Match: *\r\n",
+            &[("directory", "upload-test/new")],
+            &[("g.txt", "g"), ("a.txt", "changed")],
+        );
+        assert!(status.unwrap().starts_with("HTTP/1.1 412 "));
+        assert!(!base.join("upload-test/bad").exists());
+        assert!(!base.parent().unwrap().join("escaped.txt").exists());
+        assert!(!base.join("upload-test/new/g.txt").exists());
+        assert_eq!(
+            fs::read_to_string(base.join("upload-test/new/a.txt")).unwrap(),
+            "a"
+        );
+
+        fs::remove_dir_all(base.join("upload-test")).unwrap();
+    }
+
+    #[test]
+    fn upload_files_over_others_only_when_asked() {
+        let base = Path::new(&Config::get().uploads_dir);
+        let directory = ("directory", "conflict-test");
+        let status = upload("", &[directory], &[("a.txt", "a")]);
+        assert!(status.unwrap().starts_with("HTTP/1.1 303 "));
+
+        // Nothing is saved when a file is already at one of the paths, or two files share a path
+        let status = upload("", &[directory], &[("b.txt", "b"), ("a.txt", "changed")]);
+        assert!(status.unwrap().starts_with("HTTP/1.1 409 "));
+        let status = upload("", &[directory], &[("c.txt", "c"), ("c.txt", "other")]);
+        assert!(status.unwrap().starts_with("HTTP/1.1 409 "));
+        let status = upload(
+            "",
+            &[directory, ("overwrite", "true")],
+            &[("c.txt", "c"), ("c.txt", "other")],
+        );
+        assert!(status.unwrap().starts_with("HTTP/1.1 409 "));
+        assert!(!base.join("conflict-test/b.txt").exists());
+        assert!(!base.join("conflict-test/c.txt").exists());
+        assert_eq!(
+            fs::read_to_string(base.join("conflict-test/a.txt")).unwrap(),
+            "a"
+        );
+
+        let status = upload(
+            "",
+            &[directory, ("overwrite", "true")],
+            &[("b.txt", "b"), ("a.txt", "changed")],
+        );
+        assert!(status.unwrap().starts_with("HTTP/1.1 303 "));
+        assert_eq!(
+            fs::read_to_string(base.join("conflict-test/a.txt")).unwrap(),
+            "changed"
+        );
+        assert!(base.join("conflict-test/b.txt").exists());
+
+        fs::remove_dir_all(base.join("conflict-test")).unwrap();
+    }
+
+    #[test]
+    fn answer_invalid_requests_with_bad_request() {
+        let response = ErrorHandler::map_error_to_handler(AppError::Invalid(
+            "Form is not valid UTF-8".to_string(),
+        ));
+        let mut written = Vec::new();
+        response
+            .into_writer()
+            .unwrap()
+            .write_some(&mut written)
+            .unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"));
+        assert!(written.contains("Form is not valid UTF-8"));
+    }
 }
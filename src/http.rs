@@ -1,66 +1,258 @@
-use crate::common::{AppError, BufferedFile};
-use std::collections::HashMap;
+use crate::common::{AppError, TempFile, Time};
+use crate::config::Config;
+use std::collections::VecDeque;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::io::{BufRead, BufReader, Read, Write};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::net::TcpStream;
-use std::ops::Deref;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Limits the file size possible to upload to 50MB, to avoid very large files
-const MAX_REQUEST_BODY_SIZE: usize = 50 * 1024 * 1024;
+/// Limits the size of a regular form field, and of a whole URL encoded form, as they are held in
+/// memory, unlike files
+const MAX_FORM_FIELD_SIZE: usize = 64 * 1024;
 
-/// Represents a URL path
+/// Represents the target of a request, split into its URL decoded path, and its query
 #[derive(Debug, PartialEq)]
-pub(crate) struct Url(String);
+pub(crate) struct Url {
+    path: String,
+    /// The query as it was sent, without the `?`, kept for logging
+    raw_query: Option<String>,
+    query: Query,
+}
 
 impl Url {
-    /// Creates a new Url that is URL decoded
+    /// Creates a new Url from a request target
     ///
     /// Arguments:
-    /// - **raw_url_path**: The raw string that is the URL path
+    /// - **raw_url**: The request target as it was sent, which is a path possibly followed by a `?`
+    ///   and a query
     ///
-    /// The raw path is split into a `Chars` list, and iterated over, with each special URL character
-    /// decoded, and an error being returned if decoding is not possible.
-    fn try_new(raw_url_path: &str) -> Result<Url, AppError> {
-        let mut output = String::new();
-        let mut chars = raw_url_path.chars().peekable();
+    /// The target is split at the first `?`, and the path is decoded and normalized with
+    /// `decode_path()`, while the query is parsed into a `Query`. An error is returned if either
+    /// can't be decoded.
+    fn try_new(raw_url: &str) -> Result<Url, AppError> {
+        let (raw_path, raw_query) = match raw_url.split_once('?') {
+            Some((raw_path, raw_query)) => (raw_path, Some(raw_query)),
+            None => (raw_url, None),
+        };
 
-        while let Some(c) = chars.next() {
-            match c {
-                '%' => {
-                    let hex1 = chars
-                        .next()
-                        .ok_or(AppError::Invalid("Incomplete percent-encoding".to_string()))?;
-                    let hex2 = chars
-                        .next()
-                        .ok_or(AppError::Invalid("Incomplete percent-encoding".to_string()))?;
-                    let hex = format!("{}{}", hex1, hex2);
-                    let decoded_byte = u8::from_str_radix(&hex, 16).map_err(|_| {
-                        AppError::Invalid("Invalid hex in percent-encoding".to_string())
-                    })?;
-                    output.push(decoded_byte as char);
+        Ok(Url {
+            path: Self::decode_path(raw_path)?,
+            raw_query: raw_query.map(str::to_string),
+            query: raw_query.map(Query::parse).transpose()?.unwrap_or_default(),
+        })
+    }
+
+    /// Creates a new Url from a URI, which can be absolute, like the value of a *Destination* header
+    ///
+    /// Arguments:
+    /// - **uri**: Either a path, or an absolute URI like `http://localhost:7878/uploads/a.txt`
+    ///
+    /// The scheme and authority of an absolute URI are dropped, as only the path and query are
+    /// used, and the rest is parsed the same as a request target.
+    pub(crate) fn from_uri(uri: &str) -> Result<Url, AppError> {
+        let target = match uri.split_once("://") {
+            Some((_, rest)) if !uri.starts_with('/') => {
+                rest.find('/').map_or("/", |start| &rest[start..])
+            }
+            _ => uri,
+        };
+        Self::try_new(target)
+    }
+
+    /// Decodes a path, and removes its dot segments
+    ///
+    /// Arguments:
+    /// - **raw_path**: The path as it was sent
+    ///
+    /// Each segment between slashes is percent-decoded on its own, so that a decoded `/` can't be
+    /// told apart from a real one, which is why an encoded slash is rejected, along with a NUL byte,
+    /// which can't be in a file name.  
+    /// A `.` segment is then dropped, and a `..` segment removes the one before it, without ever
+    /// going above the root, as RFC 3986 describes. A target that isn't a path, like the `*` of an
+    /// OPTIONS request, is only decoded.
+    fn decode_path(raw_path: &str) -> Result<String, AppError> {
+        let Some(raw_path) = raw_path.strip_prefix('/') else {
+            return Self::decode(raw_path, false);
+        };
+
+        let raw_segments: Vec<&str> = raw_path.split('/').collect();
+        let mut segments: Vec<String> = Vec::new();
+        for (index, raw_segment) in raw_segments.iter().enumerate() {
+            let segment = Self::decode(raw_segment, false)?;
+            if segment.contains('/') {
+                return Err(AppError::Invalid(format!(
+                    "Path has an encoded slash: {raw_path}"
+                )));
+            }
+            if segment.contains('\0') {
+                return Err(AppError::Invalid(format!(
+                    "Path has an encoded NUL byte: {raw_path}"
+                )));
+            }
+
+            match segment.as_str() {
+                "." | ".." => {
+                    if segment == ".." {
+                        segments.pop();
+                    }
+                    // A path ending in a dot segment still names a directory
+                    if index == raw_segments.len() - 1 {
+                        segments.push(String::new());
+                    }
                 }
-                '+' => output.push(' '),
-                _ => output.push(c),
+                _ => segments.push(segment),
             }
         }
 
-        Ok(Url(output))
+        Ok(format!("/{}", segments.join("/")))
+    }
+
+    /// Percent-decodes a part of a URL
+    ///
+    /// Arguments:
+    /// - **text**: The encoded text
+    /// - **plus_as_space**: Whether a `+` stands for a space, which is only the case in queries, as
+    ///   HTML forms encode them that way
+    ///
+    /// Every `%` followed by two hex digits is decoded into the byte they stand for, and the bytes
+    /// are then read as UTF-8, so that a character encoded as several bytes, like `%C3%A9` for `é`,
+    /// is decoded as one. An error is returned if a `%` isn't followed by two hex digits, or the
+    /// result isn't valid UTF-8.
+    fn decode(text: &str, plus_as_space: bool) -> Result<String, AppError> {
+        let mut output = Vec::with_capacity(text.len());
+        let mut bytes = text.bytes();
+
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'%' => {
+                    let hex = [bytes.next(), bytes.next()];
+                    let [Some(high), Some(low)] = hex else {
+                        return Err(AppError::Invalid("Incomplete percent-encoding".to_string()));
+                    };
+                    let digit = |byte: u8| (byte as char).to_digit(16);
+                    let (Some(high), Some(low)) = (digit(high), digit(low)) else {
+                        return Err(AppError::Invalid(
+                            "Invalid hex in percent-encoding".to_string(),
+                        ));
+                    };
+                    output.push((high * 16 + low) as u8);
+                }
+                b'+' if plus_as_space => output.push(b' '),
+                _ => output.push(byte),
+            }
+        }
+
+        String::from_utf8(output)
+            .map_err(|_| AppError::Invalid(format!("URL is not valid UTF-8: {text}")))
+    }
+
+    /// Percent-encodes a path, so that it can be put in a link
+    ///
+    /// Arguments:
+    /// - **path**: The path to encode, whose slashes are kept
+    ///
+    /// Every byte other than the unreserved characters of RFC 3986 and `/` is encoded, including
+    /// each byte of a non-ASCII character.
+    pub(crate) fn encode_path(path: &str) -> String {
+        let mut output = String::with_capacity(path.len());
+        for byte in path.bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+                output.push(byte as char);
+            } else {
+                output.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        output
     }
-}
 
-impl Deref for Url {
-    type Target = String;
+    /// Gets the URL decoded path, without the query
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Gets the parsed query, which is empty if the request had none
+    pub(crate) fn query(&self) -> &Query {
+        &self.query
     }
 }
 
 impl Display for Url {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        self.path.fmt(f)?;
+        match &self.raw_query {
+            Some(raw_query) => write!(f, "?{raw_query}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A `Query` holds the parameters of the query of a URL, in the order they were sent.
+/// A name can appear more than once, like `?tag=a&tag=b`, so every value is kept.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Query(Vec<(String, String)>);
+
+impl Query {
+    /// Parses a query in the `application/x-www-form-urlencoded` format HTML forms use
+    ///
+    /// Arguments:
+    /// - **raw_query**: The query as it was sent, without the `?`
+    ///
+    /// Parameters are separated by `&`, and a name is separated from its value by the first `=`.
+    /// A parameter without a `=` has an empty value, and empty parameters are skipped.
+    fn parse(raw_query: &str) -> Result<Query, AppError> {
+        raw_query
+            .split('&')
+            .filter(|parameter| !parameter.is_empty())
+            .map(|parameter| {
+                let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+                Ok((Url::decode(name, true)?, Url::decode(value, true)?))
+            })
+            .collect::<Result<Vec<(String, String)>, AppError>>()
+            .map(Query)
+    }
+
+    /// Gets the first value of a parameter
+    ///
+    /// Arguments:
+    /// - **name**: The name of the parameter
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Gets every value of a parameter, in the order they were sent
+    ///
+    /// Arguments:
+    /// - **name**: The name of the parameter
+    pub(crate) fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(parameter_name, _)| parameter_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Gets the first value of a parameter parsed as a type, like a number or a `bool`
+    ///
+    /// Arguments:
+    /// - **name**: The name of the parameter
+    ///
+    /// `None` is returned if the parameter wasn't sent, and an `AppError::Invalid` if its value
+    /// can't be parsed, as the client asked for something the server can't make sense of.
+    pub(crate) fn get_parsed<T: FromStr>(&self, name: &str) -> Result<Option<T>, AppError> {
+        self.get(name)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    AppError::Invalid(format!(
+                        "Invalid value of query parameter `{name}`: {value}"
+                    ))
+                })
+            })
+            .transpose()
     }
 }
 
@@ -76,6 +268,8 @@ pub(crate) enum HttpMethod {
     Options,
     Trace,
     Connect,
+    /// The WebDAV method to move or rename a resource to the path in its *Destination* header
+    Move,
 }
 
 impl TryFrom<String> for HttpMethod {
@@ -93,6 +287,7 @@ impl TryFrom<String> for HttpMethod {
             "OPTIONS" => HttpMethod::Options,
             "TRACE" => HttpMethod::Trace,
             "CONNECT" => HttpMethod::Connect,
+            "MOVE" => HttpMethod::Move,
             _ => {
                 return Err(AppError::Invalid(format!("Unknown method: {}", value)));
             }
@@ -113,6 +308,7 @@ impl Display for HttpMethod {
             HttpMethod::Options => write!(f, "OPTIONS"),
             HttpMethod::Trace => write!(f, "TRACE"),
             HttpMethod::Connect => write!(f, "CONNECT"),
+            HttpMethod::Move => write!(f, "MOVE"),
         }
     }
 }
@@ -122,73 +318,220 @@ pub(crate) struct Request {
     pub(crate) path: Url,
     pub(crate) method: HttpMethod,
     http_version: String,
-    headers: HashMap<String, String>,
+    headers: Headers,
     pub(crate) body: RequestBody,
 }
 
 /// A `RequestBody` is an abstraction of an HTTP request body
+/// - **Multipart**: A `multipart/form-data` form, which can have files
+/// - **Form**: An `application/x-www-form-urlencoded` form, which HTML forms send by default, with
+///   its fields parsed like a query
+/// - **Empty**: No body
 pub(crate) enum RequestBody {
-    Multipart(BufferedFile),
+    Multipart(MultipartForm),
+    Form(Query),
     Empty,
 }
 
 impl Display for RequestBody {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            RequestBody::Multipart(uploaded_file) => write!(f, "{}", uploaded_file),
+            RequestBody::Multipart(form) => write!(f, "{}", form),
+            RequestBody::Form(form) => {
+                for (name, value) in &form.0 {
+                    writeln!(f, "Field: {name}={value}")?;
+                }
+                Ok(())
+            }
             RequestBody::Empty => write!(f, "Empty"),
         }
     }
 }
 
+/// A `MultipartForm` is an abstraction of a multipart/form-data body.  
+/// It holds every file and every regular field sent in the form, each in the order they were sent
+#[derive(Default)]
+pub(crate) struct MultipartForm {
+    pub(crate) files: Vec<FormFile>,
+    pub(crate) fields: Vec<FormField>,
+}
+
+impl Display for MultipartForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for field in &self.fields {
+            writeln!(f, "Field: {}={}", field.name, field.value)?;
+        }
+        for form_file in &self.files {
+            writeln!(
+                f,
+                "File: {} ({}, {} bytes) in field {}",
+                form_file.filename,
+                form_file.content_type.as_deref().unwrap_or("unknown type"),
+                form_file.file.size(),
+                form_file.field_name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A `FormFile` is a file part of a multipart form, with the name of the field it was sent in, the
+/// file name and content type the client gave it, and the `TempFile` its content was streamed into
+pub(crate) struct FormFile {
+    pub(crate) field_name: String,
+    pub(crate) filename: String,
+    pub(crate) content_type: Option<String>,
+    pub(crate) file: TempFile,
+}
+
+/// A `FormField` is a regular text part of a multipart form
+pub(crate) struct FormField {
+    pub(crate) name: String,
+    pub(crate) value: String,
+}
+
 /// A `ResponseBody` is an abstraction of an HTTP response body
-#[derive(Debug)]
+/// - **File**: The path of a file, which is streamed from disk
+/// - **FileRanges**: The path of a file and the ranges of it to send
+/// - **Text**: An HTML page
+/// - **Stream**: A reader of a body whose size isn't known up front, which is streamed as it is read.
+///   It is read on the event loop, so it must only produce bytes from memory, like a
+///   `TemplateReader` does, and never wait on the disk or the network
+/// - **Empty**: No body at all
 pub(crate) enum ResponseBody {
     File(String),
+    FileRanges(String, Vec<ByteRange>),
     Text(String),
+    Stream(Box<dyn Read + Send>),
     Empty,
 }
 
-impl Request {
-    /// Tries to create a new HTTP request
+/// A `ByteRange` is an inclusive range of bytes of a file, requested with the *Range* header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl ByteRange {
+    /// The most ranges a single request can ask for, as every range costs a seek and a part header
+    const MAX_RANGES: usize = 16;
+
+    /// Parses the value of a *Range* header against the size of a file
     ///
     /// Arguments:
-    /// - **buf_reader**: a `BufReader` of a `TcpStream` containing the current HTTP request
+    /// - **value**: The value of the *Range* header
+    /// - **file_size**: The size of the file the ranges are in
     ///
-    /// The `BufReader`'s first line is read into a string, and the request line is extracted from that.
-    /// It is then used to extract the headers from the next couple of lines.
-    /// And finally, used to extract the request body. If extracting the body fails, the body is drained,
-    /// in case it was unread, as this could lead to unexpected behavior.
-    pub(crate) fn try_new(mut buf_reader: BufReader<&mut TcpStream>) -> Result<Request, AppError> {
-        let mut line = String::new();
+    /// Each comma separated range is either `start-end`, `start-` for everything from `start`, or
+    /// `-length` for the last `length` bytes. The end of a range is clamped to the end of the file,
+    /// and ranges that start past the end of the file are dropped, so an empty list means that
+    /// none of the ranges can be satisfied.  
+    /// Overlapping and adjacent ranges are merged into one.  
+    /// `None` is returned if the header is malformed, or asks for too many ranges, in which case
+    /// the header must be ignored and the whole file served.
+    pub(crate) fn parse_ranges(value: &str, file_size: u64) -> Option<Vec<ByteRange>> {
+        let range_set = value.trim().strip_prefix("bytes=")?;
+        let mut ranges = Vec::new();
+
+        for range_spec in range_set.split(',').map(str::trim) {
+            if range_spec.is_empty() {
+                continue;
+            }
+            let (start, end) = range_spec.split_once('-')?;
+            let (start, end) = (start.trim(), end.trim());
+
+            if start.is_empty() {
+                let suffix_length: u64 = end.parse().ok()?;
+                if suffix_length > 0 && file_size > 0 {
+                    ranges.push(ByteRange {
+                        start: file_size.saturating_sub(suffix_length),
+                        end: file_size - 1,
+                    });
+                }
+                continue;
+            }
 
-        buf_reader
-            .read_line(&mut line)
-            .map_err(|_| AppError::IO("Error reading request".to_string()))?;
-        let (method, path, http_version) = Self::extract_request_line(line)?;
-        let headers = Self::extract_headers(&mut buf_reader)?;
-        let body = match Self::extract_body(&mut buf_reader, &headers) {
-            Ok(body) => body,
-            Err(e) => {
-                let content_length = headers
-                    .get(HttpHeader::CONTENT_LENGTH)
-                    .map(|value| value.parse::<usize>())
-                    // If for some reason, there is no content length header, default to 50MB
-                    .unwrap_or(Ok(MAX_REQUEST_BODY_SIZE))
-                    .unwrap_or(MAX_REQUEST_BODY_SIZE);
-                // Drain the request body before writing a response to the stream
-                Self::drain_body(&mut buf_reader, content_length)?;
-                return Err(e);
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = if end.is_empty() {
+                u64::MAX
+            } else {
+                end.parse().ok()?
+            };
+            if end < start {
+                return None;
             }
-        };
+            if start < file_size {
+                ranges.push(ByteRange {
+                    start,
+                    end: end.min(file_size - 1),
+                });
+            }
+        }
 
-        Ok(Request {
-            path,
-            method,
-            http_version,
-            headers,
-            body,
-        })
+        if ranges.len() > Self::MAX_RANGES {
+            return None;
+        }
+
+        ranges.sort_by_key(|range| range.start);
+        let mut merged_ranges: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged_ranges.last_mut() {
+                Some(last) if range.start <= last.end.saturating_add(1) => {
+                    last.end = last.end.max(range.end);
+                }
+                _ => merged_ranges.push(range),
+            }
+        }
+
+        Some(merged_ranges)
+    }
+
+    /// Returns the number of bytes in the range
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+impl Request {
+    /// Gets the value of a request header, if the client sent it
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header, in header case, like the `HttpHeader` constants
+    pub(crate) fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Gets the parsed query of the request, to read its parameters from
+    pub(crate) fn query(&self) -> &Query {
+        self.path.query()
+    }
+
+    /// Gets every header of the request, for headers that can be sent more than once, and their
+    /// typed accessors
+    pub(crate) fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Checks if the client can receive a response body with `Transfer-Encoding: chunked`, which
+    /// HTTP/1.0 clients can't
+    pub(crate) fn accepts_chunked(&self) -> bool {
+        self.http_version != "HTTP/1.0"
+    }
+
+    /// Checks if the connection the request came in on should be kept open after responding
+    ///
+    /// HTTP/1.1 connections are persistent unless the client sends `Connection: close`, while
+    /// HTTP/1.0 connections are closed unless the client explicitly asks for `Connection: keep-alive`.
+    pub(crate) fn is_keep_alive(&self) -> bool {
+        let has_connection_option =
+            |option: &str| self.headers.has_token(HttpHeader::CONNECTION, option);
+
+        if self.http_version == "HTTP/1.0" {
+            has_connection_option("keep-alive")
+        } else {
+            !has_connection_option("close")
+        }
     }
 
     /// Extracts the method path and HTTP version from the request line.
@@ -219,22 +562,21 @@ impl Request {
         Ok((method, path, http_version))
     }
 
-    /// Extracts headers from a `TcpStream`
+    /// Extracts headers from the head of a request
     ///
     /// Arguments:
-    /// - **buf_reader**: A mutable reference to a `BufReader` of a mutable reference to a `TcpStream`
+    /// - **reader**: A `BufRead` of the head of the request, after the request line
     ///
-    /// Starts a loop of reading a line from the `BufReader` to a string, and then splitting the string
+    /// Starts a loop of reading a line from the reader to a string, and then splitting the string
     /// on a colon to get the key and value of each header. The loop breaks when we reach an empty line,
-    /// marking the end of the headers in the HTTP request.
-    fn extract_headers(
-        buf_reader: &mut BufReader<&mut TcpStream>,
-    ) -> Result<HashMap<String, String>, AppError> {
-        let mut headers = HashMap::new();
+    /// marking the end of the headers in the HTTP request.  
+    /// Headers are kept in the order they were sent, including repeated ones.
+    fn extract_headers<R: BufRead>(reader: &mut R) -> Result<Headers, AppError> {
+        let mut headers = Headers::new();
 
         loop {
             let mut line = String::new();
-            buf_reader
+            reader
                 .read_line(&mut line)
                 .map_err(|e| AppError::Invalid(format!("Error reading headers: {}", e)))?;
 
@@ -246,282 +588,1401 @@ impl Request {
             let Some((key, value)) = line.split_once(":") else {
                 return Err(AppError::Invalid("Error parsing headers".to_string()));
             };
-            // Transform the case of the header to ensure we always store them in header case
-            let key = Self::transform_to_header_case(key.trim());
-            headers.insert(key, value.trim().to_string());
+            headers.append(key.trim(), value.trim());
         }
         Ok(headers)
     }
 
-    /// Extracts a body from a `TcpStream`
+    /// Extracts a body that isn't a multipart form from a reader of it
     ///
     /// Arguments:
-    /// - **buf_reader**: A mutable reference to a `BufReader` of a mutable reference to a `TcpStream`
-    /// - **headers**: A reference to a `HashMap` containing HTTP request headers.
+    /// - **reader**: A reader of the body, decoded if it was sent chunked, which ends where the body ends
+    /// - **content_type**: The *Content-Type* header value, if any
     ///
-    /// Gets the content length and content type headers to know how to read the body.
-    /// If the content length is 0 or either is not set, the request has no body.
-    /// Otherwise, the content type is matched against and determines the extractor to call.
-    fn extract_body(
-        buf_reader: &mut BufReader<&mut TcpStream>,
-        headers: &HashMap<String, String>,
+    /// The content type is matched against and determines how the body is read. A URL encoded form
+    /// is read whole, up to `MAX_FORM_FIELD_SIZE`, and parsed like a query. A body without a
+    /// content type is ignored.  
+    /// Multipart forms never get here, as they are parsed by a `MultipartParser` while they arrive.
+    fn extract_body<R: Read>(
+        mut reader: R,
+        content_type: Option<&str>,
     ) -> Result<RequestBody, AppError> {
-        let content_length = headers
-            .get(HttpHeader::CONTENT_LENGTH)
-            .map(|value| value.parse::<usize>())
-            .transpose()
-            .map_err(|_| {
-                AppError::Invalid(format!(
-                    "{} request header is not a number",
-                    HttpHeader::CONTENT_LENGTH
-                ))
-            })?;
-        let content_type_header = headers
-            .get(HttpHeader::CONTENT_TYPE)
-            .and_then(|content_type| Some(content_type.to_string()));
-
-        if content_length.is_none()
-            || content_length.is_some_and(|len| len == 0)
-            || content_type_header.is_none()
-        {
-            return Ok(RequestBody::Empty);
-        }
-
-        let content_length_header = content_length.unwrap();
-        let content_type_header = content_type_header.unwrap();
-
-        match content_type_header {
-            content_type if content_type.starts_with("multipart/form-data") => {
-                MultiPartFormExtractor::extract(buf_reader, content_type, content_length_header)
-                    .map(|uploaded_file| RequestBody::Multipart(uploaded_file))
+        match content_type {
+            Some(content_type) if content_type.starts_with("application/x-www-form-urlencoded") => {
+                let mut content = LimitedBuffer::new(MAX_FORM_FIELD_SIZE);
+                io::copy(&mut reader, &mut content)
+                    .map_err(|e| AppError::Invalid(format!("Failed to read form: {e}")))
+                    .and_then(|_| {
+                        String::from_utf8(content.buffer)
+                            .map_err(|_| AppError::Invalid("Form is not valid UTF-8".to_string()))
+                    })
+                    .and_then(|form| Query::parse(&form))
+                    .map(RequestBody::Form)
             }
-            _ => Err(AppError::Invalid(format!(
-                "Unsupported content type: {content_type_header}"
+            Some(content_type) => Err(AppError::Invalid(format!(
+                "Unsupported content type: {content_type}"
             ))),
+            None => Ok(RequestBody::Empty),
         }
     }
+}
 
-    fn transform_to_header_case(s: &str) -> String {
-        s.split('-')
-            .map(|word| {
-                let mut chars = word.chars();
-                match chars.next() {
-                    Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
-                    None => String::new(),
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("-")
+/// A `RequestParser` reads a request from the bytes of a connection as they arrive, so that
+/// waiting on a slow client never holds up a thread.
+///
+/// The lines of the head are checked against the limits in the `Config` as they arrive, and the head
+/// is parsed once all of it has, which tells how its body is framed.
+/// The body is then decoded as it arrives, until its framing says it is complete, and queued in a
+/// `BodyQueue` a chunk at a time, for workers to write into its `RequestBodySink`, so that the event
+/// loop never waits on the disk. A multipart form is parsed as it is written, so that uploaded files
+/// are only written to disk once, and any other body is collected in a `RequestBodySpool`, and
+/// extracted by `into_request()`.
+#[derive(Default)]
+pub(crate) struct RequestParser {
+    head: Option<RequestHead>,
+    /// How much of the buffer is made of complete lines of the head, which were already checked
+    head_scanned: usize,
+    /// The number of complete lines of the head, the request line included
+    head_lines: usize,
+    body: Arc<BodyQueue>,
+    /// The bytes of the body decoded since the last chunk was queued
+    body_chunk: Vec<u8>,
+    /// The number of bytes of the body that arrived, as they were sent
+    body_received: u64,
+    continue_expected: bool,
+}
+
+impl RequestParser {
+    /// Creates a new `RequestParser` for the next request on a connection
+    pub(crate) fn new() -> Self {
+        Self::default()
     }
 
-    /// Drains a `TCPStream` of its body
+    /// Parses the bytes that arrived on a connection
     ///
     /// Arguments:
-    /// - **reader**: a mutable reference to a `BufReader` of a mutable reference to a `TcpStream`
-    /// - **content_length**: The length of the body to be drained
-    ///
-    /// This method makes sure a request body is read, in case of a failure while extracting the body.
-    /// Failure to read the request body before responding can lead to the client not knowing to
-    /// expect a response and closing the connection early.
+    /// - **buffer**: The bytes read from the connection that weren't parsed yet
     ///
-    /// An 8KB buffer is created and the stream is repeatedly read into it, overwriting the last read,
-    /// until the stream has no more bytes to be read, indicating the body has been successfully drained.
-    fn drain_body(
-        reader: &mut BufReader<&mut TcpStream>,
-        content_length: usize,
-    ) -> Result<(), AppError> {
-        let mut buffer = [0u8; 8192];
-        let mut remaining = content_length;
-
-        while remaining > 0 {
-            let to_read = std::cmp::min(remaining, buffer.len());
-            let bytes_read = reader
-                .read(&mut buffer[..to_read])
-                .map_err(|e| AppError::IO(e.to_string()))?;
+    /// The bytes belonging to this request are taken out of the buffer, leaving those of the next
+    /// request, if the client already sent it. The head is parsed once the empty line ending it has
+    /// arrived, and the body is then decoded into chunks queued in the `BodyQueue` until it is
+    /// complete, when the last chunk is queued however short it is.  
+    /// Returns `true` once the whole request has arrived, or an error if it can't be parsed, after
+    /// which the connection can't be trusted to be at the start of a new request.
+    pub(crate) fn parse(&mut self, buffer: &mut Vec<u8>) -> Result<bool, AppError> {
+        let head = match &mut self.head {
+            Some(head) => head,
+            None => {
+                let Some(head_end) = self.scan_head(buffer)? else {
+                    return Ok(false);
+                };
+
+                let head = RequestHead::parse(&buffer[..head_end])?;
+                buffer.drain(..head_end);
+                self.continue_expected = head.expects_continue();
+                let sink = RequestBodySink::for_content_type(head.headers.content_type());
+                self.body = Arc::new(BodyQueue::new(sink));
+                self.head.insert(head)
+            }
+        };
 
-            if bytes_read == 0 {
-                break; // client closed connection early
+        let (body, body_chunk) = (&self.body, &mut self.body_chunk);
+        let mut write = |data: &[u8]| {
+            body_chunk.extend_from_slice(data);
+            if body_chunk.len() >= BodyQueue::CHUNK_SIZE {
+                body.push(mem::take(body_chunk));
+            }
+            Ok(())
+        };
+        let (body_length, is_complete) = match &mut head.framing {
+            BodyFraming::Length(remaining) => {
+                let length = std::cmp::min(*remaining, buffer.len() as u64);
+                *remaining -= length;
+                write(&buffer[..length as usize])?;
+                (length as usize, *remaining == 0)
             }
+            BodyFraming::Chunked(decoder) => decoder.decode(buffer, write)?,
+        };
+        self.body_received += body_length as u64;
+        buffer.drain(..body_length);
 
-            remaining -= bytes_read;
+        if is_complete {
+            self.body.push(mem::take(&mut self.body_chunk));
         }
-
-        Ok(())
+        Ok(is_complete)
     }
-}
-
-/// This represents a contract that all body extractors should fulfill
-trait BodyExtractor {
-    type Body;
 
-    /// Extracts a body from a TCP stream
+    /// Checks the lines of the head that arrived since the last call
     ///
     /// Arguments:
-    /// - **buf_reader**: A mutable reference to a `BufReader` of a mutable reference to a `TcpStream`
-    /// - **content_type**: The content type of the body to determine how to read it
-    /// - **content_length**: The length of the body, to determine how much to read from the stream
+    /// - **buffer**: The bytes read from the connection that weren't parsed yet
     ///
-    /// This method must be implemented by any struct that implements this trait
-    fn extract(
-        buf_reader: &mut BufReader<&mut TcpStream>,
-        content_type: String,
-        content_length: usize,
-    ) -> Result<Self::Body, AppError>;
-}
-
-/// A type that helps extract a body from a multipart/form
-struct MultiPartFormExtractor;
+    /// Empty lines before the request line are dropped from the buffer, as some clients send one
+    /// after the body of their previous request.  
+    /// A line is rejected as soon as it is longer than allowed, even if it hasn't fully arrived, so
+    /// that a client can't make the server buffer an unbounded head. A request line that is too long
+    /// is answered with 414, as it is almost always its URI that is, and a header line that is too
+    /// long, or too many headers, with 431.  
+    /// Returns the length of the head once the empty line ending it has arrived.
+    fn scan_head(&mut self, buffer: &mut Vec<u8>) -> Result<Option<usize>, AppError> {
+        let config = Config::get();
+        if self.head_lines == 0 {
+            let blank_length = buffer
+                .iter()
+                .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+                .count();
+            buffer.drain(..blank_length);
+        }
 
-impl BodyExtractor for MultiPartFormExtractor {
-    type Body = BufferedFile;
+        loop {
+            let (kind, max_length, error): (&str, u64, fn(String) -> AppError) =
+                if self.head_lines == 0 {
+                    (
+                        "Request line",
+                        config.max_request_line_length,
+                        AppError::UriTooLong,
+                    )
+                } else {
+                    (
+                        "Header line",
+                        config.max_header_line_length,
+                        AppError::HeaderTooLarge,
+                    )
+                };
+            let too_long = || error(format!("{kind} exceeds the limit of {max_length} bytes"));
+
+            let rest = &buffer[self.head_scanned..];
+            let Some(line_end) = rest.iter().position(|&byte| byte == b'\n') else {
+                // The line hasn't fully arrived, but may already be too long, leaving room for its `\r`
+                if rest.len().saturating_sub(1) as u64 > max_length {
+                    return Err(too_long());
+                }
+                return Ok(None);
+            };
+            let line = &rest[..line_end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            self.head_scanned += line_end + 1;
 
-    /// Extracts a body from a multipart form request
-    ///
-    /// Arguments:
-    /// - **buf_reader**: A mutable reference to a `BufReader` of a mutable reference to a `TcpStream`
-    /// - **content_type**: *Content-Type* header value
-    /// - **content_length**: *Content-Length* header value
-    ///
-    /// The *Content-Type* header is checked to determine if the file is larger the allowed size, if
-    /// so, an error is returned.  
-    /// The boundary is gotten from the *Content-Type* header value, and then the `TcpStream` is read
-    /// into a byte buffer of the size determined by the *Content-Length* header, which is the exact
-    /// size of the body.  
-    /// The body is then stripped of the boundaries and split on newlines, extracting the first 3
-    /// parts; the content disposition which contains the file name, the content type and the file.  
-    /// The file name and file data are parsed and used to construct a `BufferedFile` which gets returned.
-    fn extract(
-        buf_reader: &mut BufReader<&mut TcpStream>,
-        content_type: String,
-        content_length: usize,
-    ) -> Result<Self::Body, AppError> {
-        if content_length > MAX_REQUEST_BODY_SIZE {
-            return Err(AppError::Invalid(
-                "File size exceeds 50MB limit".to_string(),
-            ));
+            if line.is_empty() {
+                return Ok(Some(self.head_scanned));
+            }
+            if line.len() as u64 > max_length {
+                return Err(too_long());
+            }
+            self.head_lines += 1;
+            if self.head_lines - 1 > config.max_header_count {
+                return Err(AppError::HeaderTooLarge(format!(
+                    "Request has more than {} headers",
+                    config.max_header_count
+                )));
+            }
         }
+    }
 
-        let (_, boundary) = content_type
-            .split_once("boundary=")
-            .ok_or(AppError::Invalid(
-                "Boundary missing in Content-Type header".to_string(),
-            ))?;
-        let boundary = boundary.trim();
-
-        let mut form_body_buffer = vec![0; content_length];
-        buf_reader
-            .read_exact(&mut form_body_buffer)
-            .map_err(|_| AppError::Invalid("Failed to read form data".to_string()))?;
-        let form_body = String::from_utf8(form_body_buffer)
-            .map_err(|_| AppError::Invalid("Failed to parse form data".to_string()))?;
+    /// Gets the number of bytes of the body that arrived so far
+    pub(crate) fn body_received(&self) -> u64 {
+        self.body_received
+    }
 
-        let form_body = form_body
-            .trim()
-            .strip_prefix(format!("--{boundary}").as_str())
-            .and_then(|body| body.strip_suffix(format!("--{boundary}--").as_str()))
-            .ok_or(AppError::Invalid(
-                "Form body not surrounded with boundary".to_string(),
-            ))?
-            .trim()
-            .to_string();
+    /// Checks if any of the request has been parsed yet
+    pub(crate) fn is_started(&self) -> bool {
+        self.head.is_some()
+    }
 
-        let mut parts = form_body.splitn(3, "\n");
-        let filename = parts
-            .next()
-            .and_then(|content_disposition| {
-                let (_, filename_part) = content_disposition.rsplit_once(';')?;
-                let (_, filename) = filename_part.split_once("=")?;
-                let filename = filename.trim().trim_matches('"').to_string();
+    /// Checks if the client is waiting for a `100 Continue` response before sending the body, which
+    /// is only reported once
+    pub(crate) fn take_continue_expected(&mut self) -> bool {
+        mem::take(&mut self.continue_expected)
+    }
 
-                Some(filename)
-            })
-            .ok_or(AppError::Invalid("Invalid content disposition".to_string()))?
-            .to_string();
-        parts.next().ok_or(AppError::Invalid(
-            "Content type missing from form body".to_string(),
-        ))?;
-        let data = parts
-            .next()
-            .ok_or(AppError::Invalid(
-                "file data missing from form body".to_string(),
-            ))?
-            .to_string()
-            .trim()
-            .as_bytes()
-            .to_vec();
+    /// Checks if so much of the body is queued that no more of it should be read until a worker
+    /// has written some of it
+    pub(crate) fn is_body_backlogged(&self) -> bool {
+        self.body.is_backlogged()
+    }
 
-        Ok(BufferedFile {
-            name: filename,
-            content: data,
-        })
+    /// Gets a `BodyDrain` for the chunks of the body waiting to be written, unless there are none,
+    /// or a worker is already writing them
+    pub(crate) fn take_body_drain(&self) -> Option<BodyDrain> {
+        self.body.start_drain()
     }
-}
 
-impl Display for Request {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let mut headers_string = String::new();
-        for (key, value) in &self.headers {
-            headers_string.push_str(&format!("{}: {}\r\n", key, value));
+    /// Turns a complete request into a `Request`, extracting its body
+    ///
+    /// Any trailer fields sent after a chunked body are added to the headers, unless they are
+    /// already there. The chunks of the body still queued are written into the `RequestBodySink`
+    /// first, and a body collected in the `RequestBodySpool` is read again to be extracted, so this
+    /// is meant to be done by a worker rather than the event loop.
+    pub(crate) fn into_request(mut self) -> Result<Request, AppError> {
+        let Some(RequestHead {
+            method,
+            path,
+            http_version,
+            mut headers,
+            framing,
+        }) = self.head.take()
+        else {
+            return Err(AppError::Unknown(
+                "Request was handled before its head arrived".to_string(),
+            ));
+        };
+
+        if let BodyFraming::Chunked(decoder) = &framing {
+            for (key, value) in decoder.trailers.iter() {
+                if !headers.contains(key) {
+                    headers.append(key, value);
+                }
+            }
         }
 
-        write!(
-            f,
-            "{} {} {}\r\n{}",
-            self.method, self.path, self.http_version, headers_string
-        )
+        let body = match self.body.finish()? {
+            RequestBodySink::Spool(mut spool) => {
+                Request::extract_body(spool.reader()?, headers.content_type())?
+            }
+            RequestBodySink::Multipart(parser) => RequestBody::Multipart(parser.finish()?),
+        };
+
+        Ok(Request {
+            path,
+            method,
+            http_version,
+            headers,
+            body,
+        })
     }
 }
 
-/// A `HttpStatus` is an abstraction of an HTTP status code and a reason phrase
-#[derive(Debug)]
-pub(crate) enum HttpStatus {
-    Ok,
-    SeeOther,
-    Forbidden,
-    NotFound,
-    ServerError,
+/// The request line and headers of a request, parsed once all of them have arrived
+struct RequestHead {
+    method: HttpMethod,
+    path: Url,
+    http_version: String,
+    headers: Headers,
+    framing: BodyFraming,
 }
 
-impl HttpStatus {
-    /// Gets the status code used in an HTTP response from a `HttpStatus`
-    fn get_status_code(&self) -> u16 {
-        match self {
-            HttpStatus::Ok => 200,
-            HttpStatus::SeeOther => 303,
-            HttpStatus::Forbidden => 403,
-            HttpStatus::NotFound => 404,
-            HttpStatus::ServerError => 500,
-        }
+impl RequestHead {
+    /// Parses the head of a request
+    ///
+    /// Arguments:
+    /// - **head**: The bytes of the head, up to and including the empty line ending it
+    ///
+    /// The first line is read into a string, and the request line is extracted from that.
+    /// It is then used to extract the headers from the next couple of lines, which tell how the body
+    /// is framed.
+    fn parse(mut head: &[u8]) -> Result<RequestHead, AppError> {
+        let mut line = String::new();
+        head.read_line(&mut line)
+            .map_err(|_| AppError::Invalid("Error reading request line".to_string()))?;
+        let (method, path, http_version) = Request::extract_request_line(line)?;
+        let headers = Request::extract_headers(&mut head)?;
+        let framing = BodyFraming::from_headers(&headers)?;
+
+        Ok(RequestHead {
+            method,
+            path,
+            http_version,
+            headers,
+            framing,
+        })
     }
 
-    /// Gets the reason phrase used in an HTTP response from a `HttpStatus`
-    fn get_reason_phrase(&self) -> String {
-        match self {
-            HttpStatus::Ok => "OK".to_string(),
-            HttpStatus::SeeOther => "SEE OTHER".to_string(),
-            HttpStatus::Forbidden => "FORBIDDEN".to_string(),
-            HttpStatus::NotFound => "NOT FOUND".to_string(),
-            HttpStatus::ServerError => "SERVER ERROR".to_string(),
-        }
+    /// Checks if the client sent `Expect: 100-continue`, and a body it would be waiting to send
+    fn expects_continue(&self) -> bool {
+        self.http_version != "HTTP/1.0"
+            && !matches!(self.framing, BodyFraming::Length(0))
+            && self
+                .headers
+                .get(HttpHeader::EXPECT)
+                .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
     }
 }
 
-/// Contains constants for HTTP headers
-pub(crate) struct HttpHeader;
+/// How the body of a request is framed, which is what tells where it ends
+/// - **Length**: The body is the number of bytes in the *Content-Length* header, or empty without
+///   it, holding the number of bytes still to arrive
+/// - **Chunked**: The body is sent with `Transfer-Encoding: chunked`, and ends after its last chunk
+enum BodyFraming {
+    Length(u64),
+    Chunked(ChunkedDecoder),
+}
+
+impl BodyFraming {
+    /// Gets the framing of a body from the headers of its request
+    ///
+    /// Requests with both headers are rejected, as they could be read differently by a proxy in
+    /// front of the server, and so are bodies with a *Content-Length* larger than the allowed size.
+    fn from_headers(headers: &Headers) -> Result<BodyFraming, AppError> {
+        if let Some(transfer_encoding) = headers.get_combined(HttpHeader::TRANSFER_ENCODING) {
+            if headers.contains(HttpHeader::CONTENT_LENGTH) {
+                return Err(AppError::Invalid(format!(
+                    "Request has both {} and {} headers",
+                    HttpHeader::TRANSFER_ENCODING,
+                    HttpHeader::CONTENT_LENGTH
+                )));
+            }
+            let mut codings = headers.get_list(HttpHeader::TRANSFER_ENCODING);
+            if !codings
+                .next()
+                .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+                || codings.next().is_some()
+            {
+                return Err(AppError::Invalid(format!(
+                    "Unsupported transfer encoding: {transfer_encoding}"
+                )));
+            }
+            return Ok(BodyFraming::Chunked(ChunkedDecoder::new()));
+        }
+
+        let content_length = headers.content_length()?.unwrap_or(0);
+
+        let max_body_size = Config::get().max_body_size;
+        if content_length > max_body_size {
+            return Err(AppError::Invalid(format!(
+                "Request body exceeds the limit of {max_body_size} bytes"
+            )));
+        }
+
+        Ok(BodyFraming::Length(content_length))
+    }
+}
+
+/// Where a `ChunkedDecoder` is in a chunked body
+#[derive(Clone, Copy)]
+enum ChunkState {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked` while it arrives, so that what is done
+/// with the body doesn't have to know how it was framed
+///
+/// Each chunk is a line with the chunk size in hexadecimal, followed by the chunk data and a CRLF.
+/// A chunk of size 0 ends the body, and can be followed by trailer fields, which are kept in
+/// `trailers` unless they are in `FORBIDDEN_TRAILERS`, and an empty line.  
+/// It keeps just enough state to continue where the previous bytes ended, so the body can arrive
+/// in pieces of any size.
+struct ChunkedDecoder {
+    state: ChunkState,
+    line: Vec<u8>,
+    total_size: u64,
+    trailers: Headers,
+}
+
+impl ChunkedDecoder {
+    /// The longest chunk size or trailer line allowed, to avoid reading unbounded lines into memory
+    const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+    /// Trailer fields that are never merged into the headers, as they decide how a request is read
+    /// and routed, so they must come before the body
+    const FORBIDDEN_TRAILERS: [&'static str; 6] = [
+        HttpHeader::CONNECTION,
+        HttpHeader::CONTENT_LENGTH,
+        HttpHeader::CONTENT_TYPE,
+        HttpHeader::HOST,
+        HttpHeader::TRAILER,
+        HttpHeader::TRANSFER_ENCODING,
+    ];
+
+    fn new() -> Self {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+            line: Vec::new(),
+            total_size: 0,
+            trailers: Headers::new(),
+        }
+    }
+
+    /// Decodes the next bytes of the body
+    ///
+    /// Arguments:
+    /// - **bytes**: The bytes that arrived, which can go past the end of the body
+    /// - **data**: Takes the chunk data found in the bytes, in the order it was sent
+    ///
+    /// Returns how many of the bytes belong to the body, and whether it ended.
+    fn decode<F>(&mut self, bytes: &[u8], mut data: F) -> Result<(usize, bool), AppError>
+    where
+        F: FnMut(&[u8]) -> Result<(), AppError>,
+    {
+        let mut position = 0;
+
+        while position < bytes.len() {
+            match self.state {
+                ChunkState::Done => break,
+                ChunkState::Data(remaining) => {
+                    let length = std::cmp::min(remaining, (bytes.len() - position) as u64);
+                    data(&bytes[position..position + length as usize])?;
+                    position += length as usize;
+                    self.state = if length == remaining {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - length)
+                    };
+                }
+                ChunkState::Size | ChunkState::DataEnd | ChunkState::Trailer => {
+                    self.line.push(bytes[position]);
+                    position += 1;
+                    if self.line.len() as u64 > Self::MAX_LINE_LENGTH {
+                        return Err(AppError::Invalid("Chunk line is too long".to_string()));
+                    }
+                    if let Some(line) = self.line.strip_suffix(b"\r\n") {
+                        let line = line.to_vec();
+                        self.line.clear();
+                        self.end_line(&line)?;
+                    }
+                }
+            }
+        }
+
+        Ok((position, matches!(self.state, ChunkState::Done)))
+    }
+
+    /// Moves to the next state once a whole line of the body has been decoded
+    ///
+    /// Chunk extensions after a `;` are ignored.
+    fn end_line(&mut self, line: &[u8]) -> Result<(), AppError> {
+        let invalid = |message: &str| AppError::Invalid(message.to_string());
+
+        self.state = match self.state {
+            ChunkState::Size => {
+                let size = str::from_utf8(line)
+                    .ok()
+                    .and_then(|line| line.split(';').next())
+                    .map(str::trim)
+                    .and_then(|size| u64::from_str_radix(size, 16).ok())
+                    .ok_or(invalid("Invalid chunk size"))?;
+
+                self.total_size = self.total_size.saturating_add(size);
+                let max_body_size = Config::get().max_body_size;
+                if self.total_size > max_body_size {
+                    return Err(AppError::Invalid(format!(
+                        "Request body exceeds the limit of {max_body_size} bytes"
+                    )));
+                }
+
+                if size == 0 {
+                    ChunkState::Trailer
+                } else {
+                    ChunkState::Data(size)
+                }
+            }
+            ChunkState::DataEnd if line.is_empty() => ChunkState::Size,
+            ChunkState::DataEnd => return Err(invalid("Chunk data is longer than its size")),
+            ChunkState::Trailer if line.is_empty() => ChunkState::Done,
+            ChunkState::Trailer => {
+                let (key, value) = str::from_utf8(line)
+                    .ok()
+                    .and_then(|line| line.split_once(':'))
+                    .ok_or(invalid("Invalid trailer field"))?;
+                let key = key.trim();
+                if !Self::FORBIDDEN_TRAILERS
+                    .iter()
+                    .any(|forbidden| forbidden.eq_ignore_ascii_case(key))
+                {
+                    self.trailers.append(key, value.trim());
+                }
+                ChunkState::Trailer
+            }
+            state => state,
+        };
+        Ok(())
+    }
+}
+
+/// The chunks of a request body that arrived, waiting to be written into its `RequestBodySink`.
+///
+/// Writing to the sink may mean writing to disk, so it is only done by workers: the first chunk
+/// queued while no worker is draining the queue gets a `BodyDrain` handed to the thread pool, and
+/// the worker running it writes chunks until the queue is empty. The sink stays locked while it
+/// does, and chunks are only taken off the queue with it locked, so that they are written in the
+/// order they arrived, even when the worker handling the request writes the last of them.  
+/// A connection isn't read from while more than `MAX_QUEUED` bytes are waiting, which bounds the
+/// memory a client can fill faster than the disk takes it.
+struct BodyQueue {
+    chunks: Mutex<QueuedChunks>,
+    /// Where the chunks are written, or the first error writing them, after which they are dropped
+    sink: Mutex<Result<RequestBodySink, AppError>>,
+}
+
+/// The chunks waiting in a `BodyQueue`
+#[derive(Default)]
+struct QueuedChunks {
+    chunks: VecDeque<Vec<u8>>,
+    /// The number of bytes in the chunks, including the ones being written
+    size: usize,
+    /// Whether a `BodyDrain` was handed out that didn't finish yet
+    draining: bool,
+}
+
+impl Default for BodyQueue {
+    fn default() -> Self {
+        BodyQueue::new(RequestBodySink::default())
+    }
+}
+
+impl BodyQueue {
+    /// The size of the chunks a body is queued in
+    const CHUNK_SIZE: usize = 64 * 1024;
+    /// The most bytes queued before the connection stops being read
+    const MAX_QUEUED: usize = 1024 * 1024;
+
+    /// Creates a new, empty `BodyQueue`
+    ///
+    /// Arguments:
+    /// - **sink**: The `RequestBodySink` the chunks are written into
+    fn new(sink: RequestBodySink) -> Self {
+        BodyQueue {
+            chunks: Mutex::new(QueuedChunks::default()),
+            sink: Mutex::new(Ok(sink)),
+        }
+    }
+
+    /// Locks the chunks, which is never held while anything can panic, so a poisoned lock is
+    /// recovered
+    fn chunks(&self) -> MutexGuard<'_, QueuedChunks> {
+        self.chunks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds a chunk of the body to the end of the queue
+    fn push(&self, chunk: Vec<u8>) {
+        if chunk.is_empty() {
+            return;
+        }
+        let mut chunks = self.chunks();
+        chunks.size += chunk.len();
+        chunks.chunks.push_back(chunk);
+    }
+
+    /// Checks if at least `MAX_QUEUED` bytes are waiting to be written
+    fn is_backlogged(&self) -> bool {
+        self.chunks().size >= Self::MAX_QUEUED
+    }
+
+    /// Hands out a `BodyDrain` if chunks are waiting, and no other one is draining them
+    fn start_drain(self: &Arc<Self>) -> Option<BodyDrain> {
+        let mut chunks = self.chunks();
+        if chunks.draining || chunks.chunks.is_empty() {
+            return None;
+        }
+        chunks.draining = true;
+        Some(BodyDrain(Some(Arc::clone(self))))
+    }
+
+    /// Writes the queued chunks into the sink until the queue is empty
+    ///
+    /// Arguments:
+    /// - **sink**: The locked sink
+    /// - **end_drain**: Whether a `BodyDrain` is writing the chunks, which is marked as finished
+    ///   once it finds the queue empty, with the queue still locked, so that a chunk queued right
+    ///   after gets a `BodyDrain` of its own
+    fn write_into(&self, sink: &mut Result<RequestBodySink, AppError>, end_drain: bool) {
+        loop {
+            let chunk = {
+                let mut chunks = self.chunks();
+                match chunks.chunks.pop_front() {
+                    Some(chunk) => chunk,
+                    None => {
+                        if end_drain {
+                            chunks.draining = false;
+                        }
+                        return;
+                    }
+                }
+            };
+
+            if let Ok(body_sink) = sink
+                && let Err(app_error) = body_sink.write(&chunk)
+            {
+                *sink = Err(app_error);
+            }
+            self.chunks().size -= chunk.len();
+        }
+    }
+
+    /// Writes the chunks still queued into the sink, once the whole body arrived, and takes the sink
+    ///
+    /// A `BodyDrain` still running is waited on, as it holds the sink.  
+    /// Returns the first error writing any chunk of the body, if there was one.
+    fn finish(&self) -> Result<RequestBodySink, AppError> {
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        self.write_into(&mut sink, false);
+        mem::replace(&mut *sink, Ok(RequestBodySink::default()))
+    }
+}
+
+/// A `BodyDrain` is the task of writing the chunks queued in a `BodyQueue` into its sink, which is
+/// run by a worker.  
+/// Only one exists for a queue at a time. One dropped without finishing, such as when the job queue
+/// of the workers is full, or writing a chunk panicked, lets the next one be handed out.
+pub(crate) struct BodyDrain(Option<Arc<BodyQueue>>);
+
+impl BodyDrain {
+    /// Writes every chunk queued, until the queue is empty
+    pub(crate) fn run(mut self) {
+        if let Some(queue) = &self.0 {
+            let mut sink = queue.sink.lock().unwrap_or_else(PoisonError::into_inner);
+            queue.write_into(&mut sink, true);
+        }
+        // The queue was marked as no longer draining when it was found empty
+        self.0 = None;
+    }
+}
+
+impl Drop for BodyDrain {
+    fn drop(&mut self) {
+        if let Some(queue) = &self.0 {
+            queue.chunks().draining = false;
+        }
+    }
+}
+
+/// Where the body of a request goes while it arrives
+/// - **Spool**: The body, held until it is complete and extracted by a worker
+/// - **Multipart**: A `multipart/form-data` form, parsed while it arrives, so that the files in it
+///   are written once, straight into the `TempFile` each of them is saved from
+enum RequestBodySink {
+    Spool(RequestBodySpool),
+    Multipart(Box<MultipartParser>),
+}
+
+impl Default for RequestBodySink {
+    fn default() -> Self {
+        RequestBodySink::Spool(RequestBodySpool::default())
+    }
+}
+
+impl RequestBodySink {
+    /// Gets where the body of a request goes, from its *Content-Type* header value
+    fn for_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.starts_with("multipart/form-data") => {
+                RequestBodySink::Multipart(Box::new(MultipartParser::new(content_type)))
+            }
+            _ => RequestBodySink::default(),
+        }
+    }
+
+    /// Adds the next bytes of the body
+    fn write(&mut self, bytes: &[u8]) -> Result<(), AppError> {
+        match self {
+            RequestBodySink::Spool(spool) => spool.write(bytes),
+            RequestBodySink::Multipart(parser) => {
+                parser.write(bytes);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Holds the body of a request while it arrives, in memory while it is small, and in a `TempFile`
+/// in the uploads directory once it isn't, so that a large upload is never held in memory
+#[derive(Default)]
+struct RequestBodySpool {
+    memory: Vec<u8>,
+    file: Option<TempFile>,
+}
+
+impl RequestBodySpool {
+    /// The largest body held in memory
+    const MEMORY_LIMIT: usize = 64 * 1024;
+
+    /// Adds the next bytes of the body
+    fn write(&mut self, bytes: &[u8]) -> Result<(), AppError> {
+        if self.file.is_none() && self.memory.len() + bytes.len() <= Self::MEMORY_LIMIT {
+            self.memory.extend_from_slice(bytes);
+            return Ok(());
+        }
+
+        let write_error = |e: io::Error| AppError::IO(format!("Failed to write request body: {e}"));
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let mut file = TempFile::create(&Config::get().uploads_dir)?;
+                file.write_all(&mem::take(&mut self.memory))
+                    .map_err(write_error)?;
+                self.file.insert(file)
+            }
+        };
+        file.write_all(bytes).map_err(write_error)
+    }
+
+    /// Gets a reader of the whole body, from its start
+    fn reader(&mut self) -> Result<Box<dyn Read + '_>, AppError> {
+        match &mut self.file {
+            Some(file) => Ok(Box::new(file.open_reader()?)),
+            None => Ok(Box::new(self.memory.as_slice())),
+        }
+    }
+}
+
+/// A writer that collects bytes in memory, failing with `InvalidData` once more bytes than its
+/// limit are written to it
+struct LimitedBuffer {
+    buffer: Vec<u8>,
+    limit: usize,
+}
+
+impl LimitedBuffer {
+    fn new(limit: usize) -> Self {
+        LimitedBuffer {
+            buffer: Vec::new(),
+            limit,
+        }
+    }
+}
+
+impl Write for LimitedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Form field exceeds {} bytes limit", self.limit),
+            ));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The headers of a single part in a multipart body
+#[derive(Debug, Default, PartialEq)]
+struct PartHeaders {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl PartHeaders {
+    /// Adds a header line of the part, of which only *Content-Disposition* and *Content-Type* are kept
+    fn add_line(&mut self, line: &str) -> Result<(), AppError> {
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(AppError::Invalid(format!(
+                "Invalid header in form body: {line}"
+            )));
+        };
+        let value = value.trim();
+        if key
+            .trim()
+            .eq_ignore_ascii_case(HttpHeader::CONTENT_DISPOSITION)
+        {
+            for (parameter, parameter_value) in MultipartParser::parse_parameters(value) {
+                match parameter.to_ascii_lowercase().as_str() {
+                    "name" => self.name = Some(parameter_value),
+                    "filename" => self.filename = Some(parameter_value),
+                    _ => {}
+                }
+            }
+        } else if key.trim().eq_ignore_ascii_case(HttpHeader::CONTENT_TYPE) {
+            self.content_type = Some(value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Where the body of a part of a multipart form goes
+/// - **File**: A file, streamed into the `TempFile` of its `FormFile`
+/// - **Field**: A regular text field, with its name, collected up to `MAX_FORM_FIELD_SIZE`
+/// - **Skipped**: Bytes nobody needs, like the preamble or an empty file input
+enum PartSink {
+    File(FormFile),
+    Field(String, LimitedBuffer),
+    Skipped,
+}
+
+impl PartSink {
+    /// Gets where the body of a part goes from its headers
+    ///
+    /// Parts with a file name are files, and every other part is a regular field. Empty file inputs,
+    /// which browsers send as a part with an empty file name, are skipped.
+    fn new(part_headers: PartHeaders) -> Result<PartSink, AppError> {
+        let field_name = part_headers
+            .name
+            .ok_or(AppError::Invalid("Invalid content disposition".to_string()))?;
+
+        match part_headers.filename {
+            Some(filename) if filename.is_empty() => Ok(PartSink::Skipped),
+            Some(filename) => Ok(PartSink::File(FormFile {
+                field_name,
+                filename,
+                content_type: part_headers.content_type,
+                file: TempFile::create(&Config::get().uploads_dir)?,
+            })),
+            None => Ok(PartSink::Field(
+                field_name,
+                LimitedBuffer::new(MAX_FORM_FIELD_SIZE),
+            )),
+        }
+    }
+
+    /// Writes the next bytes of the body of the part
+    fn write(&mut self, bytes: &[u8]) -> Result<(), AppError> {
+        let result = match self {
+            PartSink::File(form_file) => form_file.file.write_all(bytes),
+            PartSink::Field(_, content) => content.write_all(bytes),
+            PartSink::Skipped => Ok(()),
+        };
+        result.map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => AppError::Invalid(e.to_string()),
+            _ => AppError::IO(format!("Failed to write form data: {e}")),
+        })
+    }
+
+    /// Adds the part to the form once all of its body was written
+    fn finish(self, form: &mut MultipartForm) -> Result<(), AppError> {
+        match self {
+            PartSink::File(form_file) => form.files.push(form_file),
+            PartSink::Field(name, content) => {
+                let value = String::from_utf8(content.buffer).map_err(|_| {
+                    AppError::Invalid(format!("Form field is not valid UTF-8: {name}"))
+                })?;
+                form.fields.push(FormField { name, value });
+            }
+            PartSink::Skipped => {}
+        }
+        Ok(())
+    }
+}
+
+/// Where a `MultipartParser` is in a multipart body
+/// - **Body**: Writing the body of a part, or skipping the preamble, until the next delimiter
+/// - **Delimiter**: Reading the rest of a delimiter line, which starts with `--` after the last part
+/// - **Headers**: Reading the headers of a part, until an empty line
+/// - **Done**: Discarding the epilogue after the closing delimiter
+/// - **Failed**: Discarding the rest of a body that can't be parsed
+enum MultipartState {
+    Body(PartSink),
+    Delimiter,
+    Headers(PartHeaders),
+    Done,
+    Failed(AppError),
+}
+
+/// Parses a `multipart/form-data` body while it arrives, working on raw bytes so that binary
+/// content is never altered
+///
+/// The body can arrive in pieces of any size, so it holds a small buffer of its own: a delimiter
+/// can be split between two pieces, and bytes that could be the start of one can't be written out
+/// until the next piece decides whether they are.  
+/// Every file is streamed into its own `TempFile` in the uploads directory as it arrives, and saving
+/// the upload is then a rename of that file, so memory use stays the same no matter how large the
+/// upload is, and it is only ever written to disk once.  
+/// An error doesn't stop the rest of the body from being taken, so that the request is answered
+/// once all of it has arrived, like any other. The error is returned by `finish()`.
+struct MultipartParser {
+    buffer: Vec<u8>,
+    delimiter: Vec<u8>,
+    state: MultipartState,
+    form: MultipartForm,
+}
+
+impl MultipartParser {
+    /// The longest line allowed in the part headers, to avoid reading unbounded lines into memory
+    const MAX_HEADER_LINE_LENGTH: usize = 8 * 1024;
+
+    /// Creates a new `MultipartParser`
+    ///
+    /// Arguments:
+    /// - **content_type**: *Content-Type* header value, with the boundary of the body
+    ///
+    /// Every delimiter in the body is a CRLF followed by `--` and the boundary. The buffer starts
+    /// with a CRLF, so that the first delimiter, which has nothing before it, is found the same way.
+    fn new(content_type: &str) -> Self {
+        let (delimiter, state) = match Self::get_boundary(content_type) {
+            Ok(boundary) => (
+                format!("\r\n--{boundary}").into_bytes(),
+                MultipartState::Body(PartSink::Skipped),
+            ),
+            Err(app_error) => (Vec::new(), MultipartState::Failed(app_error)),
+        };
+
+        MultipartParser {
+            buffer: b"\r\n".to_vec(),
+            delimiter,
+            state,
+            form: MultipartForm::default(),
+        }
+    }
+
+    /// Gets the boundary from a *Content-Type* header value
+    ///
+    /// Arguments:
+    /// - **content_type**: *Content-Type* header value
+    ///
+    /// The header value is split into its parameters and the `boundary` parameter is returned, with
+    /// any quotes around it removed. An error is returned if there is no boundary.
+    fn get_boundary(content_type: &str) -> Result<String, AppError> {
+        content_type
+            .split(';')
+            .filter_map(|parameter| parameter.trim().split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, boundary)| boundary.trim().trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+            .ok_or(AppError::Invalid(
+                "Boundary missing in Content-Type header".to_string(),
+            ))
+    }
+
+    /// Parses the next bytes of the body
+    ///
+    /// Once parsing fails, the file being written is removed, and the rest of the body is discarded.
+    fn write(&mut self, bytes: &[u8]) {
+        if matches!(self.state, MultipartState::Done | MultipartState::Failed(_)) {
+            return;
+        }
+
+        self.buffer.extend_from_slice(bytes);
+        if let Err(app_error) = self.parse() {
+            self.buffer.clear();
+            self.state = MultipartState::Failed(app_error);
+        }
+    }
+
+    /// Parses as much of the buffer as can be, leaving what can't be parsed until more arrives
+    ///
+    /// The body of a part is searched for the delimiter, and if it is found, everything before it is
+    /// written out and the part is added to the form. Otherwise, everything except the last few
+    /// bytes, which could be the start of a delimiter, is written out.  
+    /// After a delimiter, the rest of its line is read, a `--` marking the end of the body, and the
+    /// part headers are then read until an empty line.
+    fn parse(&mut self) -> Result<(), AppError> {
+        loop {
+            match &mut self.state {
+                MultipartState::Body(part_sink) => {
+                    let Some(position) = self
+                        .buffer
+                        .windows(self.delimiter.len())
+                        .position(|window| window == self.delimiter)
+                    else {
+                        let safe_length =
+                            self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                        part_sink.write(&self.buffer[..safe_length])?;
+                        self.buffer.drain(..safe_length);
+                        return Ok(());
+                    };
+
+                    part_sink.write(&self.buffer[..position])?;
+                    self.buffer.drain(..position + self.delimiter.len());
+                    if let MultipartState::Body(part_sink) =
+                        mem::replace(&mut self.state, MultipartState::Delimiter)
+                    {
+                        part_sink.finish(&mut self.form)?;
+                    }
+                }
+                MultipartState::Delimiter => {
+                    // The closing delimiter may be the very end of the body, without a line ending
+                    if self.buffer.starts_with(b"--") {
+                        self.state = MultipartState::Done;
+                        continue;
+                    }
+                    let Some(line) = Self::take_line(&mut self.buffer)? else {
+                        return Ok(());
+                    };
+                    if !line.trim().is_empty() {
+                        return Err(AppError::Invalid(
+                            "Form body not surrounded with boundary".to_string(),
+                        ));
+                    }
+                    self.state = MultipartState::Headers(PartHeaders::default());
+                }
+                MultipartState::Headers(part_headers) => {
+                    let Some(line) = Self::take_line(&mut self.buffer)? else {
+                        return Ok(());
+                    };
+                    if !line.is_empty() {
+                        part_headers.add_line(&line)?;
+                        continue;
+                    }
+                    let part_sink = PartSink::new(mem::take(part_headers))?;
+                    self.state = MultipartState::Body(part_sink);
+                }
+                MultipartState::Done | MultipartState::Failed(_) => {
+                    self.buffer.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Takes a single CRLF terminated line from the start of a buffer, without the line ending, or
+    /// `None` if the whole line hasn't arrived yet
+    ///
+    /// An error is returned if the line is longer than `MAX_HEADER_LINE_LENGTH` or isn't valid UTF-8.
+    fn take_line(buffer: &mut Vec<u8>) -> Result<Option<String>, AppError> {
+        let Some(line_end) = buffer.windows(2).position(|window| window == b"\r\n") else {
+            if buffer.len() > Self::MAX_HEADER_LINE_LENGTH {
+                return Err(AppError::Invalid(
+                    "Header line in form body is too long".to_string(),
+                ));
+            }
+            return Ok(None);
+        };
+
+        let line = String::from_utf8(buffer[..line_end].to_vec())
+            .map_err(|_| AppError::Invalid("Failed to parse form data".to_string()))?;
+        buffer.drain(..line_end + 2);
+
+        Ok(Some(line))
+    }
+
+    /// Gets the form once all of the body has arrived
+    ///
+    /// An error is returned if parsing failed, or the body ended before its closing delimiter.
+    fn finish(self) -> Result<MultipartForm, AppError> {
+        match self.state {
+            MultipartState::Done => Ok(self.form),
+            MultipartState::Failed(app_error) => Err(app_error),
+            _ => Err(AppError::Invalid(
+                "Form body not surrounded with boundary".to_string(),
+            )),
+        }
+    }
+
+    /// Parses the parameters of a header value such as `form-data; name="file"; filename="a.txt"`
+    ///
+    /// Arguments:
+    /// - **value**: The header value to parse
+    ///
+    /// Quoted values may contain semicolons, so the value is walked through character by character
+    /// rather than split. Browsers percent-encode any quotes inside a quoted value, so a quote
+    /// always ends it.
+    fn parse_parameters(value: &str) -> Vec<(String, String)> {
+        let mut parameters = Vec::new();
+        let mut chars = value.chars().peekable();
+
+        // Skip the disposition type, such as `form-data`
+        for c in chars.by_ref() {
+            if c == ';' {
+                break;
+            }
+        }
+
+        while chars.peek().is_some() {
+            let mut key = String::new();
+            let mut parameter_value = String::new();
+            let mut has_value = false;
+
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+                if c == '=' {
+                    has_value = true;
+                    break;
+                }
+                key.push(c);
+            }
+
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+            if has_value && chars.peek() == Some(&'"') {
+                chars.next();
+                parameter_value = chars.by_ref().take_while(|c| *c != '"').collect();
+                // Skip anything left before the next parameter
+                for c in chars.by_ref() {
+                    if c == ';' {
+                        break;
+                    }
+                }
+            } else if has_value {
+                parameter_value = chars.by_ref().take_while(|c| *c != ';').collect();
+            }
+
+            let key = key.trim();
+            if !key.is_empty() {
+                parameters.push((key.to_string(), parameter_value.trim().to_string()));
+            }
+        }
+
+        parameters
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut headers_string = String::new();
+        for (key, value) in self.headers.iter() {
+            headers_string.push_str(&format!("{}: {}\r\n", key, value));
+        }
+
+        write!(
+            f,
+            "{} {} {}\r\n{}",
+            self.method, self.path, self.http_version, headers_string
+        )
+    }
+}
+
+/// A `HttpStatus` is an abstraction of an HTTP status code and a reason phrase
+#[derive(Debug)]
+pub(crate) enum HttpStatus {
+    Ok,
+    Created,
+    NoContent,
+    PartialContent,
+    SeeOther,
+    NotModified,
+    BadRequest,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    PreconditionFailed,
+    UriTooLong,
+    RangeNotSatisfiable,
+    HeaderTooLarge,
+    ServerError,
+    ServiceUnavailable,
+}
+
+impl HttpStatus {
+    /// Gets the status code used in an HTTP response from a `HttpStatus`
+    fn get_status_code(&self) -> u16 {
+        match self {
+            HttpStatus::Ok => 200,
+            HttpStatus::Created => 201,
+            HttpStatus::NoContent => 204,
+            HttpStatus::PartialContent => 206,
+            HttpStatus::SeeOther => 303,
+            HttpStatus::NotModified => 304,
+            HttpStatus::BadRequest => 400,
+            HttpStatus::Forbidden => 403,
+            HttpStatus::NotFound => 404,
+            HttpStatus::MethodNotAllowed => 405,
+            HttpStatus::RequestTimeout => 408,
+            HttpStatus::Conflict => 409,
+            HttpStatus::PreconditionFailed => 412,
+            HttpStatus::UriTooLong => 414,
+            HttpStatus::RangeNotSatisfiable => 416,
+            HttpStatus::HeaderTooLarge => 431,
+            HttpStatus::ServerError => 500,
+            HttpStatus::ServiceUnavailable => 503,
+        }
+    }
+
+    /// Gets the reason phrase used in an HTTP response from a `HttpStatus`
+    fn get_reason_phrase(&self) -> String {
+        match self {
+            HttpStatus::Ok => "OK".to_string(),
+            HttpStatus::Created => "CREATED".to_string(),
+            HttpStatus::NoContent => "NO CONTENT".to_string(),
+            HttpStatus::PartialContent => "PARTIAL CONTENT".to_string(),
+            HttpStatus::SeeOther => "SEE OTHER".to_string(),
+            HttpStatus::NotModified => "NOT MODIFIED".to_string(),
+            HttpStatus::BadRequest => "BAD REQUEST".to_string(),
+            HttpStatus::Forbidden => "FORBIDDEN".to_string(),
+            HttpStatus::NotFound => "NOT FOUND".to_string(),
+            HttpStatus::MethodNotAllowed => "METHOD NOT ALLOWED".to_string(),
+            HttpStatus::RequestTimeout => "REQUEST TIMEOUT".to_string(),
+            HttpStatus::Conflict => "CONFLICT".to_string(),
+            HttpStatus::PreconditionFailed => "PRECONDITION FAILED".to_string(),
+            HttpStatus::UriTooLong => "URI TOO LONG".to_string(),
+            HttpStatus::RangeNotSatisfiable => "RANGE NOT SATISFIABLE".to_string(),
+            HttpStatus::HeaderTooLarge => "REQUEST HEADER FIELDS TOO LARGE".to_string(),
+            HttpStatus::ServerError => "SERVER ERROR".to_string(),
+            HttpStatus::ServiceUnavailable => "SERVICE UNAVAILABLE".to_string(),
+        }
+    }
+}
+
+/// Contains constants for HTTP headers
+pub(crate) struct HttpHeader;
 
 impl HttpHeader {
+    pub(crate) const ACCEPT_RANGES: &'static str = "Accept-Ranges";
+    pub(crate) const ALLOW: &'static str = "Allow";
+    pub(crate) const CONNECTION: &'static str = "Connection";
     pub(crate) const CONTENT_LENGTH: &'static str = "Content-Length";
+    pub(crate) const CONTENT_RANGE: &'static str = "Content-Range";
     pub(crate) const CONTENT_TYPE: &'static str = "Content-Type";
     pub(crate) const CONTENT_DISPOSITION: &'static str = "Content-Disposition";
+    pub(crate) const DESTINATION: &'static str = "Destination";
+    pub(crate) const ETAG: &'static str = "ETag";
+    pub(crate) const EXPECT: &'static str = "Expect";
+    pub(crate) const HOST: &'static str = "Host";
+    pub(crate) const IF_MATCH: &'static str = "If-Match";
+    pub(crate) const IF_MODIFIED_SINCE: &'static str = "If-Modified-Since";
+    pub(crate) const IF_NONE_MATCH: &'static str = "If-None-Match";
+    pub(crate) const IF_RANGE: &'static str = "If-Range";
+    pub(crate) const IF_UNMODIFIED_SINCE: &'static str = "If-Unmodified-Since";
+    pub(crate) const LAST_MODIFIED: &'static str = "Last-Modified";
     pub(crate) const LOCATION: &'static str = "Location";
+    pub(crate) const OVERWRITE: &'static str = "Overwrite";
+    pub(crate) const RANGE: &'static str = "Range";
+    pub(crate) const RETRY_AFTER: &'static str = "Retry-After";
+    pub(crate) const TRAILER: &'static str = "Trailer";
+    pub(crate) const TRANSFER_ENCODING: &'static str = "Transfer-Encoding";
+}
+
+/// `Headers` are the header fields of a request or a response, in the order they were sent or set.
+/// Names are compared case-insensitively, and a name can have several values, from a field that
+/// was sent more than once, or added more than once, like *Set-Cookie*, which can't be combined into
+/// a single field.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Creates empty `Headers`
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the first value of a header
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header, in any case
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Gets every value of a header, in the order they were sent or added
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header, in any case
+    pub(crate) fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.fields
+            .iter()
+            .filter(move |(field_name, _)| field_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Gets the items of a header whose value is a comma-separated list, like *Connection*, across
+    /// every field of it
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header, in any case
+    ///
+    /// Empty items are skipped. It must not be used for headers whose values can have commas in them,
+    /// like dates or *Set-Cookie*.
+    pub(crate) fn get_list<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+    }
+
+    /// Gets every value of a list header joined into one, as if it was sent in a single field
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header, in any case
+    pub(crate) fn get_combined(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.get_all(name).collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    /// Checks if a header is present, even with an empty value
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.get_all(name).next().is_some()
+    }
+
+    /// Checks if a comma-separated list header has an item, compared case-insensitively, like
+    /// `close` in *Connection*
+    pub(crate) fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name)
+            .any(|item| item.eq_ignore_ascii_case(token))
+    }
+
+    /// Sets a header, replacing every value it had, where its first value was
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header
+    /// - **value**: The new value of the header
+    pub(crate) fn set(&mut self, name: &str, value: &str) {
+        let mut value = Some(value.to_string());
+        self.fields.retain_mut(|(field_name, field_value)| {
+            if !field_name.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            match value.take() {
+                Some(value) => {
+                    *field_value = value;
+                    true
+                }
+                None => false,
+            }
+        });
+        if let Some(value) = value {
+            self.fields.push((name.to_string(), value));
+        }
+    }
+
+    /// Adds a value to a header after the ones it already has
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header
+    /// - **value**: The value to add
+    pub(crate) fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Gets every header field, in order, with a header sent more than once appearing as many times
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Gets the *Content-Length* header as a number of bytes, if it is present
+    ///
+    /// The header can be repeated, or hold a list, as some clients and proxies send it that way, as
+    /// long as every value is the same number. Otherwise, an error is returned, as the length of the
+    /// body can't be told.
+    pub(crate) fn content_length(&self) -> Result<Option<u64>, AppError> {
+        let mut content_length = None;
+        for value in self
+            .get_all(HttpHeader::CONTENT_LENGTH)
+            .flat_map(|value| value.split(','))
+        {
+            let length = value.trim().parse::<u64>().map_err(|_| {
+                AppError::Invalid(format!(
+                    "{} header is not a number",
+                    HttpHeader::CONTENT_LENGTH
+                ))
+            })?;
+            if content_length.is_some_and(|previous| previous != length) {
+                return Err(AppError::Invalid(format!(
+                    "{} header has conflicting values",
+                    HttpHeader::CONTENT_LENGTH
+                )));
+            }
+            content_length = Some(length);
+        }
+        Ok(content_length)
+    }
+
+    /// Gets the *Content-Type* header, if it is present
+    pub(crate) fn content_type(&self) -> Option<&str> {
+        self.get(HttpHeader::CONTENT_TYPE)
+    }
+
+    /// Gets a header holding an HTTP date, like *If-Modified-Since*, as a Unix timestamp
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header, in any case
+    ///
+    /// `None` is returned if the header is missing or isn't a valid HTTP date.
+    pub(crate) fn date(&self, name: &str) -> Option<u64> {
+        self.get(name).and_then(Time::get_timestamp_from_http_date)
+    }
 }
 
 /// Holds data to create a `Response` using the builder pattern
 #[derive(Default)]
 pub(crate) struct ResponseBuilder {
     status: Option<HttpStatus>,
-    headers: HashMap<String, String>,
+    headers: Headers,
     body: Option<ResponseBody>,
+    chunked: bool,
 }
 
 impl ResponseBuilder {
@@ -540,14 +2001,17 @@ impl ResponseBuilder {
         self
     }
 
-    /// Updates the headers of the `ResponseBuilder`
+    /// Adds a header to the `ResponseBuilder`
     ///
     /// Arguments:
     /// - **mut self**: A mutable capture of self
-    /// - **name**: The name of a single header to update
+    /// - **name**: The name of a single header to add
     /// - **value**: The value of the header being added
+    ///
+    /// A header added more than once is sent as that many fields, in the order they were added, which
+    /// is how several cookies are set with *Set-Cookie*.
     pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
-        self.headers.insert(name.to_string(), value.to_string());
+        self.headers.append(name, value);
         self
     }
 
@@ -561,23 +2025,36 @@ impl ResponseBuilder {
         self
     }
 
+    /// Makes the `Response` send its body with `Transfer-Encoding: chunked`, for bodies whose size
+    /// isn't known up front
+    ///
+    /// Arguments:
+    /// - **mut self**: A mutable capture of self
+    pub(crate) fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
     /// Builds a `Response` from a `ResponseBuilder`
     /// Sets useful defaults for status and body if not present, and passes the headers alongside
     pub(crate) fn build(self) -> Response {
         let status = self.status.unwrap_or(HttpStatus::Ok);
         let body = self.body.unwrap_or(ResponseBody::Empty);
 
-        Response::new(status, self.headers, body)
+        let mut response = Response::new(status, self.headers, body);
+        response.chunked = self.chunked;
+        response
     }
 }
 
 /// A `Response` is an abstraction of an HTTP response and its contents
-#[derive(Debug)]
 pub(crate) struct Response {
     http_version: String,
     status: HttpStatus,
-    headers: HashMap<String, String>,
+    headers: Headers,
     body: ResponseBody,
+    chunked: bool,
+    head_only: bool,
 }
 
 impl Response {
@@ -590,80 +2067,299 @@ impl Response {
     ///
     /// Arguments:
     /// - **status**: The `HttpStatus` of the `Response`
-    /// - **headers**: A `HashMap` of all HTTP headers of the `Response`
+    /// - **headers**: The `Headers` of the `Response`
     /// - **body**: the body of the `Response`
-    fn new(status: HttpStatus, headers: HashMap<String, String>, body: ResponseBody) -> Self {
+    fn new(status: HttpStatus, headers: Headers, body: ResponseBody) -> Self {
         Response {
             http_version: "HTTP/1.1".to_string(),
             status,
             headers,
             body,
+            chunked: false,
+            head_only: false,
         }
     }
 
-    /// Tries to cast a `Response` into bytes that get written to the TCP stream as a response
+    /// Sets whether the body of the `Response` is sent with `Transfer-Encoding: chunked`
     ///
-    /// Writes each field of the `Response` to a byte buffer.  
-    /// First the status line is written, then an attempt is made to get an `Option<BufferedFile>` for
-    /// cases where the response has or doesn't have a body.  
-    /// Content-Type and Content-Length headers are overridden, depending on whether there is
-    /// a body and how long it is.  
-    /// The headers are written next, then an empty line, then the response body, if any.
-    pub(crate) fn to_bytes(mut self) -> Result<Vec<u8>, AppError> {
-        let mut buffer = Vec::new();
+    /// Arguments:
+    /// - **chunked**: Whether the body is chunked, which must be `false` for HTTP/1.0 clients, as
+    ///   they don't understand chunked bodies
+    pub(crate) fn set_chunked(&mut self, chunked: bool) {
+        self.chunked = chunked;
+    }
 
-        let status_code = self.status.get_status_code();
-        let reason_phrase = self.status.get_reason_phrase();
-        write!(
-            buffer,
-            "{} {} {}\r\n",
-            self.http_version, status_code, reason_phrase
-        )
-        .map_err(|_| AppError::IO("Error writing HTTP response to buffer".to_string()))?;
+    /// Makes the `Response` answer a HEAD request, sending the same headers it would for a GET
+    /// request, including the *Content-Length* of the body, but not the body itself
+    pub(crate) fn set_head_only(&mut self) {
+        self.head_only = true;
+    }
+
+    /// Sets a header on an already built `Response`, replacing any previous value
+    ///
+    /// Arguments:
+    /// - **name**: The name of the header to set
+    /// - **value**: The value of the header
+    pub(crate) fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.set(name, value);
+    }
 
-        let file: Option<BufferedFile> = self.body.try_into()?;
+    /// Checks if the `Response` has a 2xx status, meaning the request succeeded
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.status.get_status_code())
+    }
 
-        let body_buffer = match file {
-            Some(file) => {
-                let content_type = Self::get_content_type(&file.name);
+    /// Checks if the length of the body can only be told by closing the connection after it, which
+    /// is the case for a `Stream` body that isn't chunked
+    pub(crate) fn is_close_delimited(&self) -> bool {
+        matches!(self.body, ResponseBody::Stream(_)) && !self.chunked && !self.head_only
+    }
 
-                self.headers.insert(
-                    HttpHeader::CONTENT_LENGTH.to_string(),
-                    file.content.len().to_string(),
-                );
-                self.headers.insert(
-                    HttpHeader::CONTENT_TYPE.to_string(),
-                    content_type.to_string(),
-                );
-                if !content_type.starts_with("text/html") {
-                    let content_disposition = format!(r#"inline; filename="{}""#, file.name);
-                    self.headers.insert(
-                        HttpHeader::CONTENT_DISPOSITION.to_string(),
-                        content_disposition,
-                    );
+    /// Tries to turn a `Response` into a `ResponseWriter` that writes it to the TCP stream
+    ///
+    /// Everything that can fail before the first byte is written happens here, so that an error
+    /// can still be answered with an error response: files are opened and the headers are
+    /// completed.  
+    /// Content-Type and Content-Length headers are overridden, depending on whether there is
+    /// a body and how long it is. The body itself is never read here, but streamed by the
+    /// `ResponseWriter` in bounded chunks.  
+    /// The status line and headers are then serialized, ending with an empty line.
+    pub(crate) fn into_writer(mut self) -> Result<ResponseWriter, AppError> {
+        let mut segments = VecDeque::new();
+
+        match mem::replace(&mut self.body, ResponseBody::Empty) {
+            ResponseBody::File(path) => {
+                let (file, file_size) = Self::open_file(&path)?;
+                let file_name = Path::new(&path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let content_type = Self::get_content_type(&path).to_string();
+
+                self.set_header(HttpHeader::CONTENT_LENGTH, &file_size.to_string());
+                if !content_type.starts_with("text/html")
+                    && !self.headers.contains(HttpHeader::CONTENT_DISPOSITION)
+                {
+                    let content_disposition = Self::content_disposition("inline", &file_name);
+                    self.set_header(HttpHeader::CONTENT_DISPOSITION, &content_disposition);
                 }
-
-                Some(file.content)
+                self.set_header(HttpHeader::CONTENT_TYPE, &content_type);
+                segments.push_back(BodySegment::File {
+                    file,
+                    offset: 0,
+                    remaining: file_size,
+                });
             }
-            None => {
-                self.headers
-                    .insert(HttpHeader::CONTENT_LENGTH.to_string(), "0".to_string());
-                None
+            ResponseBody::FileRanges(path, ranges) => {
+                let (file, file_size) = Self::open_file(&path)?;
+                let content_type = Self::get_content_type(&path).to_string();
+                self.add_file_range_segments(
+                    &mut segments,
+                    file,
+                    file_size,
+                    &content_type,
+                    &ranges,
+                )?;
             }
-        };
+            ResponseBody::Text(text) => {
+                if !self.headers.contains(HttpHeader::CONTENT_TYPE) {
+                    self.set_header(HttpHeader::CONTENT_TYPE, "text/html; charset=UTF-8");
+                }
+                let body = if self.chunked {
+                    self.set_header(HttpHeader::TRANSFER_ENCODING, "chunked");
+                    Self::encode_chunked(text.as_bytes())
+                } else {
+                    self.set_header(HttpHeader::CONTENT_LENGTH, &text.len().to_string());
+                    text.into_bytes()
+                };
+                segments.push_back(BodySegment::Bytes(body));
+            }
+            ResponseBody::Stream(reader) => {
+                if !self.headers.contains(HttpHeader::CONTENT_TYPE) {
+                    self.set_header(HttpHeader::CONTENT_TYPE, "application/octet-stream");
+                }
+                if self.chunked {
+                    self.set_header(HttpHeader::TRANSFER_ENCODING, "chunked");
+                }
+                segments.push_back(BodySegment::Reader {
+                    reader,
+                    chunked: self.chunked,
+                });
+            }
+            // A 304 response stands in for the full response, so it can't claim an empty body, and a
+            // 204 response can't have a body at all
+            ResponseBody::Empty
+                if matches!(self.status, HttpStatus::NotModified | HttpStatus::NoContent) => {}
+            ResponseBody::Empty => self.set_header(HttpHeader::CONTENT_LENGTH, "0"),
+        }
+
+        // The `Display` implementation writes the status line, headers and the empty line after them
+        let head = self.to_string().into_bytes();
+        if self.head_only {
+            segments.clear();
+        }
+
+        Ok(ResponseWriter {
+            pending: head,
+            pending_offset: 0,
+            segments,
+        })
+    }
+
+    /// Opens a file to be sent in a response, returning it with its size
+    fn open_file(path: &str) -> Result<(File, u64), AppError> {
+        let file = File::open(path)
+            .map_err(|_| AppError::NotFound(format!("File failed to open: {path}")))?;
+        let metadata = file
+            .metadata()
+            .map_err(|_| AppError::IO(format!("Error reading file metadata: {path}")))?;
+        if metadata.is_dir() {
+            return Err(AppError::NotFound(format!(
+                "Path for file is a directory: {path}"
+            )));
+        }
+
+        Ok((file, metadata.len()))
+    }
+
+    /// Adds the body segments for ranges of a file, setting the headers that describe them
+    ///
+    /// Arguments:
+    /// - **segments**: The body segments of the `ResponseWriter`
+    /// - **file**: The opened file
+    /// - **file_size**: The size of the file
+    /// - **content_type**: The content type of the file
+    /// - **ranges**: The `ByteRange`s of the file to send
+    ///
+    /// A single range is sent as is, with a *Content-Range* header saying where it is in the file.  
+    /// Multiple ranges are sent as a multipart/byteranges body, where each range is a part with its
+    /// own *Content-Type* and *Content-Range* headers. The part headers are small, so they are
+    /// prepared up front, which also gives the length of the whole body.
+    fn add_file_range_segments(
+        &mut self,
+        segments: &mut VecDeque<BodySegment>,
+        file: File,
+        file_size: u64,
+        content_type: &str,
+        ranges: &[ByteRange],
+    ) -> Result<(), AppError> {
+        if let [range] = ranges {
+            self.set_header(HttpHeader::CONTENT_TYPE, content_type);
+            self.set_header(
+                HttpHeader::CONTENT_RANGE,
+                &format!("bytes {}-{}/{}", range.start, range.end, file_size),
+            );
+            self.set_header(HttpHeader::CONTENT_LENGTH, &range.len().to_string());
+            segments.push_back(BodySegment::File {
+                file,
+                offset: range.start,
+                remaining: range.len(),
+            });
+            return Ok(());
+        }
+
+        let boundary = format!(
+            "byteranges-{:x}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        );
+        let mut content_length = 0;
+        for range in ranges {
+            let part_header = format!(
+                "\r\n--{boundary}\r\n{}: {content_type}\r\n{}: bytes {}-{}/{}\r\n\r\n",
+                HttpHeader::CONTENT_TYPE,
+                HttpHeader::CONTENT_RANGE,
+                range.start,
+                range.end,
+                file_size
+            );
+            let range_file = file
+                .try_clone()
+                .map_err(|e| AppError::IO(format!("Error opening file for range: {e}")))?;
+
+            content_length += part_header.len() as u64 + range.len();
+            segments.push_back(BodySegment::Bytes(part_header.into_bytes()));
+            segments.push_back(BodySegment::File {
+                file: range_file,
+                offset: range.start,
+                remaining: range.len(),
+            });
+        }
+        let closing_boundary = format!("\r\n--{boundary}--\r\n");
+        content_length += closing_boundary.len() as u64;
+        segments.push_back(BodySegment::Bytes(closing_boundary.into_bytes()));
+
+        self.set_header(
+            HttpHeader::CONTENT_TYPE,
+            &format!("multipart/byteranges; boundary={boundary}"),
+        );
+        self.set_header(HttpHeader::CONTENT_LENGTH, &content_length.to_string());
+
+        Ok(())
+    }
+
+    /// Encodes a body with the chunked transfer coding
+    ///
+    /// Arguments:
+    /// - **body**: The body to encode
+    ///
+    /// The body is split into chunks of up to 8KB, each written with `encode_chunk()`. The body is
+    /// ended with a chunk of size 0 and an empty line.
+    fn encode_chunked(body: &[u8]) -> Vec<u8> {
+        let mut encoded_body = Vec::with_capacity(body.len() + 16);
+
+        for chunk in body.chunks(8192) {
+            encoded_body.append(&mut Self::encode_chunk(chunk));
+        }
+        encoded_body.extend_from_slice(b"0\r\n\r\n");
 
-        for (key, value) in &self.headers {
-            write!(buffer, "{}: {}\r\n", key, value)
-                .map_err(|_| AppError::IO("Error writing HTTP response to buffer".to_string()))?;
+        encoded_body
+    }
+
+    /// Encodes a single chunk, as its size in hexadecimal on a line, followed by the chunk and a CRLF
+    fn encode_chunk(chunk: &[u8]) -> Vec<u8> {
+        let mut encoded_chunk = format!("{:x}\r\n", chunk.len()).into_bytes();
+        encoded_chunk.extend_from_slice(chunk);
+        encoded_chunk.extend_from_slice(b"\r\n");
+
+        encoded_chunk
+    }
+
+    /// Builds a *Content-Disposition* header value naming a file
+    ///
+    /// Arguments:
+    /// - **disposition_type**: `inline` or `attachment`
+    /// - **file_name**: The name of the file, which can be any text
+    ///
+    /// The quoted `filename` parameter is for clients that only understand ASCII, so quotes and
+    /// backslashes in it are escaped, and control and non-ASCII characters, which could end the
+    /// header or be misread, are replaced with `_`. The `filename*` parameter of RFC 6266 carries
+    /// the exact name, as percent-encoded UTF-8, and is preferred by clients that understand it.
+    pub(crate) fn content_disposition(disposition_type: &str, file_name: &str) -> String {
+        let mut ascii_name = String::with_capacity(file_name.len());
+        for c in file_name.chars() {
+            match c {
+                '"' | '\\' => {
+                    ascii_name.push('\\');
+                    ascii_name.push(c);
+                }
+                c if c.is_ascii() && !c.is_ascii_control() => ascii_name.push(c),
+                _ => ascii_name.push('_'),
+            }
         }
-        write!(buffer, "\r\n")
-            .map_err(|_| AppError::IO("Error writing HTTP response to buffer".to_string()))?;
 
-        if let Some(mut body) = body_buffer {
-            buffer.append(&mut body)
+        let mut encoded_name = String::with_capacity(file_name.len());
+        for byte in file_name.bytes() {
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                encoded_name.push(byte as char);
+            } else {
+                encoded_name.push_str(&format!("%{byte:02X}"));
+            }
         }
 
-        Ok(buffer)
+        format!(r#"{disposition_type}; filename="{ascii_name}"; filename*=UTF-8''{encoded_name}"#)
     }
 
     /// Gets the HTTP content type based on the extension of a file
@@ -698,7 +2394,7 @@ impl Display for Response {
         )?;
 
         // Add headers
-        for (key, value) in &self.headers {
+        for (key, value) in self.headers.iter() {
             write!(f, "{}: {}\r\n", key, value)?;
         }
 
@@ -707,49 +2403,662 @@ impl Display for Response {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::Request;
-    use crate::http::{HttpMethod, Url};
-    use std::io::{BufReader, Write};
-    use std::net::{TcpListener, TcpStream};
-    use std::thread;
+/// A destination a `ResponseWriter` writes to, which may be able to send parts of files itself,
+/// without them being copied through the server's memory
+pub(crate) trait ResponseSink: Write {
+    /// Sends up to `length` bytes of a file from `offset`, returning how many bytes were sent, or
+    /// `None` if the sink can't send files itself and they must be read and written instead
+    fn send_file(&mut self, _file: &File, _offset: u64, _length: u64) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
 
-    #[test]
-    fn try_new_request() {
-        let listener = TcpListener::bind("localhost:7878").expect("Could not bind localhost:7878");
-        let handle = thread::spawn(move || {
-            for stream in listener.incoming() {
-                let mut stream = stream.unwrap();
-
-                let buf_reader = BufReader::new(&mut stream);
-                let request = Request::try_new(buf_reader).expect("Could not parse request");
-
-                assert_eq!(request.method, HttpMethod::Get);
-                assert_eq!(request.path, Url::try_new("/home").unwrap());
-                assert_eq!(request.http_version, "HTTP/1.1");
-                assert_eq!(request.headers.len(), 3);
-                assert_eq!(request.headers.get("Host").unwrap(), "localhost");
-                assert_eq!(request.headers.get("Accept").unwrap(), "text/html");
-                break;
+impl ResponseSink for Vec<u8> {}
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" {
+    fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+}
+
+impl ResponseSink for TcpStream {
+    /// Uses the `sendfile` system call on Linux, which copies the file to the socket inside the kernel
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &File, offset: u64, length: u64) -> io::Result<Option<u64>> {
+        use std::os::fd::AsRawFd;
+
+        // Linux never sends more than this in one call
+        let count = std::cmp::min(length, 0x7fff_f000) as usize;
+        let mut offset = offset as i64;
+
+        loop {
+            // SAFETY: both file descriptors are open for the duration of the call, and `offset`
+            // is a valid pointer to an `i64` that the kernel updates
+            let sent = unsafe { sendfile(self.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+            if sent >= 0 {
+                return Ok(Some(sent as u64));
             }
-        });
 
-        // Wait for server to start
-        thread::sleep(std::time::Duration::from_millis(100));
+            let error = io::Error::last_os_error();
+            match error.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => return Err(error),
+                // The file system doesn't support it, so fall back to reading the file
+                _ if matches!(error.raw_os_error(), Some(22) | Some(38)) => return Ok(None),
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+/// A part of a response body waiting to be written by a `ResponseWriter`
+enum BodySegment {
+    Bytes(Vec<u8>),
+    File {
+        file: File,
+        offset: u64,
+        remaining: u64,
+    },
+    Reader {
+        reader: Box<dyn Read + Send>,
+        chunked: bool,
+    },
+}
+
+/// A `ResponseWriter` writes a `Response` to a `ResponseSink` a bounded chunk at a time, so the
+/// whole response never has to be held in memory.  
+/// It keeps track of how much has been written, so writing can stop when the sink can't take any
+/// more, and pick up where it left off.
+pub(crate) struct ResponseWriter {
+    pending: Vec<u8>,
+    pending_offset: usize,
+    segments: VecDeque<BodySegment>,
+}
+
+impl ResponseWriter {
+    /// The most bytes of a file or reader held in memory at once
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    /// Creates a `ResponseWriter` of the interim `100 Continue` response, which tells a client that
+    /// sent `Expect: 100-continue` to go on with the body of its request
+    pub(crate) fn continue_response() -> Self {
+        ResponseWriter {
+            pending: b"HTTP/1.1 100 Continue\r\n\r\n".to_vec(),
+            pending_offset: 0,
+            segments: VecDeque::new(),
+        }
+    }
+
+    /// Writes as much of the response as the sink takes, returning `true` once all of it is written
+    ///
+    /// Arguments:
+    /// - **sink**: The `ResponseSink` to write to
+    ///
+    /// Bytes waiting in the pending buffer are written first. Once it is empty, it is filled from the
+    /// next body segment: bytes are moved in as they are, files are sent with `send_file()` where the
+    /// sink supports it or read a chunk at a time otherwise, and readers are read a chunk at a time,
+    /// each chunk framed with `Response::encode_chunk()` if the body is chunked.  
+    /// This runs on the event loop, unlike the body of a request, which is written by workers.
+    /// Readers are pulled here on purpose: a `Stream` body is only ever made of bytes in memory, so
+    /// a chunk of it is produced without blocking, and handing each chunk to a worker would cost
+    /// more than producing it.  
+    /// Errors from the sink, including `WouldBlock` for a non-blocking sink, are returned as they are,
+    /// and calling this again continues from where writing stopped.
+    pub(crate) fn write_some<W: ResponseSink>(&mut self, sink: &mut W) -> io::Result<bool> {
+        loop {
+            if self.pending_offset < self.pending.len() {
+                let bytes_written = sink.write(&self.pending[self.pending_offset..])?;
+                if bytes_written == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.pending_offset += bytes_written;
+                continue;
+            }
+            self.pending.clear();
+            self.pending_offset = 0;
+
+            let Some(segment) = self.segments.front_mut() else {
+                sink.flush()?;
+                return Ok(true);
+            };
+
+            match segment {
+                BodySegment::Bytes(bytes) => {
+                    self.pending = mem::take(bytes);
+                    self.segments.pop_front();
+                }
+                BodySegment::File { remaining: 0, .. } => {
+                    self.segments.pop_front();
+                }
+                BodySegment::File {
+                    file,
+                    offset,
+                    remaining,
+                } => {
+                    let bytes_sent = match sink.send_file(file, *offset, *remaining)? {
+                        Some(bytes_sent) => bytes_sent,
+                        None => {
+                            let to_read = std::cmp::min(*remaining, Self::CHUNK_SIZE as u64);
+                            self.pending.resize(to_read as usize, 0);
+                            file.seek(SeekFrom::Start(*offset))?;
+                            file.read_exact(&mut self.pending)?;
+                            to_read
+                        }
+                    };
+                    if bytes_sent == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "File became shorter while it was being sent",
+                        ));
+                    }
+                    *offset += bytes_sent;
+                    *remaining -= bytes_sent;
+                }
+                BodySegment::Reader { reader, chunked } => {
+                    let mut chunk = vec![0; Self::CHUNK_SIZE];
+                    let bytes_read = reader.read(&mut chunk)?;
+                    chunk.truncate(bytes_read);
+
+                    self.pending = match (bytes_read, *chunked) {
+                        (0, true) => b"0\r\n\r\n".to_vec(),
+                        (0, false) => Vec::new(),
+                        (_, true) => Response::encode_chunk(&chunk),
+                        (_, false) => chunk,
+                    };
+                    if bytes_read == 0 {
+                        self.segments.pop_front();
+                    }
+                }
+            }
+        }
+    }
+}
 
-        // Create a mock HTTP client request
-        let mut stream = TcpStream::connect("localhost:7878").expect("Failed to connect");
+#[cfg(test)]
+mod tests {
+    use crate::common::AppError;
+    use crate::config::Config;
+    use crate::http::{
+        ByteRange, ChunkedDecoder, HttpHeader, HttpMethod, MultipartParser, Query, RequestBody,
+        RequestParser, Response, ResponseBody, Url,
+    };
+    use std::io::{self, Read};
+    use std::thread;
 
-        let request = "GET /home HTTP/1.1\r\n\
+    #[test]
+    fn parse_request() {
+        let mut buffer = b"GET /home HTTP/1.1\r\n\
                Host: localhost\r\n\
                User-Agent: MyTestClient/1.0\r\n\
                Accept: text/html\r\n\
-               \r\n";
-        stream
-            .write_all(request.as_bytes())
-            .expect("Failed to send request");
+               \r\n"
+            .to_vec();
+
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).expect("Could not parse request"));
+        let request = parser.into_request().expect("Could not parse request");
+
+        assert_eq!(request.method, HttpMethod::Get);
+        assert_eq!(request.path, Url::try_new("/home").unwrap());
+        assert_eq!(request.http_version, "HTTP/1.1");
+        assert_eq!(request.headers.iter().count(), 3);
+        assert_eq!(request.headers.get("Host").unwrap(), "localhost");
+        assert_eq!(request.headers.get("Accept").unwrap(), "text/html");
+        assert!(request.is_keep_alive());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn parse_multipart_body_with_several_files() {
+        let body = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"directory\"\r\n\r\n\
+            team\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            first file\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"b.png\"\r\n\r\n\
+            second\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"other\"; filename=\"\"\r\n\r\n\
+            \r\n--XyZ--\r\n";
+        let mut buffer = format!(
+            "POST /upload HTTP/1.1\r\n\
+             Content-Type: multipart/form-data; boundary=\"XyZ\"\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {body}",
+            body.len()
+        )
+        .into_bytes();
+
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).unwrap());
+        let request = parser.into_request().unwrap();
+        let RequestBody::Multipart(mut form) = request.body else {
+            panic!("Body is not multipart: {}", request.body);
+        };
+
+        assert_eq!(form.fields.len(), 1);
+        assert_eq!(form.fields[0].name, "directory");
+        assert_eq!(form.fields[0].value, "team");
+
+        // The empty file input is skipped
+        assert_eq!(form.files.len(), 2);
+        let mut contents = Vec::new();
+        for form_file in &mut form.files {
+            assert_eq!(form_file.field_name, "file");
+            let mut content = String::new();
+            form_file
+                .file
+                .open_reader()
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(form_file.file.size(), content.len() as u64);
+            contents.push((form_file.filename.as_str(), content));
+        }
+        assert_eq!(
+            contents,
+            [
+                ("a.txt", "first file".to_string()),
+                ("b.png", "second".to_string())
+            ]
+        );
+        assert_eq!(form.files[0].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(form.files[1].content_type, None);
+    }
+
+    #[test]
+    fn hand_large_bodies_to_workers_in_chunks() {
+        let content = "0123456789abcdef".repeat(96 * 1024);
+        let part_head =
+            "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"big.txt\"\r\n\r\n";
+        let part_tail = "\r\n--XyZ--\r\n";
+        let mut buffer = format!(
+            "POST /upload HTTP/1.1\r\n\
+             Content-Type: multipart/form-data; boundary=XyZ\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {part_head}{content}",
+            part_head.len() + content.len() + part_tail.len()
+        )
+        .into_bytes();
+
+        // Once too much of the body is queued, it is backlogged until a worker writes it
+        let mut parser = RequestParser::new();
+        assert!(!parser.parse(&mut buffer).unwrap());
+        assert!(parser.is_body_backlogged());
+        let body_drain = parser.take_body_drain().unwrap();
+        assert!(parser.take_body_drain().is_none());
+        thread::spawn(move || body_drain.run()).join().unwrap();
+        assert!(!parser.is_body_backlogged());
+        assert!(parser.take_body_drain().is_none());
+
+        // The rest of the body is written by the worker the request is handled by
+        assert!(parser.parse(&mut part_tail.as_bytes().to_vec()).unwrap());
+        let request = parser.into_request().unwrap();
+        let RequestBody::Multipart(mut form) = request.body else {
+            panic!("Body is not multipart: {}", request.body);
+        };
+        let mut written = String::new();
+        form.files[0]
+            .file
+            .open_reader()
+            .unwrap()
+            .read_to_string(&mut written)
+            .unwrap();
+        assert!(written == content);
+    }
+
+    #[test]
+    fn parse_url_encoded_form_body() {
+        let body = "_method=DELETE&name=a+b%2Fc.txt";
+        let mut buffer = format!(
+            "POST /uploads/a.txt HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {body}",
+            body.len()
+        )
+        .into_bytes();
+
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).expect("Could not parse request"));
+        let request = parser.into_request().expect("Could not parse request");
+
+        let RequestBody::Form(form) = &request.body else {
+            panic!("Body is not a form: {}", request.body);
+        };
+        assert_eq!(form.get("_method"), Some("DELETE"));
+        assert_eq!(form.get("name"), Some("a b/c.txt"));
+    }
+
+    #[test]
+    fn split_url_into_path_and_query() {
+        let url = Url::try_new("/uploads/a%20b.txt?download=true&tag=a&tag=b+c%26d&flag").unwrap();
+        assert_eq!(url.path(), "/uploads/a b.txt");
+        assert_eq!(
+            url.to_string(),
+            "/uploads/a b.txt?download=true&tag=a&tag=b+c%26d&flag"
+        );
+
+        let query = url.query();
+        assert_eq!(query.get_parsed::<bool>("download").unwrap(), Some(true));
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), ["a", "b c&d"]);
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get_parsed::<u32>("missing").unwrap(), None);
+        assert!(query.get_parsed::<u32>("tag").is_err());
+
+        assert_eq!(Url::try_new("/").unwrap().query(), &Query::default());
+    }
+
+    #[test]
+    fn decode_and_normalize_url_path() {
+        let path = |raw_url: &str| Url::try_new(raw_url).map(|url| url.path().to_string());
+
+        assert_eq!(
+            path("/uploads/caf%C3%A9+1.txt").unwrap(),
+            "/uploads/café+1.txt"
+        );
+        assert_eq!(path("/uploads/./docs/../a.txt").unwrap(), "/uploads/a.txt");
+        assert_eq!(path("/../../etc/passwd").unwrap(), "/etc/passwd");
+        assert_eq!(path("/uploads/%2e%2E/").unwrap(), "/");
+        assert_eq!(path("/uploads/docs/..").unwrap(), "/uploads/");
+        assert_eq!(path("*").unwrap(), "*");
+
+        assert!(path("/uploads/..%2Fconfig.toml").is_err());
+        assert!(path("/uploads/a%00.txt").is_err());
+        assert!(path("/uploads/%C3").is_err());
+        assert!(path("/uploads/%+1").is_err());
+
+        let destination = |uri: &str| Url::from_uri(uri).map(|url| url.path().to_string());
+        assert_eq!(
+            destination("http://localhost:7878/uploads/new%20name.txt").unwrap(),
+            "/uploads/new name.txt"
+        );
+        assert_eq!(destination("https://localhost").unwrap(), "/");
+        assert_eq!(
+            destination("/uploads/a.txt?from=http://x").unwrap(),
+            "/uploads/a.txt"
+        );
+
+        assert_eq!(
+            Url::encode_path("docs/café #1.txt"),
+            "docs/caf%C3%A9%20%231.txt"
+        );
+    }
+
+    #[test]
+    fn keep_repeated_headers_in_order() {
+        let mut buffer = b"GET / HTTP/1.1\r\n\
+            Accept: text/html\r\n\
+            cookie: theme=dark\r\n\
+            Accept: text/plain\r\n\
+            Connection: upgrade, Close\r\n\r\n"
+            .to_vec();
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).unwrap());
+        let request = parser.into_request().unwrap();
+
+        let headers = request.headers();
+        assert_eq!(request.get_header("COOKIE"), Some("theme=dark"));
+        assert_eq!(
+            headers.get_all("Accept").collect::<Vec<_>>(),
+            ["text/html", "text/plain"]
+        );
+        assert!(headers.has_token(HttpHeader::CONNECTION, "close"));
+        assert!(!request.is_keep_alive());
+
+        let mut response = Response::builder()
+            .header("Set-Cookie", "theme=dark")
+            .header("Set-Cookie", "session=1; HttpOnly")
+            .build();
+        response.set_header("content-type", "text/plain");
+        response.set_header(HttpHeader::CONTENT_TYPE, "text/html");
+
+        let mut written = Vec::new();
+        assert!(
+            response
+                .into_writer()
+                .unwrap()
+                .write_some(&mut written)
+                .unwrap()
+        );
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains(
+            "Set-Cookie: theme=dark\r\nSet-Cookie: session=1; HttpOnly\r\ncontent-type: text/html\r\n"
+        ));
+    }
+
+    #[test]
+    fn parse_request_as_it_arrives() {
+        let request = b"POST /upload HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Expect: 100-continue\r\n\r\n\
+            5\r\nhello\r\n0\r\nChecksum: abc\r\n\r\n\
+            GET / HTTP/1.1\r\n\r\n";
+        let first_request_length = request.len() - b"GET / HTTP/1.1\r\n\r\n".len();
+
+        // Every byte arrives on its own, the way a slow client would send them
+        let mut parser = RequestParser::new();
+        let mut buffer = Vec::new();
+        let mut bytes_sent = 0;
+        while !parser.parse(&mut buffer).unwrap() {
+            assert!(bytes_sent < first_request_length);
+            buffer.push(request[bytes_sent]);
+            bytes_sent += 1;
+        }
+        assert_eq!(bytes_sent, first_request_length);
+        assert!(parser.take_continue_expected());
+        assert!(!parser.take_continue_expected());
+
+        let request = parser.into_request().unwrap();
+        assert_eq!(request.method, HttpMethod::Post);
+        assert_eq!(request.headers.get("Checksum").unwrap(), "abc");
+
+        // A request sent right after the previous one is left for the next parser
+        buffer.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).unwrap());
+        assert_eq!(
+            parser.into_request().unwrap().path,
+            Url::try_new("/").unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_oversized_request_head() {
+        let config = Config::get();
+
+        // A line is rejected before it has fully arrived
+        let mut buffer = format!(
+            "GET /{}",
+            "a".repeat(config.max_request_line_length as usize)
+        )
+        .into_bytes();
+        assert!(matches!(
+            RequestParser::new().parse(&mut buffer),
+            Err(AppError::UriTooLong(_))
+        ));
+
+        let mut buffer = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n",
+            "a".repeat(config.max_header_line_length as usize)
+        )
+        .into_bytes();
+        assert!(matches!(
+            RequestParser::new().parse(&mut buffer),
+            Err(AppError::HeaderTooLarge(_))
+        ));
+
+        let mut buffer = b"GET / HTTP/1.1\r\n".to_vec();
+        for index in 0..=config.max_header_count {
+            buffer.extend_from_slice(format!("X-Header-{index}: value\r\n").as_bytes());
+        }
+        assert!(matches!(
+            RequestParser::new().parse(&mut buffer),
+            Err(AppError::HeaderTooLarge(_))
+        ));
+
+        // Blank lines before a request are skipped
+        let mut buffer = b"\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        assert!(RequestParser::new().parse(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn multipart_parser_keeps_binary_content() {
+        let file_content: Vec<u8> = vec![
+            0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0xff, 0x00,
+        ];
+        let mut body = b"preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+            hello\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a;b.png\"\r\n\
+            Content-Type: image/png\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&file_content);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+        // Tiny pieces make the delimiters span several writes
+        let mut parser = MultipartParser::new("multipart/form-data; boundary=XyZ");
+        for piece in body.chunks(3) {
+            parser.write(piece);
+        }
+        let mut form = parser.finish().unwrap();
+
+        assert_eq!(form.fields.len(), 1);
+        assert_eq!(form.fields[0].name, "note");
+        assert_eq!(form.fields[0].value, "hello");
+
+        assert_eq!(form.files.len(), 1);
+        let form_file = &mut form.files[0];
+        assert_eq!(form_file.field_name, "file");
+        assert_eq!(form_file.filename, "a;b.png");
+        assert_eq!(form_file.content_type.as_deref(), Some("image/png"));
+        let mut content = Vec::new();
+        form_file
+            .file
+            .open_reader()
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, file_content);
+    }
+
+    #[test]
+    fn multipart_parser_rejects_body_without_closing_delimiter() {
+        let mut parser = MultipartParser::new("multipart/form-data; boundary=XyZ");
+        parser.write(b"--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhello");
+        assert!(matches!(parser.finish(), Err(AppError::Invalid(_))));
+
+        let mut parser = MultipartParser::new("multipart/form-data");
+        parser.write(b"--XyZ--\r\n");
+        assert!(matches!(parser.finish(), Err(AppError::Invalid(_))));
+    }
+
+    #[test]
+    fn escape_file_name_in_content_disposition() {
+        assert_eq!(
+            Response::content_disposition("attachment", "report.pdf"),
+            r#"attachment; filename="report.pdf"; filename*=UTF-8''report.pdf"#
+        );
+        assert_eq!(
+            Response::content_disposition("inline", "a \"b\"\\c.txt"),
+            r#"inline; filename="a \"b\"\\c.txt"; filename*=UTF-8''a%20%22b%22%5Cc.txt"#
+        );
+        // A line break in a file name can't end the header
+        assert_eq!(
+            Response::content_disposition("inline", "résumé\r\nX: y.txt"),
+            r#"inline; filename="r_sum___X: y.txt"; filename*=UTF-8''r%C3%A9sum%C3%A9%0D%0AX%3A%20y.txt"#
+        );
+    }
+
+    #[test]
+    fn parse_byte_ranges() {
+        let range = |start, end| ByteRange { start, end };
+
+        assert_eq!(
+            ByteRange::parse_ranges("bytes=0-99", 1000),
+            Some(vec![range(0, 99)])
+        );
+        assert_eq!(
+            ByteRange::parse_ranges("bytes=-100, 900-", 1000),
+            Some(vec![range(900, 999)])
+        );
+        assert_eq!(
+            ByteRange::parse_ranges("bytes=500-599,0-9,5-20", 1000),
+            Some(vec![range(0, 20), range(500, 599)])
+        );
+        assert_eq!(ByteRange::parse_ranges("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(ByteRange::parse_ranges("bytes=10-5", 1000), None);
+        assert_eq!(ByteRange::parse_ranges("items=0-5", 1000), None);
+    }
+
+    #[test]
+    fn chunked_decoder_decodes_body_and_trailers() {
+        let body = b"5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nChecksum: abc\r\nContent-Length: 5\r\n\r\nGET /next";
+        let mut decoder = ChunkedDecoder::new();
+
+        let mut decoded = Vec::new();
+        let (consumed, is_complete) = decoder
+            .decode(body, |data| {
+                decoded.extend_from_slice(data);
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(is_complete);
+        assert_eq!(decoded, b"hello, world");
+        assert_eq!(decoder.trailers.iter().count(), 1);
+        assert_eq!(decoder.trailers.get("Checksum").unwrap(), "abc");
+        // Nothing past the end of the chunked body is taken
+        assert_eq!(&body[consumed..], b"GET /next");
+    }
+
+    #[test]
+    fn response_writer_streams_body() {
+        let body = "a".repeat(100_000);
+        let response = Response::builder()
+            .body(ResponseBody::Stream(Box::new(io::Cursor::new(
+                body.clone(),
+            ))))
+            .chunked()
+            .build();
+
+        let mut written = Vec::new();
+        let mut response_writer = response.into_writer().unwrap();
+        assert!(response_writer.write_some(&mut written).unwrap());
+
+        let written = String::from_utf8(written).unwrap();
+        let (head, encoded_body) = written.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+
+        let mut decoded = Vec::new();
+        let (_, is_complete) = ChunkedDecoder::new()
+            .decode(encoded_body.as_bytes(), |data| {
+                decoded.extend_from_slice(data);
+                Ok(())
+            })
+            .unwrap();
+        assert!(is_complete);
+        assert_eq!(decoded, body.as_bytes());
+    }
 
-        handle.join().expect("Failed to join thread");
+    #[test]
+    fn head_response_has_headers_without_body() {
+        let mut response = Response::builder()
+            .body(ResponseBody::Text("<p>Hello</p>".to_string()))
+            .build();
+        response.set_head_only();
+
+        let mut written = Vec::new();
+        let mut response_writer = response.into_writer().unwrap();
+        assert!(response_writer.write_some(&mut written).unwrap());
+
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("Content-Length: 12\r\n"));
+        assert!(written.ends_with("\r\n\r\n"));
     }
 }
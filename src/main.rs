@@ -1,24 +1,58 @@
 mod common;
+mod config;
+mod connection;
 mod handlers;
 mod http;
+mod metrics;
+mod middleware;
+mod poller;
+mod router;
+mod signal;
+mod template;
 
 use crate::common::FileManager;
 use crate::common::{AppError, Time};
-use crate::handlers::{ErrorHandler, Router};
-use crate::http::{Request, Response};
-use std::io::{BufReader, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use crate::config::Config;
+use crate::connection::{Connection, MAX_REQUESTS_PER_CONNECTION, ReadOutcome, Timeout};
+use crate::handlers::{ErrorHandler, RequestHandler, Templates};
+use crate::http::{HttpHeader, RequestParser, Response, ResponseWriter};
+use crate::metrics::Metrics;
+use crate::middleware::{ErrorLogger, MiddlewareChain, RequestLogger};
+use crate::poller::{Interest, Poller, Waker};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
-use std::sync::{Arc, LazyLock, Mutex, mpsc};
-use std::{fs, thread};
+use std::sync::mpsc::TrySendError;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, mpsc};
+use std::time::{Duration, Instant};
+use std::{env, fs, mem, process, thread};
+
+/// How long the event loop waits for connections to be ready before checking for timed out
+/// connections, and whether the server is shutting down
+const POLL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The token the listener is registered with in the `Poller`
+const LISTENER_TOKEN: u64 = 0;
+/// The token the `Waker` is registered with in the `Poller`
+const WAKER_TOKEN: u64 = 1;
+/// The token of the first connection, every connection after it getting the next one, so that a
+/// token is never reused for another connection
+const FIRST_CONNECTION_TOKEN: u64 = 2;
+
+/// The number of seconds a client rejected with a 503 status is told to wait before trying again
+const OVERLOAD_RETRY_AFTER: u64 = 5;
 
 /// A `Job` is a type alias for any function that runs once and implements `Send` and `static`
 type Job = Box<dyn FnOnce() -> Result<(), String> + Send + 'static>;
 
 /// A `Worker` is a type that handles a single thread and runs a job received
 struct Worker {
-    _id: usize,
-    _thread: thread::JoinHandle<Arc<Mutex<mpsc::Receiver<Job>>>>,
+    id: usize,
+    thread: thread::JoinHandle<()>,
 }
 
 impl Worker {
@@ -28,38 +62,52 @@ impl Worker {
     /// - **id**: a usize to uniquely identify the worker  
     /// - **receiver**: a channel receiver wrapped in a Mutex wrapped in an Arc
     ///
-    /// This method creates a new thread and passes a closure containing an infinite loop of waiting
-    /// for the mutex to be free, acquiring the lock, getting the available job in the channel, freeing
-    /// the lock and then executing the job.
+    /// This method creates a new thread and passes a closure containing a loop of waiting for the
+    /// mutex to be free, acquiring the lock, getting the available job in the channel, freeing the
+    /// lock and then executing the job.  
+    /// The loop ends once the channel is closed and every job sent through it has been received,
+    /// which is how the `ThreadPool` shuts its workers down.
+    ///
+    /// Jobs are run with `catch_unwind`, so a panic in one is logged and the worker moves on to the
+    /// next job. The mutex is only held while receiving, which doesn't panic, but if it is ever
+    /// poisoned it is recovered, as the receiver it guards can't be left in an inconsistent state.
     fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
         let thread = thread::spawn(move || {
             loop {
-                let job = receiver
+                let message = receiver
                     .lock()
-                    .expect(format!("Worker {id} unable to acquire mutex lock").as_str())
-                    .recv()
-                    .expect(format!("Worker {id} failed to receive job from channel").as_str());
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
+                let Ok(job) = message else {
+                    break;
+                };
+                Metrics::request_dequeued();
 
-                match job() {
-                    Ok(()) => {}
-                    Err(e) => {
+                match panic::catch_unwind(AssertUnwindSafe(job)) {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
                         warn!("{}", e);
                     }
+                    Err(panic_payload) => {
+                        log_error!(
+                            "Worker {} recovered from a panic in a job: {}",
+                            id,
+                            get_panic_message(panic_payload.as_ref())
+                        );
+                    }
                 }
             }
         });
-        Worker {
-            _id: id,
-            _thread: thread,
-        }
+        Worker { id, thread }
     }
 }
 
 /// A `ThreadPool` is a struct that handles multiple threads using workers, and communicates with
 /// them by sending `Job`s through a channel, the first available worker picks up the job and executes it
 struct ThreadPool {
-    _workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    workers: Vec<Worker>,
+    sender: Option<mpsc::SyncSender<Job>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
 }
 
 impl ThreadPool {
@@ -67,16 +115,18 @@ impl ThreadPool {
     ///
     /// Arguments:
     /// - **size**: the number of workers in the `ThreadPool`
+    /// - **queue_size**: the number of jobs that can wait for a worker
     ///
-    /// This method creates a channel and holds onto the sender, passing the receiver to each new
-    /// `Worker` created.
+    /// This method creates a bounded channel and holds onto the sender, passing the receiver to each
+    /// new `Worker` created.
     /// An `Arc<Mutex>` is used so that the channel can be passed between threads and so that only
     /// one worker has access to the mutex of the receiver at a time
-    fn new(size: usize) -> ThreadPool {
+    fn new(size: usize, queue_size: usize) -> ThreadPool {
         assert!(size > 0);
         let mut workers = Vec::with_capacity(size);
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        Metrics::set_queue_capacity(queue_size);
         let receiver = Arc::new(Mutex::new(receiver));
 
         for id in 0..size {
@@ -84,8 +134,9 @@ impl ThreadPool {
             workers.push(worker);
         }
         ThreadPool {
-            _workers: workers,
-            sender,
+            workers,
+            sender: Some(sender),
+            receiver,
         }
     }
 
@@ -95,24 +146,119 @@ impl ThreadPool {
     /// - **f**: any object that implements `FnOnce()` + `Send` + `'static`
     ///
     /// This method creates a new job and sends it to a channel from the sender, to be consumed by
-    /// the first available receiver, which will be a thread in one of the workers
-    fn execute<F>(&self, f: F)
+    /// the first available receiver, which will be a thread in one of the workers.  
+    /// Dead workers are replaced first, so the pool never shrinks.
+    ///
+    /// The job is never waited on to fit in the channel. If the job queue is full, it is dropped
+    /// without running, and `false` is returned, so the caller can turn the work away instead.
+    fn execute<F>(&mut self, f: F) -> bool
     where
         F: FnOnce() -> Result<(), String> + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender
-            .send(job)
-            .expect("Failed to send job to worker through channel");
+        self.respawn_dead_workers();
+
+        // The job is counted before it is sent, as a worker may take it before `try_send` returns
+        Metrics::request_queued();
+        let sender = self
+            .sender
+            .as_ref()
+            .expect("Thread pool has been shut down");
+        match sender.try_send(Box::new(f)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                Metrics::request_not_queued();
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                panic!("Failed to send job to worker through channel")
+            }
+        }
+    }
+
+    /// Replaces every worker whose thread has stopped with a new one with the same id
+    ///
+    /// Panics in jobs are caught, so a worker thread only stops on its own if something outside a job
+    /// panicked. Without replacing it, the pool would quietly have one worker less, until there are
+    /// none left to handle requests.
+    fn respawn_dead_workers(&mut self) {
+        for worker in &mut self.workers {
+            if !worker.thread.is_finished() {
+                continue;
+            }
+
+            log_error!("Worker {} died and is being replaced", worker.id);
+            let dead_worker =
+                mem::replace(worker, Worker::new(worker.id, Arc::clone(&self.receiver)));
+            let _ = dead_worker.thread.join();
+        }
+    }
+
+    /// Shuts the `ThreadPool` down, letting its workers finish the jobs already sent to them
+    ///
+    /// Arguments:
+    /// - **timeout**: How long the workers are given to finish
+    ///
+    /// The sender is dropped, closing the channel, so each worker stops once there are no jobs left
+    /// in it. The workers that stop within the timeout are joined, and the number of workers still
+    /// running a job after it is returned. Those can't be stopped from the outside, and are cut off
+    /// when the process exits.
+    fn shutdown(&mut self, timeout: Duration) -> usize {
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + timeout;
+        while self
+            .workers
+            .iter()
+            .any(|worker| !worker.thread.is_finished())
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let (finished, unfinished): (Vec<Worker>, Vec<Worker>) = self
+            .workers
+            .drain(..)
+            .partition(|worker| worker.thread.is_finished());
+        for worker in finished {
+            if worker.thread.join().is_err() {
+                warn!("Worker {} panicked before shutting down", worker.id);
+            }
+        }
+
+        unfinished.len()
     }
 }
 
+/// A `Completion` is what a worker sends back to the event loop once it is done with a job for a
+/// connection
+/// - **Response**: The response produced to the request of the connection, to be written
+/// - **BodyDrained**: The chunks of the request body that were queued were written, so the
+///   connection can be read from again if it was backlogged
+enum Completion {
+    Response {
+        connection_id: u64,
+        response_writer: ResponseWriter,
+        keep_alive: bool,
+    },
+    BodyDrained {
+        connection_id: u64,
+    },
+}
+
 /// A `Server` is an abstraction of some of the logic that runs a web server and handles each TCP stream
-/// It holds the listener that listens for each HTTP request and the thread pool that assigns each
-/// request to an available thread.
+/// It holds the listener that accepts connections and the `Poller` of the event loop that reads
+/// requests from them and writes responses to them, along with the thread pool that each complete
+/// request is handed to, and the `MiddlewareChain` every request is handled with.
 struct Server {
-    listener: TcpListener,
+    listener: Option<TcpListener>,
+    poller: Poller,
+    waker: Arc<Waker>,
+    connections: HashMap<u64, Connection>,
+    next_connection_id: u64,
+    completion_sender: mpsc::Sender<Completion>,
+    completion_receiver: mpsc::Receiver<Completion>,
     thread_pool: ThreadPool,
+    middleware_chain: Arc<MiddlewareChain>,
 }
 
 impl Server {
@@ -121,60 +267,490 @@ impl Server {
     /// Arguments:
     /// - **server_address**: The host and port the server will run on
     /// - **number_of_workers**: The number of threads that the server will have
-    fn new(server_address: &str, number_of_workers: usize) -> Server {
-        let listener = TcpListener::bind(&server_address).expect("Could not bind to address");
-        let thread_pool = ThreadPool::new(number_of_workers);
+    /// - **queue_size**: The number of requests that can wait for a thread
+    fn new(server_address: &str, number_of_workers: usize, queue_size: usize) -> Server {
+        let listener = TcpListener::bind(server_address).expect("Could not bind to address");
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to make listener non-blocking");
+        let poller = Poller::new().expect("Failed to create poller");
+        poller
+            .add(&listener, LISTENER_TOKEN, Interest::Read)
+            .expect("Failed to register listener");
+        let waker = Waker::new().expect("Failed to create waker");
+        poller
+            .add(&waker, WAKER_TOKEN, Interest::Read)
+            .expect("Failed to register waker");
+
+        let (completion_sender, completion_receiver) = mpsc::channel();
+        let thread_pool = ThreadPool::new(number_of_workers, queue_size);
+        let middleware_chain = MiddlewareChain::new(RequestHandler::routes())
+            .with(RequestLogger)
+            .with(ErrorLogger);
 
         Server {
-            listener,
+            listener: Some(listener),
+            poller,
+            waker: Arc::new(waker),
+            connections: HashMap::new(),
+            next_connection_id: FIRST_CONNECTION_TOKEN,
+            completion_sender,
+            completion_receiver,
             thread_pool,
+            middleware_chain: Arc::new(middleware_chain),
+        }
+    }
+
+    /// Runs the event loop of the server until it is asked to shut down
+    ///
+    /// Every connection is non-blocking and registered with the `Poller`, and the loop waits until
+    /// any of them is ready, accepting new connections, reading requests from the connections
+    /// that are readable, and writing responses to the ones that are writable. Requests are handed
+    /// to the thread pool once all of them has arrived, and the workers send their responses back
+    /// through a channel, waking the loop up with the `Waker`. This way, an idle connection or a slow
+    /// client never holds a thread, and connections waiting on the client for too long time out.  
+    /// Once a shutdown signal is received, the listener is closed so no new connections are accepted,
+    /// and connections between requests are closed. The rest are given the configured shutdown
+    /// timeout to finish, and the thread pool is shut down after them.
+    fn run(mut self) {
+        let mut events = Vec::new();
+        let mut shutdown_deadline = None;
+        let mut last_timeout_check = Instant::now();
+
+        loop {
+            if shutdown_deadline.is_none() && signal::is_shutdown_requested() {
+                let shutdown_timeout = Config::get().shutdown_timeout;
+                log!(
+                    "Shutting down, waiting up to {}s for in-flight requests to finish",
+                    shutdown_timeout
+                );
+                shutdown_deadline = Some(Instant::now() + Duration::from_secs(shutdown_timeout));
+                if let Some(listener) = self.listener.take() {
+                    let _ = self.poller.delete(&listener);
+                }
+            }
+            if let Some(deadline) = shutdown_deadline {
+                self.close_connections(Connection::is_between_requests);
+                if self.connections.is_empty() || Instant::now() >= deadline {
+                    break;
+                }
+            }
+
+            if let Err(e) = self.poller.wait(&mut events, POLL_TIMEOUT) {
+                log_error!("Error waiting for connections to be ready: {}", e);
+                thread::sleep(POLL_TIMEOUT);
+                continue;
+            }
+            for event in &events {
+                match event.token {
+                    LISTENER_TOKEN => self.accept_connections(),
+                    WAKER_TOKEN => self.waker.reset(),
+                    connection_id => {
+                        if event.writable {
+                            self.update_connection(connection_id, Connection::write);
+                        } else if event.readable {
+                            self.update_connection(connection_id, Connection::read);
+                        }
+                        // Whatever arrived before the connection was closed is read first
+                        if event.closed {
+                            self.close_connection(connection_id);
+                        }
+                    }
+                }
+            }
+            while let Ok(completion) = self.completion_receiver.try_recv() {
+                match completion {
+                    Completion::Response {
+                        connection_id,
+                        response_writer,
+                        keep_alive,
+                    } => self.update_connection(connection_id, |connection| {
+                        connection.respond(response_writer, keep_alive)
+                    }),
+                    Completion::BodyDrained { connection_id } => {
+                        self.update_connection(connection_id, |_| ReadOutcome::Pending)
+                    }
+                }
+            }
+
+            if last_timeout_check.elapsed() >= POLL_TIMEOUT {
+                let now = Instant::now();
+                self.close_timed_out_connections(now);
+                self.drain_request_bodies();
+                last_timeout_check = now;
+            }
+        }
+
+        let unfinished_connections = self.connections.len();
+        self.close_connections(|_| true);
+        let remaining_time = shutdown_deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .unwrap_or_default();
+        let unfinished_workers = self.thread_pool.shutdown(remaining_time);
+
+        if unfinished_connections > 0 {
+            warn!(
+                "{} connections were still being served when the shutdown timeout ran out",
+                unfinished_connections
+            );
+        }
+        if unfinished_workers > 0 {
+            warn!(
+                "{} workers were still handling requests when the shutdown timeout ran out",
+                unfinished_workers
+            );
+        }
+        if unfinished_connections == 0 && unfinished_workers == 0 {
+            log!("All in-flight requests finished");
+        }
+    }
+
+    /// Accepts every connection waiting on the listener, registering each with the `Poller`
+    fn accept_connections(&mut self) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log_error!("Encountered error getting stream {}", e);
+                    break;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                log_error!("Error making stream non-blocking: {}", e);
+                continue;
+            }
+
+            let connection_id = self.next_connection_id;
+            self.next_connection_id += 1;
+            if let Err(e) = self.poller.add(&stream, connection_id, Interest::Read) {
+                log_error!("Error registering stream: {}", e);
+                continue;
+            }
+            Metrics::connection_opened();
+            self.connections
+                .insert(connection_id, Connection::new(stream));
         }
     }
 
-    /// Handles an HTTP connection
+    /// Updates a connection, and acts on what it produced
+    ///
+    /// Arguments:
+    /// - **connection_id**: The token of the connection
+    /// - **update**: The function updating the connection, by reading from it, writing to it, or
+    ///   giving it a response
+    fn update_connection<F>(&mut self, connection_id: u64, update: F)
+    where
+        F: FnOnce(&mut Connection) -> ReadOutcome,
+    {
+        let Some(connection) = self.connections.get_mut(&connection_id) else {
+            // The connection was closed while a worker was handling its request
+            return;
+        };
+        let outcome = update(connection);
+        self.handle_read_outcome(connection_id, outcome);
+    }
+
+    /// Acts on what updating a connection produced
     ///
     /// Arguments:
-    /// - **stream**: a mutable TcpStream that represents a single HTTP request
+    /// - **connection_id**: The token of the connection
+    /// - **outcome**: The `ReadOutcome` of the update
     ///
-    /// This method reads the stream using a BufReader and uses that to construct a new `Request`, the
-    /// `Request`'s gets passed to `Router` which handles routing and returns a `Response`.  
-    /// The `Response` is converted to bytes and any errors are passed to the `ErrorHandler` which
-    /// handles them and produces an appropriate response and logs errors.  
-    /// The response bytes are then written to the `TcpStream`, ending the request. The `TcpStream`
-    /// is then flushed, to ensure the connection is closed, in the case of unexpected behavior.
-    fn handle_connection(mut stream: TcpStream) -> Result<(), String> {
-        let buf_reader = BufReader::new(&mut stream);
+    /// A request that arrived is handed to the thread pool, and one that couldn't be parsed is
+    /// answered with an error, after which the connection is closed, as it can't be trusted to be
+    /// at the start of a new request. Any chunks of a request body that arrived are handed to the
+    /// thread pool to be written. What the connection is waited on for is then updated to match its
+    /// new state, or it is removed if it was closed.
+    fn handle_read_outcome(&mut self, connection_id: u64, outcome: ReadOutcome) {
+        match outcome {
+            ReadOutcome::Pending => {}
+            ReadOutcome::Request(parser) => self.dispatch_request(connection_id, parser),
+            ReadOutcome::Invalid(app_error) => {
+                ErrorHandler::log_error(&app_error);
+                let response = ErrorHandler::map_error_to_handler(app_error);
+                let response_writer = Self::response_to_writer(response, "close");
+                self.update_connection(connection_id, |connection| {
+                    connection.respond(response_writer, false)
+                });
+            }
+        }
 
-        let map_error_to_response_bytes = |error| {
-            let error_response = ErrorHandler::map_error_to_handler(error);
+        if self
+            .connections
+            .get(&connection_id)
+            .is_some_and(|connection| !connection.is_closed())
+        {
+            self.drain_request_body(connection_id);
+        }
 
-            error_response
-                .to_bytes()
-                .expect("Failed to convert response to http headers")
+        let Some(connection) = self.connections.get_mut(&connection_id) else {
+            return;
         };
+        if connection.is_closed() {
+            self.close_connection(connection_id);
+            return;
+        }
+        if let Some(interest) = connection.take_interest_change()
+            && let Err(e) = self
+                .poller
+                .modify(connection.stream(), connection_id, interest)
+        {
+            log_error!("Error updating registration of stream: {}", e);
+            self.close_connection(connection_id);
+        }
+    }
 
-        let response_bytes = match Request::try_new(buf_reader) {
+    /// Hands a request that arrived on a connection to the thread pool
+    ///
+    /// Arguments:
+    /// - **connection_id**: The token of the connection
+    /// - **parser**: The `RequestParser` holding the request
+    ///
+    /// Queueing more requests than the workers can get to would only leave the clients waiting until
+    /// they time out, so when the job queue is full, the request is answered with a 503 status
+    /// instead, and the connection is closed.
+    fn dispatch_request(&mut self, connection_id: u64, parser: Box<RequestParser>) {
+        let requests_served = self.connections[&connection_id].requests_served();
+        let middleware_chain = Arc::clone(&self.middleware_chain);
+        let completion_sender = self.completion_sender.clone();
+        let waker = Arc::clone(&self.waker);
+        let is_queued = self.thread_pool.execute(move || {
+            let (response_writer, keep_alive) =
+                Server::handle_request(&middleware_chain, *parser, requests_served);
+            completion_sender
+                .send(Completion::Response {
+                    connection_id,
+                    response_writer,
+                    keep_alive,
+                })
+                .map_err(|_| "Server stopped before the response could be written".to_string())?;
+            waker.wake();
+            Ok(())
+        });
+
+        if !is_queued {
+            Metrics::request_rejected();
+            warn!("Job queue is full, rejecting request");
+            let response = ErrorHandler::handle_service_unavailable(OVERLOAD_RETRY_AFTER);
+            let response_writer = Self::response_to_writer(response, "close");
+            self.update_connection(connection_id, |connection| {
+                connection.respond(response_writer, false)
+            });
+        }
+    }
+
+    /// Hands the chunks of a request body that arrived on a connection to the thread pool, to be
+    /// written into the body of the request there, as that may mean writing to disk
+    ///
+    /// Arguments:
+    /// - **connection_id**: The token of the connection
+    ///
+    /// Nothing is handed over if a worker is already writing the chunks, as it writes every chunk
+    /// queued until there are none left. If the job queue is full, the chunks wait in the queue,
+    /// and are handed over again with the next ones, or by `drain_request_bodies()`.
+    fn drain_request_body(&mut self, connection_id: u64) {
+        let Some(body_drain) = self
+            .connections
+            .get(&connection_id)
+            .and_then(Connection::take_body_drain)
+        else {
+            return;
+        };
+
+        let completion_sender = self.completion_sender.clone();
+        let waker = Arc::clone(&self.waker);
+        self.thread_pool.execute(move || {
+            body_drain.run();
+            completion_sender
+                .send(Completion::BodyDrained { connection_id })
+                .map_err(|_| "Server stopped before a request body was written".to_string())?;
+            waker.wake();
+            Ok(())
+        });
+    }
+
+    /// Hands the chunks of request bodies that are still waiting to the thread pool, which is
+    /// checked periodically, as a connection whose body is backlogged isn't read from until its
+    /// chunks are written, so nothing else would hand them over
+    fn drain_request_bodies(&mut self) {
+        let connection_ids: Vec<u64> = self.connections.keys().copied().collect();
+        for connection_id in connection_ids {
+            self.drain_request_body(connection_id);
+        }
+    }
+
+    /// Closes a connection and removes it from the `Poller`
+    fn close_connection(&mut self, connection_id: u64) {
+        if let Some(mut connection) = self.connections.remove(&connection_id) {
+            let _ = self.poller.delete(connection.stream());
+            connection.close();
+            Metrics::connection_closed();
+        }
+    }
+
+    /// Closes every connection matching a predicate
+    fn close_connections<P>(&mut self, should_close: P)
+    where
+        P: Fn(&Connection) -> bool,
+    {
+        let connection_ids: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| should_close(connection))
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+        for connection_id in connection_ids {
+            self.close_connection(connection_id);
+        }
+    }
+
+    /// Deals with the connections that waited on their client for too long
+    ///
+    /// Arguments:
+    /// - **now**: The current time
+    ///
+    /// A request that didn't arrive in time is answered with a 408 status before its connection is
+    /// closed. Idle connections, and ones whose client stopped taking the response, are just closed.
+    fn close_timed_out_connections(&mut self, now: Instant) {
+        let timeouts: Vec<(u64, Timeout)> = self
+            .connections
+            .iter()
+            .filter_map(|(connection_id, connection)| {
+                connection
+                    .check_timeout(now)
+                    .map(|timeout| (*connection_id, timeout))
+            })
+            .collect();
+
+        for (connection_id, timeout) in timeouts {
+            match timeout {
+                Timeout::Idle => self.close_connection(connection_id),
+                Timeout::Request(app_error) => {
+                    self.handle_read_outcome(connection_id, ReadOutcome::Invalid(app_error))
+                }
+                Timeout::Response(app_error) => {
+                    ErrorHandler::log_error(&app_error);
+                    self.close_connection(connection_id);
+                }
+            }
+        }
+    }
+
+    /// Handles a request, turning a panic into an error
+    ///
+    /// Arguments:
+    /// - **middleware_chain**: The `MiddlewareChain` the request is handled with
+    /// - **parser**: The `RequestParser` holding the request
+    /// - **requests_served**: The number of requests that arrived on the connection, this one included
+    ///
+    /// This runs on a worker. A panic anywhere while extracting the body, or in a middleware or
+    /// handler, is caught and logged, and answered like any other unknown error, with a 500 status,
+    /// instead of leaving the connection without a response.
+    fn handle_request(
+        middleware_chain: &MiddlewareChain,
+        parser: RequestParser,
+        requests_served: usize,
+    ) -> (ResponseWriter, bool) {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            Self::respond(middleware_chain, parser, requests_served)
+        }))
+        .unwrap_or_else(|panic_payload| {
+            let app_error = AppError::Unknown(format!(
+                "Panic while handling request: {}",
+                get_panic_message(panic_payload.as_ref())
+            ));
+            ErrorHandler::log_error(&app_error);
+            let response = ErrorHandler::map_error_to_handler(app_error);
+            (Self::response_to_writer(response, "close"), false)
+        })
+    }
+
+    /// Produces the response to a request
+    ///
+    /// Arguments:
+    /// - **middleware_chain**: The `MiddlewareChain` the request is handled with
+    /// - **parser**: The `RequestParser` holding the request
+    /// - **requests_served**: The number of requests that arrived on the connection, this one included
+    ///
+    /// The body of the request is extracted, and the `Request` gets passed through the
+    /// `MiddlewareChain` to the `Router`, which handles routing and returns a `Response`.  
+    /// Any errors are passed to the `ErrorHandler` which produces an appropriate response, and the
+    /// `Response` is converted to the `ResponseWriter` that writes it.
+    ///
+    /// Returns whether the connection is kept alive afterwards, which it isn't if the client asked
+    /// for it to be closed, or sent `MAX_REQUESTS_PER_CONNECTION` requests on it, or if the server
+    /// is shutting down.
+    fn respond(
+        middleware_chain: &MiddlewareChain,
+        parser: RequestParser,
+        requests_served: usize,
+    ) -> (ResponseWriter, bool) {
+        let (response, keep_alive) = match parser.into_request() {
             Ok(request) => {
-                log!("{} {}", request.method, request.path);
-                let response: Result<Response, AppError> = Router::route_request(request);
-                match response {
-                    Ok(response) => response
-                        .to_bytes()
-                        .unwrap_or_else(map_error_to_response_bytes),
-                    Err(e) => map_error_to_response_bytes(e),
+                let keep_alive = request.is_keep_alive()
+                    && requests_served < MAX_REQUESTS_PER_CONNECTION
+                    && !signal::is_shutdown_requested();
+                let accepts_chunked = request.accepts_chunked();
+                let mut response = middleware_chain
+                    .handle(request)
+                    .unwrap_or_else(ErrorHandler::map_error_to_handler);
+                if !accepts_chunked {
+                    response.set_chunked(false);
                 }
+                (response, keep_alive)
+            }
+            // The body can't be trusted to have been read as the client meant it after a parsing
+            // error, so the connection is always closed
+            Err(app_error) => {
+                ErrorHandler::log_error(&app_error);
+                (ErrorHandler::map_error_to_handler(app_error), false)
             }
-            Err(app_error) => map_error_to_response_bytes(app_error),
         };
 
-        stream
-            .write_all(&response_bytes)
-            .map_err(|e| format!("Error writing response to stream: {}", e))?;
+        // A body that ends when the connection closes can't be followed by another response
+        let keep_alive = keep_alive && !response.is_close_delimited();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        (Self::response_to_writer(response, connection), keep_alive)
+    }
+
+    /// Converts a `Response` into the `ResponseWriter` that writes it to the `TcpStream`
+    ///
+    /// Arguments:
+    /// - **response**: The `Response` to be converted
+    /// - **connection**: The value of the *Connection* header, telling the client whether the
+    ///   connection stays open after this response
+    ///
+    /// If the conversion fails, the error is passed to the `ErrorHandler` and its response is
+    /// converted instead.
+    fn response_to_writer(mut response: Response, connection: &str) -> ResponseWriter {
+        response.set_header(HttpHeader::CONNECTION, connection);
+
+        response.into_writer().unwrap_or_else(|error| {
+            ErrorHandler::log_error(&error);
+            let mut error_response = ErrorHandler::map_error_to_handler(error);
+            error_response.set_header(HttpHeader::CONNECTION, connection);
+
+            error_response
+                .into_writer()
+                .expect("Failed to convert response to http headers")
+        })
+    }
+}
 
-        stream
-            .shutdown(Shutdown::Both)
-            .map_err(|e| format!("Error shutting down stream: {}", e))?;
-        Ok(())
+/// Gets the message a panic was started with, from the payload `catch_unwind` returns
+fn get_panic_message(panic_payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic_payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic_payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Unknown panic"
     }
 }
 
@@ -184,38 +760,242 @@ impl Server {
 ///
 /// This is required for the binary to be self-sufficient.
 fn ensure_uploads_dir() {
-    let uploads_path = Path::new("uploads");
+    let uploads_dir = &Config::get().uploads_dir;
+    let uploads_path = Path::new(uploads_dir);
     if !uploads_path.exists() {
         log!("Uploads directory does not exist, and is being created");
-        fs::create_dir(uploads_path).expect("Failed to create uploads directory");
+        fs::create_dir_all(uploads_path).expect("Failed to create uploads directory");
+    }
+    if let Err(AppError::IO(e)) = FileManager::remove_temp_files(uploads_dir) {
+        warn!("Failed to clean up temporary uploads: {}", e);
     }
 }
 
 struct Locks {
     create_file: Mutex<()>,
-    append_log: Mutex<()>,
+    append_log: Mutex<Option<File>>,
 }
 
 static LOCKS: LazyLock<Locks> = LazyLock::new(|| Locks {
     create_file: Mutex::new(()),
-    append_log: Mutex::new(()),
+    append_log: Mutex::new(None),
 });
 
 fn main() {
-    let server = Server::new("localhost:7878", 4);
-    log!("Server started and running on port 7878");
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", Config::USAGE);
+        return;
+    }
+    match Config::load(args, |name| env::var(name).ok()) {
+        Ok(config) => Config::init(config),
+        Err(AppError::Invalid(error)) | Err(AppError::IO(error)) => {
+            eprintln!("Invalid configuration:\n{error}\n\nRun with --help to see the options");
+            process::exit(2);
+        }
+        Err(error) => {
+            eprintln!("Failed to load configuration: {error:?}");
+            process::exit(2);
+        }
+    }
+    let config = Config::get();
+    match Templates::init() {
+        Ok(()) => {}
+        Err(AppError::Invalid(error)) => {
+            eprintln!("Invalid templates:\n{error}");
+            process::exit(2);
+        }
+        Err(error) => {
+            eprintln!("Failed to compile templates: {error:?}");
+            process::exit(2);
+        }
+    }
+
+    signal::install_shutdown_handler();
+    let server = Server::new(&config.address, config.workers, config.queue_size);
+    log!("Server started and running on {}", config.address);
     ensure_uploads_dir();
 
-    for stream in server.listener.incoming() {
-        let stream = match stream {
-            Ok(stream) => stream,
-            Err(e) => {
-                log_error!("Encountered error getting stream {}", e);
-                continue;
+    server.run();
+
+    log!("Server stopped");
+    if let Err(AppError::IO(e)) = FileManager::flush_log_file() {
+        eprintln!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::MAX_REQUESTS_PER_CONNECTION;
+    use crate::http::{RequestParser, Response, ResponseBody};
+    use crate::middleware::MiddlewareChain;
+    use crate::router::Router;
+    use crate::{Connection, FIRST_CONNECTION_TOKEN, Server, ThreadPool, Worker};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Parses a whole request
+    fn parse(request: &str) -> RequestParser {
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut request.as_bytes().to_vec()).unwrap());
+        parser
+    }
+
+    #[test]
+    fn keep_connection_alive_until_closed_or_capped() {
+        let middleware_chain = MiddlewareChain::new(Router::new().get("/", |_, _| {
+            Ok(Response::builder()
+                .body(ResponseBody::Text("index".to_string()))
+                .build())
+        }));
+        let respond = |request: &str, requests_served| {
+            let (mut response_writer, keep_alive) =
+                Server::respond(&middleware_chain, parse(request), requests_served);
+            let mut written = Vec::new();
+            assert!(response_writer.write_some(&mut written).unwrap());
+            (String::from_utf8(written).unwrap(), keep_alive)
+        };
+
+        let (response, keep_alive) = respond("GET / HTTP/1.1\r\n\r\n", 1);
+        assert!(keep_alive);
+        assert!(response.contains("Connection: keep-alive\r\n"));
+
+        let (response, keep_alive) = respond("GET / HTTP/1.1\r\nConnection: close\r\n\r\n", 1);
+        assert!(!keep_alive);
+        assert!(response.contains("Connection: close\r\n"));
+
+        // HTTP/1.0 connections are only kept alive when the client asks for it
+        assert!(!respond("GET / HTTP/1.0\r\n\r\n", 1).1);
+        assert!(respond("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", 1).1);
+
+        assert!(respond("GET / HTTP/1.1\r\n\r\n", MAX_REQUESTS_PER_CONNECTION - 1).1);
+        let (response, keep_alive) = respond("GET / HTTP/1.1\r\n\r\n", MAX_REQUESTS_PER_CONNECTION);
+        assert!(!keep_alive);
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn reject_requests_while_queue_is_full() {
+        let mut server = Server::new("127.0.0.1:0", 1, 1);
+        let address = server.listener.as_ref().unwrap().local_addr().unwrap();
+
+        // One job keeps the only worker busy, and another fills the queue behind it, which it only
+        // fits in once the first one was taken off
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let release_receiver = Arc::new(Mutex::new(release_receiver));
+        let blocking_job = || {
+            let started_sender = started_sender.clone();
+            let release_receiver = Arc::clone(&release_receiver);
+            move || {
+                let _ = started_sender.send(());
+                let _ = release_receiver.lock().unwrap().recv();
+                Ok(())
             }
         };
-        server
-            .thread_pool
-            .execute(move || Server::handle_connection(stream));
+        assert!(server.thread_pool.execute(blocking_job()));
+        started_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert!(server.thread_pool.execute(blocking_job()));
+        assert!(!server.thread_pool.execute(|| Ok(())));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        server.accept_connections();
+        let connection_id = FIRST_CONNECTION_TOKEN;
+        for _ in 0..200 {
+            server.update_connection(connection_id, Connection::read);
+            if !server.connections.contains_key(&connection_id) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The connection is closed once the rejection is written
+        assert!(!server.connections.contains_key(&connection_id));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert!(response.contains("Retry-After: 5\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+
+        drop(release_sender);
+        assert_eq!(server.thread_pool.shutdown(Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn keep_running_jobs_after_a_panic() {
+        let mut thread_pool = ThreadPool::new(1, 4);
+        let (sender, receiver) = mpsc::channel();
+        assert!(thread_pool.execute(|| panic!("Job failed")));
+        assert!(thread_pool.execute(move || sender.send(()).map_err(|e| e.to_string())));
+
+        // The only worker caught the panic, and is still there to run the next job
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(thread_pool.shutdown(Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn respawn_dead_workers_before_running_jobs() {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let dead_worker = Worker {
+            id: 0,
+            thread: thread::spawn(|| {}),
+        };
+        while !dead_worker.thread.is_finished() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut thread_pool = ThreadPool {
+            workers: vec![dead_worker],
+            sender: Some(sender),
+            receiver: Arc::new(Mutex::new(receiver)),
+        };
+
+        let (job_sender, job_receiver) = mpsc::channel();
+        assert!(thread_pool.execute(move || job_sender.send(()).map_err(|e| e.to_string())));
+        assert!(job_receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(!thread_pool.workers[0].thread.is_finished());
+        assert_eq!(thread_pool.shutdown(Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn shut_down_thread_pool_after_draining_jobs() {
+        let mut thread_pool = ThreadPool::new(2, 8);
+        let (sender, receiver) = mpsc::channel();
+        for job in 0..6 {
+            let sender = sender.clone();
+            assert!(thread_pool.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                sender.send(job).map_err(|e| e.to_string())
+            }));
+        }
+
+        // Every job sent before the shutdown is run, even those still waiting for a worker
+        assert_eq!(thread_pool.shutdown(Duration::from_secs(10)), 0);
+        let mut finished_jobs = receiver.try_iter().collect::<Vec<_>>();
+        finished_jobs.sort();
+        assert_eq!(finished_jobs, [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn stop_waiting_for_jobs_after_shutdown_timeout() {
+        let mut thread_pool = ThreadPool::new(2, 8);
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        assert!(thread_pool.execute(move || {
+            let _ = release_receiver.recv();
+            Ok(())
+        }));
+
+        let shutdown_started = Instant::now();
+        assert_eq!(thread_pool.shutdown(Duration::from_millis(200)), 1);
+        let shutdown_duration = shutdown_started.elapsed();
+        assert!(shutdown_duration >= Duration::from_millis(200));
+        assert!(shutdown_duration < Duration::from_secs(5));
+
+        drop(release_sender);
     }
 }
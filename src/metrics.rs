@@ -0,0 +1,110 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The number of requests waiting in the job queue for a worker
+static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// The number of requests the job queue can hold
+static QUEUE_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+/// The number of requests handed to the workers since the server started
+static QUEUED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// The number of requests answered with 503 because the job queue was full
+static REJECTED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// The number of connections accepted since the server started
+static ACCEPTED_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+/// The number of connections currently open, idle ones included
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// `Metrics` are counters about the load on the server, kept for monitoring.
+/// They are updated by the `ThreadPool` and the event loop, and can be read at any time from any
+/// thread, which is what the `/metrics` route does.
+pub(crate) struct Metrics;
+
+impl Metrics {
+    /// Sets the capacity of the job queue, once it is created
+    pub(crate) fn set_queue_capacity(capacity: usize) {
+        QUEUE_CAPACITY.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Records that a request was added to the job queue
+    pub(crate) fn request_queued() {
+        QUEUE_DEPTH.fetch_add(1, Ordering::SeqCst);
+        QUEUED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes back `request_queued()` for a request that didn't fit in the job queue after all
+    pub(crate) fn request_not_queued() {
+        QUEUE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+        QUEUED_REQUESTS.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Records that a worker took a request from the job queue
+    pub(crate) fn request_dequeued() {
+        QUEUE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Records that a request was rejected because the job queue was full
+    pub(crate) fn request_rejected() {
+        REJECTED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a connection was accepted
+    pub(crate) fn connection_opened() {
+        ACCEPTED_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a connection was closed
+    pub(crate) fn connection_closed() {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text format, with a `name value` line per metric
+    pub(crate) fn render() -> String {
+        let metrics = [
+            (
+                "web_server_queue_depth",
+                "gauge",
+                "Requests waiting for a worker",
+                QUEUE_DEPTH.load(Ordering::SeqCst) as u64,
+            ),
+            (
+                "web_server_queue_capacity",
+                "gauge",
+                "Requests the job queue can hold",
+                QUEUE_CAPACITY.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "web_server_requests_queued_total",
+                "counter",
+                "Requests handed to the workers",
+                QUEUED_REQUESTS.load(Ordering::Relaxed),
+            ),
+            (
+                "web_server_requests_rejected_total",
+                "counter",
+                "Requests rejected with 503 because the job queue was full",
+                REJECTED_REQUESTS.load(Ordering::Relaxed),
+            ),
+            (
+                "web_server_connections_accepted_total",
+                "counter",
+                "Connections accepted",
+                ACCEPTED_CONNECTIONS.load(Ordering::Relaxed),
+            ),
+            (
+                "web_server_open_connections",
+                "gauge",
+                "Connections currently open, idle ones included",
+                OPEN_CONNECTIONS.load(Ordering::Relaxed) as u64,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} {kind}");
+            let _ = writeln!(output, "{name} {value}");
+        }
+        output
+    }
+}
@@ -0,0 +1,199 @@
+use crate::common::{AppError, FileManager, Time};
+use crate::handlers::ErrorHandler;
+use crate::http::{Request, Response};
+use crate::log;
+use crate::router::Router;
+
+/// A `Middleware` is a layer around the `Router`, for logic that applies to every request.
+/// It gets each `Request` before it is routed, and can either answer it itself, short-circuiting
+/// the rest of the chain, or pass it on with `Next::run()` and change the `Response` that comes back.
+pub(crate) trait Middleware: Send + Sync {
+    /// Handles a request
+    ///
+    /// Arguments:
+    /// - **request**: The `Request` being handled
+    /// - **next**: The rest of the chain, which the request is passed to with `Next::run()`
+    fn handle(&self, request: Request, next: Next) -> Result<Response, AppError>;
+}
+
+/// `Next` is the rest of a `MiddlewareChain` after the current middleware, ending with the `Router`
+pub(crate) struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl Next<'_> {
+    /// Passes a request to the next middleware in the chain, or to the `Router` if there are none left
+    pub(crate) fn run(self, request: Request) -> Result<Response, AppError> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => middleware.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    router: self.router,
+                },
+            ),
+            None => self.router.route_request(request),
+        }
+    }
+}
+
+/// A `MiddlewareChain` is the `Router` wrapped in layers of `Middleware`, configured at startup.
+/// Middleware is run in the order it is added, so the first one added is the outermost layer, seeing
+/// the request first and the response last.
+pub(crate) struct MiddlewareChain {
+    middleware: Vec<Box<dyn Middleware>>,
+    router: Router,
+}
+
+impl MiddlewareChain {
+    /// Creates a new `MiddlewareChain` with no middleware around the `Router`
+    pub(crate) fn new(router: Router) -> Self {
+        MiddlewareChain {
+            middleware: Vec::new(),
+            router,
+        }
+    }
+
+    /// Adds a `Middleware` inside the layers already in the chain
+    pub(crate) fn with<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Handles a request by running it through the whole chain
+    pub(crate) fn handle(&self, request: Request) -> Result<Response, AppError> {
+        Next {
+            middleware: &self.middleware,
+            router: &self.router,
+        }
+        .run(request)
+    }
+}
+
+/// Logs the method and path of every request
+pub(crate) struct RequestLogger;
+
+impl Middleware for RequestLogger {
+    fn handle(&self, request: Request, next: Next) -> Result<Response, AppError> {
+        log!("{} {}", request.method, request.path);
+        next.run(request)
+    }
+}
+
+/// Logs every error returned by the rest of the chain, leaving the error itself for the server to
+/// answer with an error response
+pub(crate) struct ErrorLogger;
+
+impl Middleware for ErrorLogger {
+    fn handle(&self, request: Request, next: Next) -> Result<Response, AppError> {
+        let result = next.run(request);
+        if let Err(app_error) = &result {
+            ErrorHandler::log_error(app_error);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::AppError;
+    use crate::http::{HttpStatus, Request, RequestParser, Response};
+    use crate::middleware::{Middleware, MiddlewareChain, Next};
+    use crate::router::Router;
+    use std::sync::{Arc, Mutex};
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// Records when a request passes through it on the way in and on the way out
+    struct Recorder(&'static str, Calls);
+
+    impl Middleware for Recorder {
+        fn handle(&self, request: Request, next: Next) -> Result<Response, AppError> {
+            self.1.lock().unwrap().push(format!("{} in", self.0));
+            let response = next.run(request);
+            self.1.lock().unwrap().push(format!("{} out", self.0));
+            response
+        }
+    }
+
+    /// Answers every request itself, without passing it on
+    struct Forbid;
+
+    impl Middleware for Forbid {
+        fn handle(&self, _: Request, _: Next) -> Result<Response, AppError> {
+            Ok(Response::builder().status(HttpStatus::Forbidden).build())
+        }
+    }
+
+    /// Adds a header to every response coming back from the rest of the chain
+    struct Tag;
+
+    impl Middleware for Tag {
+        fn handle(&self, request: Request, next: Next) -> Result<Response, AppError> {
+            let mut response = next.run(request)?;
+            response.set_header("X-Tag", "tagged");
+            Ok(response)
+        }
+    }
+
+    fn recording_router(calls: &Calls) -> Router {
+        let calls = Arc::clone(calls);
+        Router::new().get("/", move |_, _| {
+            calls.lock().unwrap().push("router".to_string());
+            Ok(Response::builder().status(HttpStatus::NoContent).build())
+        })
+    }
+
+    fn handle(chain: &MiddlewareChain) -> String {
+        let mut buffer = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).unwrap());
+
+        let response = chain.handle(parser.into_request().unwrap()).unwrap();
+        let mut written = Vec::new();
+        response
+            .into_writer()
+            .unwrap()
+            .write_some(&mut written)
+            .unwrap();
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn run_middleware_in_order_and_unwind_in_reverse() {
+        let calls = Calls::default();
+        let chain = MiddlewareChain::new(recording_router(&calls))
+            .with(Recorder("outer", Arc::clone(&calls)))
+            .with(Recorder("inner", Arc::clone(&calls)));
+
+        assert!(handle(&chain).starts_with("HTTP/1.1 204 "));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["outer in", "inner in", "router", "inner out", "outer out"]
+        );
+    }
+
+    #[test]
+    fn stop_the_chain_when_middleware_answers() {
+        let calls = Calls::default();
+        let chain = MiddlewareChain::new(recording_router(&calls))
+            .with(Recorder("outer", Arc::clone(&calls)))
+            .with(Forbid)
+            .with(Recorder("inner", Arc::clone(&calls)));
+
+        assert!(handle(&chain).starts_with("HTTP/1.1 403 "));
+        assert_eq!(*calls.lock().unwrap(), ["outer in", "outer out"]);
+    }
+
+    #[test]
+    fn change_responses_on_the_way_out() {
+        let calls = Calls::default();
+        let chain = MiddlewareChain::new(recording_router(&calls)).with(Tag);
+
+        let response = handle(&chain);
+        assert!(response.starts_with("HTTP/1.1 204 "));
+        assert!(response.contains("X-Tag: tagged\r\n"));
+        assert_eq!(*calls.lock().unwrap(), ["router"]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("The server uses epoll to wait on its connections, so it only runs on Linux");
+
+const EPOLL_CLOEXEC: i32 = 0o2000000;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLL_CTL_MOD: i32 = 3;
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLERR: u32 = 0x008;
+const EPOLLHUP: u32 = 0x010;
+
+/// The most events taken from the kernel in a single `Poller::wait()`
+const MAX_EVENTS: usize = 1024;
+
+/// The `epoll_event` struct of the kernel, which is packed on x86-64 only
+#[repr(C)]
+#[cfg_attr(target_arch = "x86_64", repr(packed))]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+unsafe extern "C" {
+    fn epoll_create1(flags: i32) -> i32;
+    fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut EpollEvent) -> i32;
+    fn epoll_wait(epfd: i32, events: *mut EpollEvent, max_events: i32, timeout: i32) -> i32;
+    fn close(fd: i32) -> i32;
+}
+
+/// What a registered file descriptor is waited on for
+/// - **Read**: Until there is something to read, or a connection to accept
+/// - **Write**: Until it can be written to without blocking
+/// - **Nothing**: Only until it is closed or fails, which is always reported
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Interest {
+    Read,
+    Write,
+    Nothing,
+}
+
+impl Interest {
+    fn to_epoll_events(self) -> u32 {
+        match self {
+            Interest::Read => EPOLLIN,
+            Interest::Write => EPOLLOUT,
+            Interest::Nothing => 0,
+        }
+    }
+}
+
+/// An `Event` is a registered file descriptor that became ready, identified by the token it was
+/// registered with
+pub(crate) struct Event {
+    pub(crate) token: u64,
+    pub(crate) readable: bool,
+    pub(crate) writable: bool,
+    /// The connection was closed or failed, so it can't be read from or written to anymore
+    pub(crate) closed: bool,
+}
+
+/// A `Poller` waits on many file descriptors at once with epoll, so that a single thread can
+/// handle every connection, only reading from and writing to the ones that are ready.
+/// File descriptors are registered with a token, which is how the events they get are told apart,
+/// and are waited on level-triggered, so a file descriptor that is still ready is reported again
+/// by the next `wait()`.
+pub(crate) struct Poller {
+    epoll_fd: RawFd,
+}
+
+impl Poller {
+    /// Creates a new `Poller` with nothing registered
+    pub(crate) fn new() -> io::Result<Poller> {
+        // SAFETY: `epoll_create1` takes no pointers, and its result is checked
+        let epoll_fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if epoll_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Poller { epoll_fd })
+    }
+
+    /// Registers a file descriptor
+    ///
+    /// Arguments:
+    /// - **source**: The file descriptor, usually a socket
+    /// - **token**: The token that events of the file descriptor are reported with
+    /// - **interest**: What the file descriptor is waited on for
+    pub(crate) fn add(
+        &self,
+        source: &impl AsRawFd,
+        token: u64,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.control(EPOLL_CTL_ADD, source.as_raw_fd(), token, interest)
+    }
+
+    /// Changes what a registered file descriptor is waited on for
+    pub(crate) fn modify(
+        &self,
+        source: &impl AsRawFd,
+        token: u64,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.control(EPOLL_CTL_MOD, source.as_raw_fd(), token, interest)
+    }
+
+    /// Stops waiting on a file descriptor, which must be done before closing it
+    pub(crate) fn delete(&self, source: &impl AsRawFd) -> io::Result<()> {
+        self.control(EPOLL_CTL_DEL, source.as_raw_fd(), 0, Interest::Nothing)
+    }
+
+    fn control(&self, operation: i32, fd: RawFd, token: u64, interest: Interest) -> io::Result<()> {
+        let mut event = EpollEvent {
+            events: interest.to_epoll_events(),
+            data: token,
+        };
+        // SAFETY: `event` is a valid `epoll_event` for the duration of the call, which the kernel
+        // only reads from
+        if unsafe { epoll_ctl(self.epoll_fd, operation, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits until at least one registered file descriptor is ready, or the timeout runs out
+    ///
+    /// Arguments:
+    /// - **events**: The `Event`s that are ready are put in here, replacing what it held
+    /// - **timeout**: The longest time to wait
+    ///
+    /// Being interrupted by a signal isn't an error, and returns with no events, so that the caller
+    /// can check what the signal asked for.
+    pub(crate) fn wait(&self, events: &mut Vec<Event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        let mut epoll_events = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+        // SAFETY: `epoll_events` has room for the `MAX_EVENTS` events the kernel may write to it
+        let ready = unsafe {
+            epoll_wait(
+                self.epoll_fd,
+                epoll_events.as_mut_ptr(),
+                MAX_EVENTS as i32,
+                timeout,
+            )
+        };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(error);
+        }
+
+        events.extend(epoll_events[..ready as usize].iter().map(|epoll_event| {
+            let flags = epoll_event.events;
+            Event {
+                token: epoll_event.data,
+                readable: flags & EPOLLIN != 0,
+                writable: flags & EPOLLOUT != 0,
+                closed: flags & (EPOLLERR | EPOLLHUP) != 0,
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        // SAFETY: the file descriptor is owned by the `Poller` and closed only once
+        unsafe {
+            close(self.epoll_fd);
+        }
+    }
+}
+
+/// A `Waker` wakes a `Poller` up from another thread, by writing to a socket registered with it
+pub(crate) struct Waker {
+    sender: UnixStream,
+    receiver: UnixStream,
+}
+
+impl Waker {
+    /// Creates a new `Waker`, whose receiving end still has to be registered with a `Poller`
+    pub(crate) fn new() -> io::Result<Waker> {
+        let (sender, receiver) = UnixStream::pair()?;
+        sender.set_nonblocking(true)?;
+        receiver.set_nonblocking(true)?;
+        Ok(Waker { sender, receiver })
+    }
+
+    /// Makes the `Poller` the `Waker` is registered with report it as readable
+    ///
+    /// A full socket means a wake up is already waiting to be seen, so that isn't an error.
+    pub(crate) fn wake(&self) {
+        let _ = (&self.sender).write(&[1]);
+    }
+
+    /// Reads every pending wake up, so that the `Waker` isn't reported again until the next one
+    pub(crate) fn reset(&self) {
+        let mut buffer = [0u8; 64];
+        while matches!((&self.receiver).read(&mut buffer), Ok(bytes_read) if bytes_read > 0) {}
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.receiver.as_raw_fd()
+    }
+}
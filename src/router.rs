@@ -0,0 +1,403 @@
+use crate::common::AppError;
+use crate::handlers::ErrorHandler;
+use crate::http::{HttpHeader, HttpMethod, Request, Response};
+
+/// A `Handler` is any function a request can be routed to, receiving the `Request` and the
+/// `RouteParams` extracted from its path
+pub(crate) type Handler =
+    Box<dyn Fn(Request, RouteParams) -> Result<Response, AppError> + Send + Sync + 'static>;
+
+/// A single segment of a route pattern, between two slashes
+/// - **Literal**: Matches a path segment that is exactly the same
+/// - **Param**: Written as `:name`, matches any single non-empty path segment
+/// - **Wildcard**: Written as `*name`, matches the rest of the path, slashes included, and can only
+///   be the last segment
+#[derive(Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+/// A `Route` is a method and path pattern, and the handler that requests matching them are sent to
+struct Route {
+    method: HttpMethod,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+/// `RouteParams` are the values of the params and wildcard of a route pattern, taken from the path
+/// of the request that matched it
+#[derive(Default, Debug)]
+pub(crate) struct RouteParams(Vec<(String, String)>);
+
+impl RouteParams {
+    /// Gets the value of a param by the name it has in the route pattern
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(param_name, _)| param_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A `Router` is a table of routes that requests are matched against to find their handler.
+/// Routes are registered with a method, a path pattern and a handler, and are matched in the order
+/// they were registered in.
+///
+/// Path patterns are made of segments separated by slashes, where a segment starting with `:` is a
+/// param matching any single segment, and a last segment starting with `*` is a wildcard matching
+/// the rest of the path, e.g. `/files/:name` or `/uploads/*path`.
+/// Routes sharing a prefix can be registered together with `group()`, and a whole `Router` can be
+/// mounted under a prefix of another with `mount()`.
+#[derive(Default)]
+pub(crate) struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// Creates a new `Router` with no routes
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a route
+    ///
+    /// Arguments:
+    /// - **method**: The `HttpMethod` of the route
+    /// - **pattern**: The path pattern of the route
+    /// - **handler**: The function requests matching the route are sent to
+    ///
+    /// Panics if a wildcard is not the last segment of the pattern, as routes are registered at
+    /// startup, so this is a mistake in the route table.
+    pub(crate) fn route<H>(mut self, method: HttpMethod, pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Request, RouteParams) -> Result<Response, AppError> + Send + Sync + 'static,
+    {
+        let segments = Self::parse_pattern(pattern);
+        let wildcard_position = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)));
+        if let Some(position) = wildcard_position {
+            assert_eq!(
+                position,
+                segments.len() - 1,
+                "Wildcard must be the last segment of route pattern: {pattern}"
+            );
+        }
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Registers a GET route, which also handles HEAD requests
+    pub(crate) fn get<H>(self, pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Request, RouteParams) -> Result<Response, AppError> + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Get, pattern, handler)
+    }
+
+    /// Registers a POST route
+    pub(crate) fn post<H>(self, pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Request, RouteParams) -> Result<Response, AppError> + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Post, pattern, handler)
+    }
+
+    /// Registers a DELETE route
+    pub(crate) fn delete<H>(self, pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Request, RouteParams) -> Result<Response, AppError> + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Delete, pattern, handler)
+    }
+
+    /// Registers a group of routes that share a path prefix
+    ///
+    /// Arguments:
+    /// - **prefix**: The path prefix of every route in the group
+    /// - **build_group**: A function registering the routes of the group on the `Router` it is given,
+    ///   with patterns relative to the prefix
+    pub(crate) fn group<F>(self, prefix: &str, build_group: F) -> Self
+    where
+        F: FnOnce(Router) -> Router,
+    {
+        self.mount(prefix, build_group(Router::new()))
+    }
+
+    /// Mounts the routes of another `Router` under a path prefix
+    ///
+    /// Arguments:
+    /// - **prefix**: The path prefix the routes are mounted under
+    /// - **router**: The `Router` whose routes are mounted
+    ///
+    /// The prefix is put in front of the pattern of every route, with a route for `/` becoming a
+    /// route for the prefix itself.
+    pub(crate) fn mount(mut self, prefix: &str, router: Router) -> Self {
+        let prefix_segments = Self::parse_pattern(prefix);
+
+        for mut route in router.routes {
+            let is_root =
+                matches!(route.segments.as_slice(), [Segment::Literal(s)] if s.is_empty());
+            if is_root {
+                route.segments = prefix_segments.clone();
+            } else if !matches!(prefix_segments.as_slice(), [Segment::Literal(s)] if s.is_empty()) {
+                route.segments = [prefix_segments.clone(), route.segments].concat();
+            }
+            self.routes.push(route);
+        }
+        self
+    }
+
+    /// Routes a request to its appropriate handler
+    ///
+    /// Arguments:
+    /// - **request**: A `Request` to route to a possible handler
+    ///
+    /// The method and path are matched against the routes, ignoring the query, and the handler of
+    /// the first matching route is called with the params extracted from the path, and the response
+    /// is returned.
+    /// A HEAD request is handled by the handler of the GET route for the path, with the body left out
+    /// of the response. An OPTIONS request is answered with an *Allow* header listing the methods
+    /// the path supports. A path that exists, but doesn't support the method, gets a 405 status with
+    /// the same *Allow* header, while a path that doesn't exist gets a 404 status.
+    pub(crate) fn route_request(&self, request: Request) -> Result<Response, AppError> {
+        let is_head = request.method == HttpMethod::Head;
+        let route_method = if is_head {
+            &HttpMethod::Get
+        } else {
+            &request.method
+        };
+
+        let matching_route = self.routes.iter().find_map(|route| {
+            if &route.method != route_method {
+                return None;
+            }
+            Self::match_path(&route.segments, request.path.path()).map(|params| (route, params))
+        });
+        if let Some((route, params)) = matching_route {
+            let mut response = (route.handler)(request, params)?;
+            if is_head {
+                response.set_head_only();
+            }
+            return Ok(response);
+        }
+
+        let allowed_methods = self.get_allowed_methods(request.path.path());
+        if allowed_methods.is_empty() {
+            return Ok(ErrorHandler::handle_invalid_page_request(
+                request.method,
+                request.path.path().to_string(),
+            ));
+        }
+
+        if request.method == HttpMethod::Options {
+            return Ok(Response::builder()
+                .header(HttpHeader::ALLOW, &allowed_methods)
+                .build());
+        }
+        Ok(ErrorHandler::handle_method_not_allowed(
+            request.method,
+            request.path.path().to_string(),
+            &allowed_methods,
+        ))
+    }
+
+    /// Gets the methods supported by a path, in the format of an *Allow* header
+    ///
+    /// Arguments:
+    /// - **path**: The request path, where `*` stands for the server as a whole
+    ///
+    /// The methods of every route matching the path are collected, with HEAD following GET, as every
+    /// GET route also handles HEAD. OPTIONS is added at the end if any route matched, as it is
+    /// supported by every path that exists. An empty string is returned if no route matched.
+    fn get_allowed_methods(&self, path: &str) -> String {
+        let mut methods = Vec::new();
+        let matching_routes = self
+            .routes
+            .iter()
+            .filter(|route| path == "*" || Self::match_path(&route.segments, path).is_some());
+
+        for route in matching_routes {
+            if methods.contains(&&route.method) {
+                continue;
+            }
+            methods.push(&route.method);
+            if route.method == HttpMethod::Get {
+                methods.push(&HttpMethod::Head);
+            }
+        }
+        if !methods.is_empty() {
+            methods.push(&HttpMethod::Options);
+        }
+
+        methods
+            .iter()
+            .map(|method| method.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Splits a path pattern into its segments
+    fn parse_pattern(pattern: &str) -> Vec<Segment> {
+        pattern
+            .strip_prefix('/')
+            .unwrap_or(pattern)
+            .split('/')
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect()
+    }
+
+    /// Matches a request path against the segments of a route pattern
+    ///
+    /// Arguments:
+    /// - **segments**: The segments of the route pattern
+    /// - **path**: The request path
+    ///
+    /// The path is split the same way as patterns are, and each of its segments is compared with the
+    /// segment of the pattern in the same position. Param values are collected on the way, and a
+    /// wildcard collects the rest of the path.
+    /// The `RouteParams` are returned if the whole path matched, and `None` otherwise.
+    fn match_path(segments: &[Segment], path: &str) -> Option<RouteParams> {
+        let mut params = Vec::new();
+        let mut path_segments = path.strip_prefix('/')?.splitn(segments.len(), '/');
+
+        for segment in segments {
+            let path_segment = path_segments.next()?;
+            match segment {
+                Segment::Literal(literal) if literal == path_segment => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) if !path_segment.is_empty() && !path_segment.contains('/') => {
+                    params.push((name.clone(), path_segment.to_string()));
+                }
+                Segment::Param(_) => return None,
+                Segment::Wildcard(name) => params.push((name.clone(), path_segment.to_string())),
+            }
+        }
+
+        Some(RouteParams(params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{HttpHeader, RequestParser, Response, ResponseBody};
+    use crate::router::{RouteParams, Router, Segment};
+
+    fn files_router() -> Router {
+        Router::new()
+            .get("/files", |_, _| {
+                Ok(Response::builder()
+                    .header(HttpHeader::CONTENT_TYPE, "text/plain")
+                    .body(ResponseBody::Text("a.txt".to_string()))
+                    .build())
+            })
+            .post("/files", |_, _| Ok(Response::builder().build()))
+    }
+
+    fn route(router: &Router, request_line: &str) -> String {
+        let mut buffer = format!("{request_line}\r\nHost: localhost\r\n\r\n").into_bytes();
+        let mut parser = RequestParser::new();
+        assert!(parser.parse(&mut buffer).unwrap());
+
+        let response = router
+            .route_request(parser.into_request().unwrap())
+            .unwrap();
+        let mut written = Vec::new();
+        response
+            .into_writer()
+            .unwrap()
+            .write_some(&mut written)
+            .unwrap();
+        String::from_utf8(written).unwrap()
+    }
+
+    fn match_pattern(pattern: &str, path: &str) -> Option<RouteParams> {
+        Router::match_path(&Router::parse_pattern(pattern), path)
+    }
+
+    #[test]
+    fn match_route_patterns() {
+        assert!(match_pattern("/", "/").is_some());
+        assert!(match_pattern("/", "/upload").is_none());
+        assert!(match_pattern("/upload", "/upload/").is_none());
+
+        let params = match_pattern("/files/:name", "/files/report.pdf").unwrap();
+        assert_eq!(params.get("name"), Some("report.pdf"));
+        assert!(match_pattern("/files/:name", "/files/").is_none());
+        assert!(match_pattern("/files/:name", "/files/a/b").is_none());
+
+        let params = match_pattern("/uploads/*path", "/uploads/docs/a.txt").unwrap();
+        assert_eq!(params.get("path"), Some("docs/a.txt"));
+        assert!(match_pattern("/uploads/*path", "/uploads").is_none());
+    }
+
+    #[test]
+    fn mount_prefixes_routes() {
+        let router = Router::new().group("/api", |group| {
+            group
+                .get("/", |_, _| unreachable!())
+                .get("/files/:name", |_, _| unreachable!())
+        });
+
+        let patterns: Vec<Vec<String>> = router
+            .routes
+            .iter()
+            .map(|route| {
+                route
+                    .segments
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Literal(literal) => literal.clone(),
+                        Segment::Param(name) => format!(":{name}"),
+                        Segment::Wildcard(name) => format!("*{name}"),
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(patterns, [vec!["api"], vec!["api", "files", ":name"]]);
+    }
+
+    #[test]
+    fn answer_head_requests_with_the_get_headers_only() {
+        let router = files_router();
+        let get = route(&router, "GET /files HTTP/1.1");
+        let head = route(&router, "HEAD /files HTTP/1.1");
+
+        assert!(get.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get.contains("Content-Length: 5\r\n"));
+        assert!(get.ends_with("\r\n\r\na.txt"));
+        assert_eq!(head, get.strip_suffix("a.txt").unwrap());
+    }
+
+    #[test]
+    fn list_allowed_methods_of_known_paths_only() {
+        let router = files_router();
+
+        let options = route(&router, "OPTIONS /files HTTP/1.1");
+        assert!(options.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(options.contains("Allow: GET, HEAD, POST, OPTIONS\r\n"));
+
+        let not_allowed = route(&router, "DELETE /files HTTP/1.1");
+        assert!(not_allowed.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"));
+        assert!(not_allowed.contains("Allow: GET, HEAD, POST, OPTIONS\r\n"));
+
+        let not_found = route(&router, "GET /folders HTTP/1.1");
+        assert!(not_found.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+        assert!(!not_found.contains("Allow:"));
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the process receives SIGINT or SIGTERM, telling the server to shut down
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+const SIGINT: i32 = 2;
+#[cfg(unix)]
+const SIGTERM: i32 = 15;
+/// Restores the default action of a signal, which is to terminate the process for these two
+#[cfg(unix)]
+const SIG_DFL: usize = 0;
+
+#[cfg(unix)]
+unsafe extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+/// Handles SIGINT and SIGTERM by asking the server to shut down.
+/// The default action is restored, so sending the signal a second time stops the server right away,
+/// without waiting for in-flight requests.
+#[cfg(unix)]
+extern "C" fn handle_signal(signum: i32) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+    // SAFETY: `signal` is async-signal-safe, so it can be called from a signal handler
+    unsafe {
+        signal(signum, SIG_DFL);
+    }
+}
+
+/// Installs the handler of SIGINT and SIGTERM, which only does anything on Unix systems
+pub(crate) fn install_shutdown_handler() {
+    #[cfg(unix)]
+    for signum in [SIGINT, SIGTERM] {
+        // SAFETY: the handler only stores to an atomic and calls `signal`, which are both
+        // async-signal-safe
+        unsafe {
+            signal(signum, handle_signal as extern "C" fn(i32) as usize);
+        }
+    }
+}
+
+/// Checks if the server has been asked to shut down
+pub(crate) fn is_shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,1141 @@
+use crate::common::AppError;
+use crate::http::Url;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::mem;
+use std::sync::Arc;
+
+/// How deep templates can extend and include each other, which is only ever exceeded by templates
+/// that extend or include each other in a loop
+const MAX_DEPTH: usize = 16;
+
+/// How much output a `TemplateReader` renders at a time
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The attributes whose values are URLs, so values put in them are percent-encoded
+const URL_ATTRIBUTES: [&str; 4] = ["href", "src", "action", "formaction"];
+
+/// A value that can be put in a template
+/// - **Text**: Output escaped for where it is put, and true if it isn't empty
+/// - **Bool**: Output as `true` or `false`, and used in conditionals
+/// - **List**: Looped over with `{% for %}`, and true if it isn't empty
+/// - **Object**: Named values, read with a dotted path like `file.name`
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Text(String),
+    Bool(bool),
+    List(Vec<Value>),
+    Object(Context),
+}
+
+impl Value {
+    /// Checks if the value counts as true in a conditional
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::Bool(value) => *value,
+            Value::List(items) => !items.is_empty(),
+            Value::Object(_) => true,
+        }
+    }
+
+    /// Gets a value nested in this one by the rest of a dotted path
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        match (path, self) {
+            ([], _) => Some(self),
+            ([name, rest @ ..], Value::Object(context)) => context.get(name)?.lookup(rest),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Self {
+        Value::List(items)
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Self {
+        Value::Object(context)
+    }
+}
+
+/// A `Context` holds the named values a template is rendered with
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Context(Vec<(String, Value)>);
+
+impl Context {
+    /// Creates an empty `Context`
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value to the `Context`
+    ///
+    /// Arguments:
+    /// - **mut self**: A mutable capture of self
+    /// - **name**: The name the value is read with in templates
+    /// - **value**: The value, which can be anything that converts into a `Value`
+    pub(crate) fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.0.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// Gets a value by its name
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(value_name, _)| value_name == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// A parsed piece of a template, before inheritance and includes are resolved
+/// - **Text**: Literal text, output as it is
+/// - **Value**: `{{ path }}`, a value output with the escaping of where it is
+/// - **If**: `{% if path %}` or `{% if not path %}`, with an optional `{% else %}`
+/// - **For**: `{% for item in path %}`, repeated for every item of a list
+/// - **Include**: `{% include "name" %}`, replaced with the nodes of another template
+/// - **Extends**: `{% extends "name" %}`, making the template fill the blocks of a layout
+/// - **Block**: `{% block name %}`, a part of a layout that templates extending it can replace
+enum Node {
+    Text(String),
+    Value(Vec<String>),
+    If {
+        path: Vec<String>,
+        negated: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        variable: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+    },
+    Include(String),
+    Extends(String),
+    Block {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+/// Parses the source of a template into `Node`s
+struct Parser<'a> {
+    name: &'a str,
+    source: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(name: &'a str, source: &'a str) -> Self {
+        Parser {
+            name,
+            source,
+            position: 0,
+        }
+    }
+
+    /// Creates an error about the template, pointing at the line the parser is on
+    fn error(&self, message: &str) -> AppError {
+        let line = self.source[..self.position].matches('\n').count() + 1;
+        AppError::Invalid(format!("Template {}, line {line}: {message}", self.name))
+    }
+
+    /// Parses nodes until one of the end tags, or the end of the source if there are none
+    ///
+    /// Arguments:
+    /// - **end_tags**: The tags that end the nodes being parsed, like `endif`
+    ///
+    /// Returns the nodes, and the end tag that was found.
+    fn parse_nodes(&mut self, end_tags: &[&str]) -> Result<(Vec<Node>, String), AppError> {
+        let mut nodes = Vec::new();
+
+        loop {
+            let rest = &self.source[self.position..];
+            let Some(tag_start) = rest.find("{{").into_iter().chain(rest.find("{%")).min() else {
+                if !end_tags.is_empty() {
+                    return Err(self.error(&format!("Missing {{% {} %}}", end_tags.join(" or "))));
+                }
+                if !rest.is_empty() {
+                    nodes.push(Node::Text(rest.to_string()));
+                }
+                self.position = self.source.len();
+                return Ok((nodes, String::new()));
+            };
+            if tag_start > 0 {
+                nodes.push(Node::Text(rest[..tag_start].to_string()));
+            }
+            self.position += tag_start;
+
+            let is_value = rest[tag_start..].starts_with("{{");
+            let closing = if is_value { "}}" } else { "%}" };
+            let Some(tag_length) = self.source[self.position..].find(closing) else {
+                return Err(self.error(&format!("Missing {closing}")));
+            };
+            let tag = &self.source[self.position + 2..self.position + tag_length];
+            let words: Vec<&str> = tag.split_whitespace().collect();
+
+            if is_value {
+                let [path] = words[..] else {
+                    return Err(self.error(&format!("Expected a single value in {{{{{tag}}}}}")));
+                };
+                nodes.push(Node::Value(self.parse_path(path)?));
+                self.position += tag_length + 2;
+                continue;
+            }
+            let tag_position = self.position;
+            self.position += tag_length + 2;
+
+            match words[..] {
+                [end_tag] if end_tags.contains(&end_tag) => {
+                    return Ok((nodes, end_tag.to_string()));
+                }
+                ["if", "not", path] | ["if", path] => {
+                    let path = self.parse_path(path)?;
+                    let (then, end_tag) = self.parse_nodes(&["else", "endif"])?;
+                    let otherwise = match end_tag.as_str() {
+                        "else" => self.parse_nodes(&["endif"])?.0,
+                        _ => Vec::new(),
+                    };
+                    nodes.push(Node::If {
+                        path,
+                        negated: words.len() == 3,
+                        then,
+                        otherwise,
+                    });
+                }
+                ["for", variable, "in", path] => {
+                    let path = self.parse_path(path)?;
+                    if self.parse_path(variable)?.len() > 1 {
+                        return Err(self.error(&format!("Invalid loop variable: {variable}")));
+                    }
+                    nodes.push(Node::For {
+                        variable: variable.to_string(),
+                        path,
+                        body: self.parse_nodes(&["endfor"])?.0,
+                    });
+                }
+                ["block", name] => nodes.push(Node::Block {
+                    name: name.to_string(),
+                    body: self.parse_nodes(&["endblock"])?.0,
+                }),
+                ["include", name] => nodes.push(Node::Include(self.parse_name(name)?)),
+                ["extends", name] => nodes.push(Node::Extends(self.parse_name(name)?)),
+                _ => {
+                    self.position = tag_position;
+                    return Err(self.error(&format!("Unknown tag {{%{tag}%}}")));
+                }
+            }
+        }
+    }
+
+    /// Parses a dotted path to a value, like `file.name`
+    fn parse_path(&self, path: &str) -> Result<Vec<String>, AppError> {
+        let segments: Vec<String> = path.split('.').map(str::to_string).collect();
+        let is_valid = |segment: &String| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !segments.iter().all(is_valid) {
+            return Err(self.error(&format!("Invalid value name: {path}")));
+        }
+        Ok(segments)
+    }
+
+    /// Parses the quoted name of a template, as given to `include` and `extends`
+    fn parse_name(&self, name: &str) -> Result<String, AppError> {
+        name.strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .map(str::to_string)
+            .ok_or(self.error(&format!("Template name must be quoted: {name}")))
+    }
+}
+
+/// Where a value is put in the HTML of a template, which decides how it is escaped
+/// - **Text**: Between tags
+/// - **Tag**: Inside a tag, but outside of the values of its attributes
+/// - **Attribute**: Inside a quoted attribute value, and the kind of value the attribute has
+/// - **UnquotedAttribute**: Inside an attribute value without quotes
+/// - **RawText**: Inside a `<script>` or `<style>` element, named by its tag
+/// - **Comment**: Inside an HTML comment
+#[derive(Clone, Debug, PartialEq)]
+enum HtmlState {
+    Text,
+    Tag,
+    Attribute { quote: u8, kind: AttributeKind },
+    UnquotedAttribute,
+    RawText(String),
+    Comment,
+}
+
+/// The kind of value an attribute has
+/// - **Text**: Plain text, like `title` or `value`
+/// - **Url**: A URL, like `href` or `src`
+/// - **Code**: JavaScript or CSS, like `onclick` or `style`, where no value can be put safely
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AttributeKind {
+    Text,
+    Url,
+    Code,
+}
+
+impl AttributeKind {
+    fn from_name(name: &str) -> Self {
+        if URL_ATTRIBUTES.contains(&name) {
+            AttributeKind::Url
+        } else if name.starts_with("on") || name == "style" {
+            AttributeKind::Code
+        } else {
+            AttributeKind::Text
+        }
+    }
+}
+
+/// Follows the literal text of a template through the HTML syntax, to tell where each value is put
+#[derive(Clone, Debug, PartialEq)]
+struct HtmlTracker {
+    state: HtmlState,
+    tag_name: String,
+    closing_tag: bool,
+    attribute_name: String,
+    in_attribute_name: bool,
+    after_equals: bool,
+}
+
+impl HtmlTracker {
+    fn new() -> Self {
+        HtmlTracker {
+            state: HtmlState::Text,
+            tag_name: String::new(),
+            closing_tag: false,
+            attribute_name: String::new(),
+            in_attribute_name: false,
+            after_equals: false,
+        }
+    }
+
+    /// Moves through literal text, which only needs to be read as far as it changes the state
+    ///
+    /// The text is walked through byte by byte, as everything that changes the state is ASCII, and
+    /// the bytes of a non-ASCII character are never mistaken for it.
+    fn feed(&mut self, text: &str) {
+        let bytes = text.as_bytes();
+        let mut index = 0;
+
+        while index < bytes.len() {
+            let byte = bytes[index];
+            let rest = &bytes[index + 1..];
+            index += 1;
+
+            match &self.state {
+                HtmlState::Text if byte == b'<' && rest.starts_with(b"!--") => {
+                    self.state = HtmlState::Comment;
+                    index += 3;
+                }
+                HtmlState::Text if byte == b'<' => {
+                    let tag = rest.strip_prefix(b"/").unwrap_or(rest);
+                    let tag_name: String = tag
+                        .iter()
+                        .take_while(|byte| byte.is_ascii_alphanumeric())
+                        .map(|&byte| byte as char)
+                        .collect();
+                    if tag_name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                        index += rest.len() - tag.len() + tag_name.len();
+                        self.start_tag(tag_name.to_ascii_lowercase(), tag.len() < rest.len());
+                    }
+                }
+                HtmlState::Text => {}
+                HtmlState::Tag | HtmlState::UnquotedAttribute if byte == b'>' => {
+                    let is_raw_text =
+                        !self.closing_tag && ["script", "style"].contains(&&*self.tag_name);
+                    self.state = match is_raw_text {
+                        true => HtmlState::RawText(self.tag_name.clone()),
+                        false => HtmlState::Text,
+                    };
+                }
+                HtmlState::UnquotedAttribute if byte.is_ascii_whitespace() => {
+                    self.state = HtmlState::Tag;
+                    self.after_equals = false;
+                }
+                HtmlState::UnquotedAttribute => {}
+                HtmlState::Tag if byte == b'=' => {
+                    self.in_attribute_name = false;
+                    self.after_equals = true;
+                }
+                HtmlState::Tag if byte.is_ascii_whitespace() => self.in_attribute_name = false,
+                HtmlState::Tag if self.after_equals && (byte == b'"' || byte == b'\'') => {
+                    self.state = HtmlState::Attribute {
+                        quote: byte,
+                        kind: AttributeKind::from_name(&self.attribute_name),
+                    };
+                }
+                HtmlState::Tag if self.after_equals => self.state = HtmlState::UnquotedAttribute,
+                HtmlState::Tag if byte == b'/' => self.in_attribute_name = false,
+                HtmlState::Tag => {
+                    if !self.in_attribute_name {
+                        self.attribute_name.clear();
+                        self.in_attribute_name = true;
+                    }
+                    self.attribute_name.push(byte.to_ascii_lowercase() as char);
+                }
+                HtmlState::Attribute { quote, .. } if byte == *quote => {
+                    self.state = HtmlState::Tag;
+                    self.after_equals = false;
+                }
+                HtmlState::Attribute { .. } => {}
+                HtmlState::RawText(tag_name) => {
+                    let end_tag = rest
+                        .strip_prefix(b"/")
+                        .and_then(|rest| rest.get(..tag_name.len()))
+                        .is_some_and(|name| name.eq_ignore_ascii_case(tag_name.as_bytes()));
+                    if byte == b'<' && end_tag {
+                        index += 1 + tag_name.len();
+                        self.start_tag(tag_name.clone(), true);
+                    }
+                }
+                HtmlState::Comment if byte == b'-' && rest.starts_with(b"->") => {
+                    self.state = HtmlState::Text;
+                    index += 2;
+                }
+                HtmlState::Comment => {}
+            }
+        }
+    }
+
+    fn start_tag(&mut self, tag_name: String, closing_tag: bool) {
+        self.state = HtmlState::Tag;
+        self.tag_name = tag_name;
+        self.closing_tag = closing_tag;
+        self.attribute_name.clear();
+        self.in_attribute_name = false;
+        self.after_equals = false;
+    }
+
+    /// Gets how a value put at the current position is escaped, or `None` if there is no way to
+    /// put one there safely
+    fn escape(&self) -> Option<Escape> {
+        match &self.state {
+            HtmlState::Text => Some(Escape::Html),
+            HtmlState::Attribute { kind, .. } => match kind {
+                AttributeKind::Text => Some(Escape::Html),
+                AttributeKind::Url => Some(Escape::Url),
+                AttributeKind::Code => None,
+            },
+            HtmlState::RawText(tag_name) if tag_name == "script" => Some(Escape::Script),
+            _ => None,
+        }
+    }
+}
+
+/// How a value is escaped before it is output
+/// - **Html**: The characters with a meaning in HTML are replaced with entities, which is enough
+///   for text and quoted attribute values
+/// - **Url**: Everything but unreserved characters and `/` is percent-encoded, so that a value in
+///   a link can't end it early, or turn it into a `javascript:` URL
+/// - **Script**: Everything but letters, digits and spaces is written as a `\uXXXX` escape, which
+///   is safe inside a JavaScript string
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Escape {
+    Html,
+    Url,
+    Script,
+}
+
+impl Escape {
+    fn write(self, text: &str, output: &mut Vec<u8>) {
+        match self {
+            Escape::Html => {
+                for c in text.chars() {
+                    match c {
+                        '&' => output.extend_from_slice(b"&amp;"),
+                        '<' => output.extend_from_slice(b"&lt;"),
+                        '>' => output.extend_from_slice(b"&gt;"),
+                        '"' => output.extend_from_slice(b"&quot;"),
+                        '\'' => output.extend_from_slice(b"&#39;"),
+                        c => output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+            }
+            Escape::Url => output.extend_from_slice(Url::encode_path(text).as_bytes()),
+            Escape::Script => {
+                for unit in text.encode_utf16() {
+                    match char::from_u32(unit as u32) {
+                        Some(c) if c.is_ascii_alphanumeric() || c == ' ' => output.push(c as u8),
+                        _ => output.extend_from_slice(format!("\\u{unit:04X}").as_bytes()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A single step of a compiled template
+/// - **Text**: Outputs literal text
+/// - **Value**: Outputs a value with the escaping of where it is
+/// - **JumpUnless**: Jumps to `target` unless the value is true, or false if `negated`
+/// - **Jump**: Jumps to `target`
+/// - **LoopStart**: Starts looping over a list, or jumps to `end`, past the loop, if it is empty
+/// - **LoopEnd**: Goes back to the start of the loop for the next item, or leaves the loop
+#[derive(Debug)]
+enum Instruction {
+    Text(String),
+    Value {
+        path: Vec<String>,
+        escape: Escape,
+    },
+    JumpUnless {
+        path: Vec<String>,
+        negated: bool,
+        target: usize,
+    },
+    Jump(usize),
+    LoopStart {
+        variable: String,
+        path: Vec<String>,
+        end: usize,
+    },
+    LoopEnd {
+        start: usize,
+    },
+}
+
+/// A `Template` is an HTML template compiled into a list of `Instruction`s, with its layout and
+/// includes already resolved, and the escaping of every value already decided, so rendering it
+/// only has to follow the instructions.
+///
+/// Templates are written with:
+/// - `{{ name }}` to output a value, or `{{ item.name }}` for a value of an object
+/// - `{% if name %}`, `{% if not name %}`, `{% else %}` and `{% endif %}` for conditionals
+/// - `{% for item in list %}` and `{% endfor %}` for loops
+/// - `{% include "name" %}` to include another template
+/// - `{% extends "name" %}` as the first tag to extend a layout, replacing its
+///   `{% block name %}`...`{% endblock %}` blocks with the blocks of the same name in the template
+///
+/// Values are escaped for where they are put: as HTML in text and attribute values, percent-encoded
+/// in URL attributes like `href`, and as JavaScript inside `<script>`. A value put anywhere else,
+/// like in a `<style>` element, an event handler attribute or as an attribute name, fails
+/// compilation, as it can't be escaped.
+#[derive(Debug)]
+pub(crate) struct Template {
+    instructions: Vec<Instruction>,
+}
+
+impl Template {
+    /// Compiles a template
+    ///
+    /// Arguments:
+    /// - **name**: The name of the template
+    /// - **load**: Gets the source of a template by its name, for the template itself, and the
+    ///   layouts and templates it uses
+    /// - **values**: The names of the values the template is rendered with
+    ///
+    /// An `AppError::Invalid` pointing at the problem is returned if any template can't be parsed,
+    /// uses a value that isn't one of `values` or a loop variable, or puts a value where it can't be
+    /// escaped.
+    pub(crate) fn compile<F>(name: &str, load: &F, values: &[&str]) -> Result<Template, AppError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let nodes = Self::resolve(name, load, 0)?;
+        let mut compiler = Compiler {
+            name,
+            values,
+            loop_variables: Vec::new(),
+            instructions: Vec::new(),
+            html: HtmlTracker::new(),
+        };
+        compiler.compile_nodes(&nodes)?;
+
+        Ok(Template {
+            instructions: compiler.instructions,
+        })
+    }
+
+    /// Parses a template, and resolves the layout it extends and the templates it includes
+    ///
+    /// Arguments:
+    /// - **name**: The name of the template
+    /// - **load**: Gets the source of a template by its name
+    /// - **depth**: How many templates deep this one is extended or included
+    ///
+    /// A template extending a layout can only have blocks, which replace the blocks of the same
+    /// name anywhere in the layout, including in blocks the template replaced.
+    fn resolve<F>(name: &str, load: &F, depth: usize) -> Result<Vec<Node>, AppError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if depth > MAX_DEPTH {
+            return Err(AppError::Invalid(format!(
+                "Template {name} extends or includes itself"
+            )));
+        }
+        let source =
+            load(name).ok_or(AppError::Invalid(format!("Template {name} does not exist")))?;
+        let (mut nodes, _) = Parser::new(name, &source).parse_nodes(&[])?;
+
+        let extends = nodes.iter().position(|node| match node {
+            Node::Text(text) => !text.trim().is_empty(),
+            _ => true,
+        });
+        if let Some(position) = extends
+            && let Node::Extends(layout) = &nodes[position]
+        {
+            let layout = layout.clone();
+            let mut blocks = HashMap::new();
+            for node in nodes.drain(..).skip(position + 1) {
+                match node {
+                    Node::Block { name, body } => {
+                        blocks.insert(name, body);
+                    }
+                    Node::Text(text) if text.trim().is_empty() => {}
+                    _ => {
+                        return Err(AppError::Invalid(format!(
+                            "Template {name} extends {layout}, so it can only have blocks"
+                        )));
+                    }
+                }
+            }
+
+            nodes = Self::resolve(&layout, load, depth + 1)?;
+            Self::fill_blocks(&mut nodes, &mut blocks);
+            if let Some(block) = blocks.keys().next() {
+                return Err(AppError::Invalid(format!(
+                    "Template {name} has a block {block} that isn't in {layout}"
+                )));
+            }
+        }
+
+        Self::include_templates(nodes, load, depth)
+    }
+
+    /// Replaces the blocks of a layout with the blocks of the same name of a template extending it
+    fn fill_blocks(nodes: &mut [Node], blocks: &mut HashMap<String, Vec<Node>>) {
+        for node in nodes {
+            match node {
+                Node::Block { name, body } => {
+                    if let Some(new_body) = blocks.remove(name) {
+                        *body = new_body;
+                    }
+                    Self::fill_blocks(body, blocks);
+                }
+                Node::If {
+                    then, otherwise, ..
+                } => {
+                    Self::fill_blocks(then, blocks);
+                    Self::fill_blocks(otherwise, blocks);
+                }
+                Node::For { body, .. } => Self::fill_blocks(body, blocks),
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces every include with the nodes of the template it includes
+    fn include_templates<F>(nodes: Vec<Node>, load: &F, depth: usize) -> Result<Vec<Node>, AppError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut included = Vec::new();
+        for node in nodes {
+            match node {
+                Node::Include(name) => included.extend(Self::resolve(&name, load, depth + 1)?),
+                Node::Extends(layout) => {
+                    return Err(AppError::Invalid(format!(
+                        "{{% extends \"{layout}\" %}} must be the first tag of a template"
+                    )));
+                }
+                Node::If {
+                    path,
+                    negated,
+                    then,
+                    otherwise,
+                } => included.push(Node::If {
+                    path,
+                    negated,
+                    then: Self::include_templates(then, load, depth)?,
+                    otherwise: Self::include_templates(otherwise, load, depth)?,
+                }),
+                Node::For {
+                    variable,
+                    path,
+                    body,
+                } => included.push(Node::For {
+                    variable,
+                    path,
+                    body: Self::include_templates(body, load, depth)?,
+                }),
+                Node::Block { name, body } => included.push(Node::Block {
+                    name,
+                    body: Self::include_templates(body, load, depth)?,
+                }),
+                node => included.push(node),
+            }
+        }
+        Ok(included)
+    }
+
+    /// Renders the template into a string
+    ///
+    /// Arguments:
+    /// - **context**: The values the template is rendered with
+    ///
+    /// A value that isn't in the context is output as nothing, and is false in conditionals.
+    pub(crate) fn render(&self, context: &Context) -> String {
+        let mut output = Vec::new();
+        RenderState::default().run(self, context, &mut output, usize::MAX);
+        // Literal text comes from a `&str`, and values are escaped from strings
+        String::from_utf8(output).unwrap_or_default()
+    }
+
+    /// Renders the template as it is read, so that a long page, like a listing with a lot of items,
+    /// doesn't have to be held in memory as a whole
+    ///
+    /// Arguments:
+    /// - **self**: The `Template`, shared with the `TemplateReader`
+    /// - **context**: The values the template is rendered with
+    pub(crate) fn reader(self: Arc<Self>, context: Context) -> TemplateReader {
+        TemplateReader {
+            template: self,
+            context,
+            state: RenderState::default(),
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+/// Turns resolved `Node`s into the `Instruction`s of a `Template`, deciding how every value is
+/// escaped by following the HTML around it
+struct Compiler<'a> {
+    name: &'a str,
+    values: &'a [&'a str],
+    loop_variables: Vec<String>,
+    instructions: Vec<Instruction>,
+    html: HtmlTracker,
+}
+
+impl Compiler<'_> {
+    /// Compiles nodes, whose jumps are filled in once the position they jump to is known
+    ///
+    /// Both branches of a conditional, and the body of a loop, must end where they started in the
+    /// HTML, or the escaping of the values after them would depend on the values they are
+    /// rendered with.
+    fn compile_nodes(&mut self, nodes: &[Node]) -> Result<(), AppError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => {
+                    self.html.feed(text);
+                    self.instructions.push(Instruction::Text(text.clone()));
+                }
+                Node::Value(path) => {
+                    self.check_value(path)?;
+                    let escape = self.html.escape().ok_or(AppError::Invalid(format!(
+                        "Template {}: {{{{ {} }}}} is in a place where it can't be escaped",
+                        self.name,
+                        path.join(".")
+                    )))?;
+                    self.instructions.push(Instruction::Value {
+                        path: path.clone(),
+                        escape,
+                    });
+                }
+                Node::If {
+                    path,
+                    negated,
+                    then,
+                    otherwise,
+                } => {
+                    self.check_value(path)?;
+                    let jump = self.instructions.len();
+                    self.instructions.push(Instruction::JumpUnless {
+                        path: path.clone(),
+                        negated: *negated,
+                        target: 0,
+                    });
+                    let start = self.html.clone();
+                    self.compile_nodes(then)?;
+
+                    let mut target = self.instructions.len();
+                    if !otherwise.is_empty() {
+                        let then_end = mem::replace(&mut self.html, start.clone());
+                        let skip_otherwise = self.instructions.len();
+                        self.instructions.push(Instruction::Jump(0));
+                        target = self.instructions.len();
+                        self.compile_nodes(otherwise)?;
+                        self.instructions[skip_otherwise] =
+                            Instruction::Jump(self.instructions.len());
+                        self.check_state(&then_end, path)?;
+                    } else {
+                        self.check_state(&start, path)?;
+                    }
+                    if let Instruction::JumpUnless { target: jump, .. } =
+                        &mut self.instructions[jump]
+                    {
+                        *jump = target;
+                    }
+                }
+                Node::For {
+                    variable,
+                    path,
+                    body,
+                } => {
+                    self.check_value(path)?;
+                    let start = self.instructions.len();
+                    self.instructions.push(Instruction::LoopStart {
+                        variable: variable.clone(),
+                        path: path.clone(),
+                        end: 0,
+                    });
+                    let html = self.html.clone();
+                    self.loop_variables.push(variable.clone());
+                    self.compile_nodes(body)?;
+                    self.loop_variables.pop();
+                    self.check_state(&html, path)?;
+                    self.instructions.push(Instruction::LoopEnd { start });
+
+                    let end_position = self.instructions.len();
+                    if let Instruction::LoopStart { end, .. } = &mut self.instructions[start] {
+                        *end = end_position;
+                    }
+                }
+                Node::Block { body, .. } => self.compile_nodes(body)?,
+                Node::Include(_) | Node::Extends(_) => {
+                    return Err(AppError::Unknown(format!(
+                        "Template {} was compiled before it was resolved",
+                        self.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a value is one the template is rendered with, or the variable of a loop it is in,
+    /// which catches misspelled names that would otherwise always render as nothing
+    fn check_value(&self, path: &[String]) -> Result<(), AppError> {
+        let name = path.first().map(String::as_str).unwrap_or_default();
+        if self.values.contains(&name) || self.loop_variables.iter().any(|v| v == name) {
+            return Ok(());
+        }
+
+        let expected = match self.values {
+            [] => "it isn't rendered with any values".to_string(),
+            values => format!("it is rendered with: {}", values.join(", ")),
+        };
+        Err(AppError::Invalid(format!(
+            "Template {}: {{{{ {} }}}} is not a known value, {expected}",
+            self.name,
+            path.join(".")
+        )))
+    }
+
+    /// Checks that a conditional or loop ends where it started in the HTML
+    fn check_state(&self, expected: &HtmlTracker, path: &[String]) -> Result<(), AppError> {
+        if self.html.state != expected.state {
+            return Err(AppError::Invalid(format!(
+                "Template {}: the conditional or loop on {} doesn't end where it started in the HTML",
+                self.name,
+                path.join(".")
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A loop being rendered, with the position of its `LoopStart` and the item it is on
+struct Loop {
+    start: usize,
+    index: usize,
+}
+
+/// Where the rendering of a `Template` is, so that it can be continued
+#[derive(Default)]
+struct RenderState {
+    position: usize,
+    loops: Vec<Loop>,
+}
+
+impl RenderState {
+    /// Follows the instructions until at least `min_output` bytes were output, or the template ended
+    ///
+    /// Returns `true` once the template ended.
+    fn run(
+        &mut self,
+        template: &Template,
+        context: &Context,
+        output: &mut Vec<u8>,
+        min_output: usize,
+    ) -> bool {
+        while output.len() < min_output {
+            let Some(instruction) = template.instructions.get(self.position) else {
+                return true;
+            };
+            self.position += 1;
+
+            match instruction {
+                Instruction::Text(text) => output.extend_from_slice(text.as_bytes()),
+                Instruction::Value { path, escape } => {
+                    match Self::lookup(template, context, &self.loops, path) {
+                        Some(Value::Text(text)) => escape.write(text, output),
+                        Some(Value::Bool(value)) => escape.write(&value.to_string(), output),
+                        _ => {}
+                    }
+                }
+                Instruction::JumpUnless {
+                    path,
+                    negated,
+                    target,
+                } => {
+                    let value = Self::lookup(template, context, &self.loops, path);
+                    if value.is_some_and(Value::is_truthy) == *negated {
+                        self.position = *target;
+                    }
+                }
+                Instruction::Jump(target) => self.position = *target,
+                Instruction::LoopStart { path, end, .. } => {
+                    if Self::list_length(template, context, &self.loops, path) == 0 {
+                        self.position = *end;
+                    } else {
+                        self.loops.push(Loop {
+                            start: self.position - 1,
+                            index: 0,
+                        });
+                    }
+                }
+                Instruction::LoopEnd { start } => {
+                    let Some(Instruction::LoopStart { path, .. }) =
+                        template.instructions.get(*start)
+                    else {
+                        return true;
+                    };
+                    let outer_loops = &self.loops[..self.loops.len() - 1];
+                    let length = Self::list_length(template, context, outer_loops, path);
+                    if let Some(current) = self.loops.last_mut() {
+                        current.index += 1;
+                        if current.index < length {
+                            self.position = start + 1;
+                        } else {
+                            self.loops.pop();
+                        }
+                    }
+                }
+            }
+        }
+        self.position >= template.instructions.len()
+    }
+
+    /// Gets a value by its path, which starts either with the variable of a loop, for its current
+    /// item, or with the name of a value in the context
+    fn lookup<'a>(
+        template: &Template,
+        context: &'a Context,
+        loops: &[Loop],
+        path: &[String],
+    ) -> Option<&'a Value> {
+        let (name, rest) = path.split_first()?;
+        for (level, current) in loops.iter().enumerate().rev() {
+            let Some(Instruction::LoopStart {
+                variable,
+                path: list_path,
+                ..
+            }) = template.instructions.get(current.start)
+            else {
+                continue;
+            };
+            if variable == name {
+                let Some(Value::List(items)) =
+                    Self::lookup(template, context, &loops[..level], list_path)
+                else {
+                    return None;
+                };
+                return items.get(current.index)?.lookup(rest);
+            }
+        }
+        context.get(name)?.lookup(rest)
+    }
+
+    /// Gets the number of items of a list by its path, which is 0 if it isn't a list
+    fn list_length(
+        template: &Template,
+        context: &Context,
+        loops: &[Loop],
+        path: &[String],
+    ) -> usize {
+        match Self::lookup(template, context, loops, path) {
+            Some(Value::List(items)) => items.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// A `TemplateReader` renders a `Template` a chunk at a time, as it is read
+pub(crate) struct TemplateReader {
+    template: Arc<Template>,
+    context: Context,
+    state: RenderState,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for TemplateReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            self.buffer.clear();
+            self.position = 0;
+            self.state.run(
+                &self.template,
+                &self.context,
+                &mut self.buffer,
+                READ_CHUNK_SIZE,
+            );
+        }
+
+        let bytes_read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::template::{Context, Template, Value};
+    use std::io::Read;
+    use std::sync::Arc;
+
+    fn compile(name: &str, sources: &[(&str, &str)], values: &[&str]) -> Result<Template, String> {
+        let load = |name: &str| {
+            sources
+                .iter()
+                .find(|(source_name, _)| *source_name == name)
+                .map(|(_, source)| source.to_string())
+        };
+        Template::compile(name, &load, values).map_err(|e| format!("{e:?}"))
+    }
+
+    #[test]
+    fn escape_values_for_where_they_are() {
+        let template = compile(
+            "page",
+            &[(
+                "page",
+                r#"<a href="/uploads/{{ path }}" title="{{ name }}">{{ name }}</a><script>let name = "{{ name }}";</script>"#,
+            )],
+            &["path", "name"],
+        )
+        .unwrap();
+        let context = Context::new()
+            .with("path", "docs/<a b>.txt")
+            .with("name", r#"<script>"x" & 'y'</script>"#);
+
+        assert_eq!(
+            template.render(&context),
+            concat!(
+                r#"<a href="/uploads/docs/%3Ca%20b%3E.txt" "#,
+                r#"title="&lt;script&gt;&quot;x&quot; &amp; &#39;y&#39;&lt;/script&gt;">"#,
+                r#"&lt;script&gt;&quot;x&quot; &amp; &#39;y&#39;&lt;/script&gt;</a>"#,
+                r#"<script>let name = "\u003Cscript\u003E\u0022x\u0022 \u0026 "#,
+                r#"\u0027y\u0027\u003C\u002Fscript\u003E";</script>"#,
+            )
+        );
+
+        let fails = |source: &str| compile("page", &[("page", source)], &["name"]).is_err();
+        assert!(fails("<style>{{ name }}</style>"));
+        assert!(fails("<a {{ name }}>"));
+        assert!(fails("<button onclick=\"confirm('{{ name }}')\">"));
+        assert!(fails("{% if name %}<a href=\""));
+        assert!(fails("{% if name %}<a href=\"{% endif %}\">"));
+        assert!(fails("<p>{{ nmae }}</p>"));
+    }
+
+    #[test]
+    fn compile_templates_with_non_ascii_text() {
+        let template = compile(
+            "page",
+            &[(
+                "page",
+                r#"<h1>Größe — ✓</h1><!-- ü --><é><a title="日本" href="/{{ path }}">{{ name }}</a><script>/* ñ */</script>"#,
+            )],
+            &["path", "name"],
+        )
+        .unwrap();
+        let context = Context::new().with("path", "ü.txt").with("name", "<ü>");
+
+        assert_eq!(
+            template.render(&context),
+            concat!(
+                r#"<h1>Größe — ✓</h1><!-- ü --><é><a title="日本" href="/%C3%BC.txt">&lt;ü&gt;</a>"#,
+                r#"<script>/* ñ */</script>"#,
+            )
+        );
+        assert!(compile("page", &[("page", "<p título=\"{{ name }}\">")], &["name"]).is_ok());
+        assert!(compile("page", &[("page", "<style>é{{ name }}</style>")], &["name"]).is_err());
+    }
+
+    #[test]
+    fn render_loops_conditionals_and_layouts() {
+        let sources = [
+            (
+                "layout",
+                "<title>{% block title %}Files{% endblock %}</title>{% block content %}{% endblock %}{% include \"footer\" %}",
+            ),
+            ("footer", "<footer>{{ footer }}</footer>"),
+            (
+                "list",
+                r#"{% extends "layout" %}
+                {% block content %}<ul>{% for file in files %}<li>{{ file.name }}{% if file.shared %}!{% endif %}</li>{% endfor %}</ul>{% if not files %}Empty{% else %}{{ count }}{% endif %}{% endblock %}"#,
+            ),
+        ];
+        let values = ["files", "count", "footer"];
+        let template = Arc::new(compile("list", &sources, &values).unwrap());
+
+        let file = |name: &str, shared: bool| {
+            Value::from(Context::new().with("name", name).with("shared", shared))
+        };
+        let context = Context::new()
+            .with("files", vec![file("a.txt", true), file("<b>.txt", false)])
+            .with("count", "2")
+            .with("footer", "Bye");
+        let expected = "<title>Files</title><ul><li>a.txt!</li><li>&lt;b&gt;.txt</li></ul>2<footer>Bye</footer>";
+        assert_eq!(template.render(&context), expected);
+
+        let mut read = String::new();
+        template
+            .clone()
+            .reader(context)
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, expected);
+
+        let empty = Context::new().with("files", Vec::new());
+        assert_eq!(
+            template.render(&empty),
+            "<title>Files</title><ul></ul>Empty<footer></footer>"
+        );
+
+        let looping = [("a", "{% include \"b\" %}"), ("b", "{% include \"a\" %}")];
+        assert!(compile("a", &looping, &[]).is_err());
+        assert!(compile("missing", &[("missing", "{% extends \"layout\" %}")], &[]).is_err());
+    }
+}